@@ -0,0 +1,101 @@
+//! The steady-state request path: `poll_ready` with a valid token and the header insertion.
+//!
+//! The token is loaded from a token store, so nothing is fetched.
+
+#[path = "../src/test_util.rs"]
+mod test_util;
+
+use std::{
+    sync::{Arc, Barrier},
+    task::{self, Poll},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures_util::{task::noop_waker, FutureExt as _};
+use google_authz::{CachedToken, Credentials, GoogleAuthz, TokenStore};
+use hyper::Request;
+use test_util::Echo;
+use tower_service::Service;
+
+const CLOUD_PLATFORM: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+const USER: &[u8] = br#"{
+  "client_id": "xxx.apps.googleusercontent.com",
+  "client_secret": "secret-xxx",
+  "refresh_token": "refresh-xxx",
+  "type": "authorized_user"
+}"#;
+
+#[derive(Debug)]
+struct Preloaded;
+
+impl TokenStore for Preloaded {
+    fn load(&self) -> Option<CachedToken> {
+        Some(CachedToken {
+            value: "Bearer xxx".into(),
+            expiry: SystemTime::now() + Duration::from_secs(3600),
+            scopes: vec![CLOUD_PLATFORM.into()],
+        })
+    }
+
+    fn store(&self, _: &CachedToken) {}
+}
+
+fn service() -> GoogleAuthz<Echo> {
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        let credentials = Credentials::builder().json(USER).build().await.unwrap();
+        GoogleAuthz::builder(Echo).credentials(credentials).token_store(Preloaded).build().await
+    })
+}
+
+fn request(service: &mut GoogleAuthz<Echo>, cx: &mut task::Context<'_>) {
+    assert!(matches!(service.poll_ready(cx), Poll::Ready(Ok(()))));
+    let req = Request::get("https://example.com/").body(()).unwrap();
+    let resp = service.call(req).now_or_never().unwrap().unwrap();
+    assert!(resp.headers().contains_key("authorization"));
+}
+
+fn single_thread(c: &mut Criterion) {
+    let mut service = service();
+    let waker = noop_waker();
+    let mut cx = task::Context::from_waker(&waker);
+    c.bench_function("request", |b| b.iter(|| request(&mut service, &mut cx)));
+}
+
+// Clones of the service share the token, as the clones of a service used by concurrent tasks.
+fn contended(c: &mut Criterion) {
+    const THREADS: u32 = 4;
+
+    let service = service();
+    c.bench_function("request/contended", |b| {
+        b.iter_custom(|iters| {
+            let barrier = Arc::new(Barrier::new(THREADS as usize + 1));
+            let handles = (0..THREADS)
+                .map(|_| {
+                    let (mut service, barrier) = (service.clone(), barrier.clone());
+                    thread::spawn(move || {
+                        let waker = noop_waker();
+                        let mut cx = task::Context::from_waker(&waker);
+                        barrier.wait();
+                        for _ in 0..iters {
+                            request(&mut service, &mut cx);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            barrier.wait();
+            let start = Instant::now();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            // The time per request of each thread, comparable to the single-threaded one.
+            start.elapsed()
+        })
+    });
+}
+
+criterion_group!(benches, single_thread, contended);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+use std::{env, net::SocketAddr};
+
+use axum::{
+    body::Body,
+    extract::Extension,
+    http::{header::HOST, Request, Response, StatusCode, Uri},
+    routing::any,
+    AddExtensionLayer, Router,
+};
+use google_authz::{GoogleAuthz, GoogleAuthzLayer};
+use hyper::client::HttpConnector;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use tower::{ServiceBuilder, ServiceExt as _};
+
+type Client = GoogleAuthz<hyper::Client<HttpsConnector<HttpConnector>>>;
+
+async fn proxy(
+    Extension(client): Extension<Client>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let path_and_query = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    *req.uri_mut() = Uri::try_from(format!("https://pubsub.googleapis.com{}", path_and_query))
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    req.headers_mut().remove(HOST);
+
+    client.oneshot(req).await.map_err(|err| {
+        tracing::warn!("upstream error: {}", err);
+        StatusCode::BAD_GATEWAY
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    let addr: SocketAddr =
+        env::args().nth(1).unwrap_or_else(|| "127.0.0.1:3000".to_owned()).parse()?;
+
+    let https =
+        HttpsConnectorBuilder::new().with_webpki_roots().https_only().enable_http2().build();
+    let client: Client = ServiceBuilder::new()
+        .layer(GoogleAuthzLayer::new().await)
+        .service(hyper::Client::builder().build(https));
+
+    // e.g. curl http://127.0.0.1:3000/v1/projects/<GCP_PROJECT_ID>/topics
+    let app = Router::new().route("/*path", any(proxy)).layer(AddExtensionLayer::new(client));
+    axum::Server::bind(&addr).serve(app.into_make_service()).await?;
+
+    Ok(())
+}
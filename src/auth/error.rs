@@ -1,20 +1,264 @@
 /// Represents errors that can occur during fetching token.
-#[derive(thiserror::Error, Debug)]
+///
+/// New variants may be added, so matching on it needs a wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
-    #[error("gcemeta client error: {0}")]
-    Gcemeta(#[from] gcemeta::Error),
-    #[error("http client error: {0}")]
-    Http(#[from] hyper::Error),
-    #[error("response status code error: {0:?}")]
-    StatusCode((hyper::http::response::Parts, hyper::Body)),
-    #[error("response body deserialize error: {0}")]
-    JsonDeserialize(serde_json::Error),
-    #[error("token format error: {0:?}")]
+    Gcemeta(gcemeta::Error),
+    /// The request could not be sent or the response could not be read.
+    Io(hyper::Error),
+    /// The response had a non-200 status code without an OAuth 2.0 error body.
+    Http(hyper::StatusCode),
+    Deserialize(serde_json::Error),
+    /// An OAuth 2.0 error response from the token endpoint.
+    OAuth {
+        status: hyper::StatusCode,
+        /// e.g. `invalid_grant`, `invalid_scope`
+        code: String,
+        description: Option<String>,
+    },
+    Timeout,
+    /// A 429 response with a `Retry-After` header, `retry_after` is the delay it asked for.
+    RateLimited {
+        retry_after: std::time::Duration,
+    },
+    Jwt(Box<dyn std::error::Error + Send + Sync>),
     TokenFormat(crate::auth::oauth2::token::Response),
+    Chain(Vec<Error>),
+    /// The metadata server refused to issue a token with these scopes, usually because they are
+    /// not granted to the instance.
+    MetadataScopes(Vec<String>),
+    /// The response did not come from the metadata server as it has no `Metadata-Flavor: Google`
+    /// header, e.g. a proxy login page.
+    MetadataFlavorMissing,
+    /// The metadata server has no service account with this email attached to the instance.
+    ServiceAccountNotAttached(String),
+    /// The metadata server host is not a valid `host:port`, see
+    /// [`Metadata::with_host`](crate::credentials::Metadata::with_host).
+    InvalidMetadataHost(String),
+    /// A field of the service account key is missing or invalid, e.g. a truncated private key.
+    InvalidServiceAccount {
+        field: &'static str,
+        reason: String,
+    },
+    /// A field of the external account credentials is invalid, e.g. a malformed `token_url`.
+    InvalidExternalAccount {
+        field: &'static str,
+        reason: String,
+    },
+    UnsupportedTokenType(String),
+    /// The subject token of an external account could not be built.
+    SubjectToken(Box<dyn std::error::Error + Send + Sync>),
+    /// The interactive authorization of an end user failed, e.g. the consent was denied.
+    Authorization(Box<dyn std::error::Error + Send + Sync>),
+    /// A token could not be verified, see [`Verifier`](crate::Verifier).
+    Verify(Box<dyn std::error::Error + Send + Sync>),
+    /// A request was sent before the token was fetched, i.e. without waiting for `poll_ready`.
+    TokenNotFetched,
+    /// An access token was requested, but the credentials are not OAuth 2.0 credentials.
+    NoAccessToken,
+    /// The token of [`StaticToken`](crate::StaticToken) has expired and there is no source to
+    /// get a new one from.
+    StaticTokenExpired,
+    /// The source of [`StaticToken`](crate::StaticToken) failed to return a token.
+    TokenSource(Box<dyn std::error::Error + Send + Sync>),
+    /// The credentials have been revoked, see [`GoogleAuthz::revoke`](crate::GoogleAuthz::revoke).
+    Revoked,
+    /// A token was needed after [`GoogleAuthz::shutdown`](crate::GoogleAuthz::shutdown).
+    Shutdown,
+    /// The token fetch kept failing; `source` is the error of the last attempt.
+    MaxRetriesExceeded {
+        attempts: u8,
+        source: Box<Error>,
+    },
+    /// The default credentials could not be found or read, e.g. a malformed credentials file.
+    Credentials(crate::credentials::Error),
+    /// A setting is invalid, e.g. a refresh buffer longer than the token lifetime.
+    InvalidConfig(&'static str),
     #[cfg(not(feature = "tonic"))]
-    #[error("uri schema error: {0:?}")]
     EnforceHttps(Option<String>),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Gcemeta(err) => write!(f, "gcemeta client error: {}", err),
+            Error::Io(err) => write!(f, "http client io error: {}", err),
+            Error::Http(status) => write!(f, "response status code error: {}", status),
+            Error::Deserialize(err) => write!(f, "response body deserialize error: {}", err),
+            Error::OAuth { status, code, description } => write!(
+                f,
+                "oauth 2.0 error response: status={}, code={}, description={:?}",
+                status, code, description
+            ),
+            Error::Timeout => f.write_str("token fetch timed out"),
+            Error::RateLimited { retry_after } => {
+                write!(f, "rate limited, retry after {:?}", retry_after)
+            }
+            Error::Jwt(err) => write!(f, "jwt signing error: {}", err),
+            Error::TokenFormat(resp) => write!(f, "token format error: {:?}", resp),
+            Error::Chain(errors) => {
+                write!(f, "all credentials sources in the chain failed: {:?}", errors)
+            }
+            Error::MetadataScopes(scopes) => write!(
+                f,
+                "metadata server rejected the requested scopes, check the scopes of the instance: \
+                 {:?}",
+                scopes
+            ),
+            Error::MetadataFlavorMissing => f.write_str(
+                "response without `Metadata-Flavor: Google` header, not from the metadata server",
+            ),
+            Error::ServiceAccountNotAttached(email) => {
+                write!(f, "service account not attached to this instance: {}", email)
+            }
+            Error::InvalidMetadataHost(host) => {
+                write!(f, "invalid metadata server host: {:?}", host)
+            }
+            Error::InvalidServiceAccount { field, reason } => {
+                write!(f, "invalid service account key: `{}` {}", field, reason)
+            }
+            Error::InvalidExternalAccount { field, reason } => {
+                write!(f, "invalid external account credentials: `{}` {}", field, reason)
+            }
+            Error::UnsupportedTokenType(ty) => write!(f, "unsupported token type: {}", ty),
+            Error::SubjectToken(err) => write!(f, "subject token error: {}", err),
+            Error::Authorization(err) => write!(f, "authorization error: {}", err),
+            Error::Verify(err) => write!(f, "token verification error: {}", err),
+            Error::TokenNotFetched => f.write_str(
+                "no token has been fetched yet, `poll_ready` must be ready before `call`",
+            ),
+            Error::NoAccessToken => f.write_str(
+                "the credentials have no access token, e.g. an API key or no credentials",
+            ),
+            Error::StaticTokenExpired => f.write_str("the static token has expired"),
+            Error::TokenSource(err) => write!(f, "static token source error: {}", err),
+            Error::Revoked => f.write_str("the credentials have been revoked"),
+            Error::Shutdown => f.write_str("the token source has been shut down"),
+            Error::MaxRetriesExceeded { attempts, source } => {
+                write!(f, "max retries exceeded: attempts={}, last error: {}", attempts, source)
+            }
+            Error::Credentials(err) => write!(f, "credentials error: {}", err),
+            Error::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            #[cfg(not(feature = "tonic"))]
+            Error::EnforceHttps(scheme) => write!(f, "uri schema error: {:?}", scheme),
+        }
+    }
+}
+
+// Written by hand rather than derived, so that the source of `MaxRetriesExceeded` is the error
+// itself and not its box.
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Gcemeta(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::Deserialize(err) => Some(err),
+            Error::Credentials(err) => Some(err),
+            Error::Jwt(err)
+            | Error::SubjectToken(err)
+            | Error::Authorization(err)
+            | Error::Verify(err)
+            | Error::TokenSource(err) => Some(&**err),
+            Error::MaxRetriesExceeded { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+impl From<gcemeta::Error> for Error {
+    fn from(err: gcemeta::Error) -> Self {
+        Error::Gcemeta(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<crate::credentials::Error> for Error {
+    fn from(err: crate::credentials::Error) -> Self {
+        Error::Credentials(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Deserialize(err)
+    }
+}
+
+impl Error {
+    /// Returns true if the error may be transient, e.g. a connection error, a timeout, a 429 or a
+    /// 5xx response. Other errors, e.g. `invalid_grant` for a deleted key, fail without retrying.
+    pub fn is_retryable(&self) -> bool {
+        fn retryable_status(status: hyper::StatusCode) -> bool {
+            status == hyper::StatusCode::REQUEST_TIMEOUT
+                || status == hyper::StatusCode::TOO_MANY_REQUESTS
+                || status.is_server_error()
+        }
+
+        match self {
+            Error::Gcemeta(_) | Error::Io(_) | Error::Timeout | Error::RateLimited { .. } => true,
+            Error::Http(status) | Error::OAuth { status, .. } => retryable_status(*status),
+            // The subject token may be fetched from a server too, e.g. the AWS metadata server.
+            Error::SubjectToken(_) => true,
+            Error::Chain(errors) => errors.iter().any(Error::is_retryable),
+            Error::MaxRetriesExceeded { source, .. } => source.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Returns the delay that the server asked to wait before retrying, if any.
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            Error::RateLimited { retry_after } => Some(*retry_after),
+            Error::MaxRetriesExceeded { source, .. } => source.retry_after(),
+            _ => None,
+        }
+    }
+}
+
 /// Wrapper for the `Result` type with an [`Error`](Error).
 pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as _;
+
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<Error>();
+
+        let err = Error::MaxRetriesExceeded {
+            attempts: 4,
+            source: Box::new(Error::Jwt("invalid key".into())),
+        };
+        let source = err.source().unwrap();
+        assert!(matches!(source.downcast_ref::<Error>(), Some(Error::Jwt(_))));
+        assert_eq!(source.source().unwrap().to_string(), "invalid key");
+
+        assert!(Error::Http(hyper::StatusCode::NOT_FOUND).source().is_none());
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let oauth =
+            |status, code: &str| Error::OAuth { status, code: code.into(), description: None };
+        assert!(Error::Timeout.is_retryable());
+        assert!(Error::Http(hyper::StatusCode::SERVICE_UNAVAILABLE).is_retryable());
+        assert!(Error::Http(hyper::StatusCode::TOO_MANY_REQUESTS).is_retryable());
+        assert!(!Error::Http(hyper::StatusCode::NOT_FOUND).is_retryable());
+        assert!(!oauth(hyper::StatusCode::BAD_REQUEST, "invalid_grant").is_retryable());
+        assert!(!oauth(hyper::StatusCode::UNAUTHORIZED, "invalid_client").is_retryable());
+        assert!(oauth(hyper::StatusCode::INTERNAL_SERVER_ERROR, "internal_failure").is_retryable());
+        assert!(!Error::Jwt("invalid key".into()).is_retryable());
+        let chain = Error::Chain(vec![Error::Jwt("invalid key".into()), Error::Timeout]);
+        assert!(chain.is_retryable());
+    }
+}
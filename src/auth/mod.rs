@@ -1,6 +1,14 @@
-use std::task::{self, Poll};
+use std::{
+    fmt,
+    sync::Arc,
+    task::{self, Poll},
+    time::{Duration, SystemTime},
+};
 
-use hyper::Request;
+use hyper::{
+    header::{HeaderName, HeaderValue, AUTHORIZATION},
+    Request,
+};
 
 use crate::Credentials;
 
@@ -9,13 +17,67 @@ mod error;
 mod oauth2;
 
 pub use error::*;
-use oauth2::{token::Fetcher, Metadata, Oauth2, ServiceAccount, User};
+#[cfg(test)]
+pub(crate) use oauth2::mock;
+use oauth2::{
+    clock::{Clock, SystemClock},
+    token::Fetcher,
+    Chain, ExternalAccount, Metadata, Oauth2, Reload, ServiceAccount, StaticToken, User,
+};
+pub use oauth2::{
+    http::HttpClientConfig,
+    revoke::revoke_token,
+    signer::{JwtSigner, SignFuture},
+    store::{CachedToken, FileTokenStore, TokenStore},
+    sts::{StsExchange, StsToken},
+    token::{TokenLifetime, TokenSnapshot},
+    UserInfo,
+};
+pub(crate) use oauth2::{
+    http::{Builder as HttpBuilder, Client as HttpClient},
+    interactive::authorize as authorize_user,
+    metadata::{get_value as metadata_value, service_account_path, METADATA_FLAVOR, METADATA_IP},
+    store::{write_atomic, TokenCallback},
+    Oauth2Builder,
+};
+use tracing::warn;
 
 #[derive(Clone, Debug)]
 pub(crate) struct Config {
     #[cfg(not(feature = "tonic"))]
     pub enforce_https: bool,
     pub max_retry: u8,
+    // Refreshes the token this long before it expires.
+    pub refresh_buffer: Duration,
+    // Delay before the first retry, doubled on every following retry up to `max_backoff`.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // A `Retry-After` longer than this fails the fetch instead of waiting.
+    pub max_retry_after: Duration,
+    pub fetch_timeout: Option<Duration>,
+    pub token_store: Option<Arc<dyn TokenStore>>,
+    // Called outside of the lock with every fetched token.
+    pub on_token: Option<TokenCallback>,
+    pub http: HttpBuilder,
+    pub header_name: HeaderName,
+    // Uses `token_type` of the token response if `None`.
+    pub header_scheme: Option<String>,
+    // Used if the token response does not include `expires_in`.
+    pub default_expires_in: Duration,
+    // Keeps serving the last token this long past its expiry if refetching it fails.
+    pub stale_grace: Option<Duration>,
+    pub clock: Arc<dyn Clock>,
+    // Refreshes the token in a spawned task before it expires.
+    pub background_refresh: bool,
+    // Also sends an ID token, with its own token state.
+    pub id_token: Option<IdToken>,
+}
+
+// The audience of the ID token sent next to the access token, and the header it is sent with.
+#[derive(Clone, Debug)]
+pub(crate) struct IdToken {
+    pub audience: String,
+    pub header_name: HeaderName,
 }
 
 impl Default for Config {
@@ -24,67 +86,327 @@ impl Default for Config {
             #[cfg(not(feature = "tonic"))]
             enforce_https: true,
             max_retry: 3,
+            refresh_buffer: Duration::from_secs(10),
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(30),
+            max_retry_after: Duration::from_secs(60),
+            fetch_timeout: Some(Duration::from_secs(10)),
+            token_store: None,
+            on_token: None,
+            http: HttpBuilder::default(),
+            header_name: AUTHORIZATION,
+            header_scheme: None,
+            default_expires_in: Duration::from_secs(3600),
+            stale_grace: None,
+            clock: Arc::new(SystemClock),
+            background_refresh: false,
+            id_token: None,
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 enum Inner {
     None,
     ApiKey(api_key::ApiKey),
     Oauth2(oauth2::Oauth2),
 }
 
-impl From<(Credentials, &Config)> for Inner {
-    fn from((credentials, config): (Credentials, &Config)) -> Self {
-        let fetcher: Box<dyn Fetcher> = match credentials {
-            Credentials::None => return Self::None,
-            Credentials::ApiKey(key) => return Self::ApiKey(api_key::ApiKey::new(key)),
-            Credentials::User(user) => Box::new(User::new(user)),
-            Credentials::ServiceAccount(sa) => Box::new(ServiceAccount::new(sa)),
-            Credentials::Metadata(meta) => Box::new(Metadata::new(meta)),
-        };
-        Self::Oauth2(Oauth2::new(fetcher, config.max_retry))
+impl fmt::Debug for Inner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Make it obvious in logs that requests are sent without credentials.
+            Self::None => write!(f, "Unauthenticated"),
+            Self::ApiKey(key) => f.debug_tuple("ApiKey").field(key).finish(),
+            Self::Oauth2(oauth2) => f.debug_tuple("Oauth2").field(oauth2).finish(),
+        }
     }
 }
 
+impl Inner {
+    // Also returns the token state of the ID token if `Config::id_token` is set.
+    fn new(credentials: Credentials, oauth2: Oauth2Builder) -> Result<(Self, Option<Oauth2>)> {
+        Ok(match credentials {
+            Credentials::None | Credentials::ApiKey(_) if oauth2.config.id_token.is_some() => {
+                return Err(Error::InvalidConfig("ID tokens require OAuth 2.0 credentials"));
+            }
+            Credentials::None => (Self::None, None),
+            Credentials::ApiKey(key) => (Self::ApiKey(api_key::ApiKey::new(key)), None),
+            credentials => {
+                let (scopes, kind) = (credentials.scopes(), credentials.kind());
+                // Only `None` and `ApiKey` are not OAuth 2.0 credentials.
+                let fetcher = fetcher(credentials, &oauth2.config)?.unwrap();
+                let id_token = match oauth2.config.id_token {
+                    Some(ref id_token) => Some(id_token_oauth2(&*fetcher, id_token, &oauth2)?),
+                    None => None,
+                };
+                let oauth2 = oauth2.fetcher(fetcher).scopes(scopes).credential_type(kind);
+                (Self::Oauth2(oauth2.try_build()?), id_token)
+            }
+        })
+    }
+}
+
+// The ID token is fetched and refreshed independently of the access token. Only the access token
+// is kept in the token store and passed to `on_token`.
+fn id_token_oauth2(
+    fetcher: &dyn Fetcher,
+    id_token: &IdToken,
+    oauth2: &Oauth2Builder,
+) -> Result<Oauth2> {
+    let fetcher = fetcher.id_token(&id_token.audience).ok_or(Error::InvalidConfig(
+        "only service accounts and the metadata server can issue ID tokens",
+    ))?;
+    let mut builder = Oauth2Builder::default().fetcher(fetcher).credential_type("id_token");
+    builder.config = Config {
+        token_store: None,
+        on_token: None,
+        header_name: id_token.header_name.clone(),
+        header_scheme: None,
+        id_token: None,
+        ..oauth2.config.clone()
+    };
+    builder.try_build()
+}
+
+// Returns `None` if the credentials are not OAuth 2.0 credentials, or an error if they are
+// invalid, e.g. a service account key with a malformed private key.
+fn fetcher(mut credentials: Credentials, config: &Config) -> Result<Option<Box<dyn Fetcher>>> {
+    let reload = credentials.take_reload_file();
+    let fetcher: Box<dyn Fetcher> = match credentials {
+        Credentials::None | Credentials::ApiKey(_) => return Ok(None),
+        Credentials::User(user) => Box::new(User::new(user, http_client(config))),
+        Credentials::ServiceAccount(sa) => {
+            Box::new(ServiceAccount::try_new(sa, http_client(config))?)
+        }
+        Credentials::Metadata(meta) => Box::new(Metadata::new(meta)?),
+        Credentials::StaticToken(token) => Box::new(StaticToken::new(token)),
+        Credentials::ExternalAccount(ea) => {
+            Box::new(ExternalAccount::try_new(*ea, http_client(config))?)
+        }
+        Credentials::Chain(chain) => {
+            let mut fetchers = Vec::new();
+            for credentials in chain {
+                match fetcher(credentials, config)? {
+                    Some(fetcher) => fetchers.push(fetcher),
+                    None => warn!("ignored non OAuth 2.0 credentials in the chain"),
+                }
+            }
+            Box::new(Chain::new(fetchers))
+        }
+    };
+    match reload {
+        Some(file) => Ok(Some(Box::new(Reload::new(file, fetcher, http_client(config))))),
+        None => Ok(Some(fetcher)),
+    }
+}
+
+fn http_client(config: &Config) -> HttpClient {
+    config.http.build()
+}
+
 // https://cloud.google.com/docs/authentication
 #[derive(Clone, Debug)]
 pub(crate) struct Auth {
     inner: Inner,
+    // Sent with its own header next to the access token, see `Config::id_token`.
+    id_token: Option<Oauth2>,
+    quota_project: Option<HeaderValue>,
     #[cfg(not(feature = "tonic"))]
     enforce_https: bool,
 }
 
 impl Auth {
-    pub fn new(credentials: Credentials, config: Config) -> Self {
+    pub fn new(credentials: Credentials, oauth2: Oauth2Builder) -> Result<Self> {
+        let quota_project =
+            credentials.quota_project_id().and_then(|id| HeaderValue::from_str(id).ok());
+        #[cfg(not(feature = "tonic"))]
+        let enforce_https = oauth2.config.enforce_https;
+        let (inner, id_token) = Inner::new(credentials, oauth2)?;
+        Ok(Self {
+            inner,
+            id_token,
+            quota_project,
+            #[cfg(not(feature = "tonic"))]
+            enforce_https,
+        })
+    }
+
+    pub fn fork(&self) -> Self {
+        let inner = match self.inner {
+            Inner::Oauth2(ref oauth2) => Inner::Oauth2(oauth2.fork()),
+            ref inner => inner.clone(),
+        };
         Self {
-            inner: (credentials, &config).into(),
+            inner,
+            id_token: self.id_token.as_ref().map(Oauth2::fork),
+            quota_project: self.quota_project.clone(),
             #[cfg(not(feature = "tonic"))]
-            enforce_https: config.enforce_https,
+            enforce_https: self.enforce_https,
+        }
+    }
+
+    pub fn for_scopes(&self, scopes: &'static [&'static str]) -> Option<Self> {
+        let inner = match self.inner {
+            Inner::Oauth2(ref oauth2) => Inner::Oauth2(oauth2.for_scopes(scopes)?),
+            ref inner => inner.clone(),
+        };
+        Some(Self { inner, ..self.clone() })
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => {
+                oauth2.is_degraded() || self.id_token.as_ref().is_some_and(Oauth2::is_degraded)
+            }
+            _ => false,
         }
     }
 
+    // Both tokens are polled every time, so that their fetches run concurrently.
     #[inline]
     pub fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<()>> {
-        match self.inner {
+        let poll = match self.inner {
             Inner::Oauth2(ref mut oauth2) => oauth2.poll_ready(cx),
             _ => Poll::Ready(Ok(())),
+        };
+        match (poll, self.id_token.as_mut().map(|id_token| id_token.poll_ready(cx))) {
+            (Poll::Ready(Err(err)), _) | (_, Some(Poll::Ready(Err(err)))) => Poll::Ready(Err(err)),
+            (poll, None) | (poll, Some(Poll::Ready(Ok(())))) => poll,
+            (_, Some(Poll::Pending)) => Poll::Pending,
+        }
+    }
+
+    pub fn remaining_lifetime(&self) -> TokenLifetime {
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => oauth2.remaining_lifetime(),
+            _ => TokenLifetime::Unknown,
+        }
+    }
+
+    pub fn token_snapshot(&self) -> Option<TokenSnapshot> {
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => oauth2.token_snapshot(),
+            _ => None,
+        }
+    }
+
+    pub async fn header_value(&mut self) -> Result<HeaderValue> {
+        match self.inner {
+            Inner::Oauth2(ref mut oauth2) => oauth2.header_value().await,
+            _ => Err(Error::NoAccessToken),
+        }
+    }
+
+    pub async fn access_token(&mut self) -> Result<String> {
+        match self.inner {
+            Inner::Oauth2(ref mut oauth2) => oauth2.access_token().await,
+            _ => Err(Error::NoAccessToken),
+        }
+    }
+
+    pub async fn userinfo(&mut self) -> Result<UserInfo> {
+        match self.inner {
+            Inner::Oauth2(ref mut oauth2) => oauth2.userinfo().await,
+            _ => Err(Error::NoAccessToken),
+        }
+    }
+
+    pub async fn force_refresh(&mut self) -> Result<()> {
+        match self.inner {
+            Inner::Oauth2(ref mut oauth2) => oauth2.force_refresh().await?,
+            _ => return Err(Error::NoAccessToken),
+        }
+        match self.id_token {
+            Some(ref mut id_token) => id_token.force_refresh().await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn revoke(&self) -> Result<()> {
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => oauth2.revoke().await,
+            _ => Err(Error::NoAccessToken),
+        }
+    }
+
+    /// Fetches the first tokens concurrently, see `Oauth2::prefetch_all`. Credentials other than
+    /// OAuth 2.0 ones have nothing to fetch.
+    /// The ID tokens are fetched along with the access tokens, and the first error of each is
+    /// returned.
+    pub async fn prefetch_all(auths: Vec<&mut Auth>) -> Vec<Result<()>> {
+        let mut results = auths.iter().map(|_| Ok(())).collect::<Vec<_>>();
+        let (indices, instances): (Vec<_>, Vec<_>) = auths
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, auth)| {
+                let oauth2 = match auth.inner {
+                    Inner::Oauth2(ref mut oauth2) => Some((i, oauth2)),
+                    _ => None,
+                };
+                oauth2.into_iter().chain(auth.id_token.as_mut().map(|id_token| (i, id_token)))
+            })
+            .unzip();
+        for (i, result) in indices.into_iter().zip(Oauth2::prefetch_all(instances).await) {
+            if results[i].is_ok() {
+                results[i] = result;
+            }
+        }
+        results
+    }
+
+    pub fn invalidate(&self) {
+        if let Inner::Oauth2(ref oauth2) = self.inner {
+            oauth2.invalidate();
+        }
+        if let Some(ref id_token) = self.id_token {
+            id_token.invalidate();
+        }
+    }
+
+    /// Returns the expiry of the new access token.
+    pub async fn refresh(&mut self) -> Result<SystemTime> {
+        let expiry = match self.inner {
+            Inner::Oauth2(ref mut oauth2) => oauth2.refresh().await?,
+            _ => return Err(Error::NoAccessToken),
+        };
+        if let Some(ref mut id_token) = self.id_token {
+            id_token.force_refresh().await?;
+        }
+        Ok(expiry)
+    }
+
+    pub async fn shutdown(&self) {
+        if let Inner::Oauth2(ref oauth2) = self.inner {
+            oauth2.shutdown().await;
+        }
+        if let Some(ref id_token) = self.id_token {
+            id_token.shutdown().await;
         }
     }
 
     #[inline]
     pub fn call<B>(&self, req: Request<B>) -> Result<Request<B>> {
+        // There is nothing to leak without credentials, e.g. emulators are usually served over http.
         #[cfg(not(feature = "tonic"))]
-        if self.enforce_https {
+        if self.enforce_https && !matches!(self.inner, Inner::None) {
             check_https(req.uri().scheme_str())?;
         }
 
-        match self.inner {
-            Inner::None => Ok(req),
-            Inner::ApiKey(ref key) => Ok(key.add_query(req)),
-            Inner::Oauth2(ref oauth2) => Ok(oauth2.add_header(req)),
+        let mut req = match self.inner {
+            Inner::None => req,
+            Inner::ApiKey(ref key) => key.add_query(req),
+            Inner::Oauth2(ref oauth2) => oauth2.add_header(req)?,
+        };
+        if let Some(ref id_token) = self.id_token {
+            req = id_token.add_header(req)?;
+        }
+
+        // https://cloud.google.com/apis/docs/system-parameters
+        if let Some(ref quota_project) = self.quota_project {
+            req.headers_mut().entry("x-goog-user-project").or_insert_with(|| quota_project.clone());
         }
+        Ok(req)
     }
 }
 
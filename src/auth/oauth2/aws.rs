@@ -0,0 +1,352 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    future::Future,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use hyper::{client::HttpConnector, header::HeaderValue, Body, Method, Request, Uri};
+use ring::{digest, hmac};
+
+use crate::{
+    auth::{self, oauth2::http::Client},
+    credentials::AwsCredentialSource,
+};
+
+const SESSION_TOKEN_TTL: &str = "x-aws-ec2-metadata-token-ttl-seconds";
+const SESSION_TOKEN: &str = "x-aws-ec2-metadata-token";
+
+fn subject_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> auth::Error {
+    auth::Error::SubjectToken(err.into())
+}
+
+fn non_empty_env(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SecurityCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+impl SecurityCredentials {
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: non_empty_env("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: non_empty_env("AWS_SECRET_ACCESS_KEY")?,
+            token: non_empty_env("AWS_SESSION_TOKEN"),
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Header<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+// https://google.aip.dev/auth/4117#determining-the-subject-token-in-aws
+#[derive(serde::Serialize)]
+struct SubjectToken<'a> {
+    url: String,
+    method: &'a str,
+    headers: Vec<Header<'a>>,
+}
+
+// Builds the subject token from a signed `GetCallerIdentity` request, reading the region and
+// the security credentials from the environment or the EC2 instance metadata.
+#[derive(Clone, Debug)]
+pub struct AwsSubjectProvider {
+    // The instance metadata endpoints are plain http.
+    inner: Client,
+    source: AwsCredentialSource,
+}
+
+impl AwsSubjectProvider {
+    pub(crate) fn new(source: AwsCredentialSource) -> Self {
+        Self { inner: Client::with_connector(HttpConnector::new()), source }
+    }
+
+    pub fn subject_token(
+        &self,
+        audience: &str,
+    ) -> impl Future<Output = auth::Result<String>> + Send + 'static {
+        let (this, audience) = (self.clone(), audience.to_owned());
+        async move {
+            let region =
+                non_empty_env("AWS_REGION").or_else(|| non_empty_env("AWS_DEFAULT_REGION"));
+            let credentials = SecurityCredentials::from_env();
+
+            // IMDSv2 requires a session token, only needed if the metadata is actually read.
+            let session = match this.source.imdsv2_session_token_url {
+                Some(ref url) if region.is_none() || credentials.is_none() => {
+                    Some(this.session_token(url).await?)
+                }
+                _ => None,
+            };
+            let region = match region {
+                Some(region) => region,
+                None => this.region(session.as_ref()).await?,
+            };
+            let credentials = match credentials {
+                Some(credentials) => credentials,
+                None => this.security_credentials(session.as_ref()).await?,
+            };
+
+            let url = this.source.regional_cred_verification_url.replace("{region}", &region);
+            let uri = url.parse::<Uri>().map_err(subject_error)?;
+            subject_token(&uri, &region, &credentials, &audience, SystemTime::now())
+        }
+    }
+
+    async fn session_token(&self, url: &str) -> auth::Result<HeaderValue> {
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri(url.parse::<Uri>().map_err(subject_error)?)
+            .header(SESSION_TOKEN_TTL, "300")
+            .body(Body::empty())
+            .unwrap();
+        let body = self.inner.send_bytes(req).await?;
+        HeaderValue::from_maybe_shared(body).map_err(subject_error)
+    }
+
+    async fn get(&self, url: &str, session: Option<&HeaderValue>) -> auth::Result<String> {
+        let mut req = Request::builder().uri(url.parse::<Uri>().map_err(subject_error)?);
+        if let Some(session) = session {
+            req = req.header(SESSION_TOKEN, session);
+        }
+        let body: Bytes = self.inner.send_bytes(req.body(Body::empty()).unwrap()).await?;
+        String::from_utf8(body.to_vec()).map_err(subject_error)
+    }
+
+    async fn region(&self, session: Option<&HeaderValue>) -> auth::Result<String> {
+        let url =
+            self.source.region_url.as_deref().ok_or_else(|| {
+                subject_error("`region_url` is required unless `AWS_REGION` is set")
+            })?;
+        // The availability zone, e.g. `us-east-1a` of `us-east-1`.
+        let mut region = self.get(url, session).await?.trim().to_owned();
+        region.pop();
+        Ok(region)
+    }
+
+    async fn security_credentials(
+        &self,
+        session: Option<&HeaderValue>,
+    ) -> auth::Result<SecurityCredentials> {
+        let url =
+            self.source.url.as_deref().ok_or_else(|| {
+                subject_error("`url` is required unless `AWS_ACCESS_KEY_ID` is set")
+            })?;
+        let role = self.get(url, session).await?;
+        let url = format!("{}/{}", url.trim_end_matches('/'), role.trim());
+        let credentials = self.get(&url, session).await?;
+        serde_json::from_str(&credentials).map_err(auth::Error::Deserialize)
+    }
+}
+
+fn subject_token(
+    uri: &Uri,
+    region: &str,
+    credentials: &SecurityCredentials,
+    audience: &str,
+    now: SystemTime,
+) -> auth::Result<String> {
+    let host = uri.authority().ok_or_else(|| subject_error("invalid verification url"))?;
+    let amz_date = amz_date(now);
+    let mut headers = BTreeMap::new();
+    headers.insert("host", host.as_str());
+    headers.insert("x-amz-date", amz_date.as_str());
+    if let Some(ref token) = credentials.token {
+        headers.insert("x-amz-security-token", token.as_str());
+    }
+    headers.insert("x-goog-cloud-target-resource", audience);
+
+    let authorization = authorization("POST", uri, &headers, region, "sts", credentials, &amz_date);
+    let mut token = SubjectToken { url: uri.to_string(), method: "POST", headers: Vec::new() };
+    token.headers.push(Header { key: "Authorization", value: &authorization });
+    token.headers.extend(headers.iter().map(|(&key, &value)| Header { key, value }));
+    Ok(uri_encode(&serde_json::to_string(&token).unwrap()))
+}
+
+// https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html
+fn authorization(
+    method: &str,
+    uri: &Uri,
+    headers: &BTreeMap<&str, &str>,
+    region: &str,
+    service: &str,
+    credentials: &SecurityCredentials,
+    amz_date: &str,
+) -> String {
+    let date = &amz_date[..8];
+    let mut query: Vec<_> = uri.query().unwrap_or_default().split('&').collect();
+    query.retain(|pair| !pair.is_empty());
+    query.sort_unstable();
+    let canonical_headers: String =
+        headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let signed_headers = headers.keys().copied().collect::<Vec<_>>().join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        if uri.path().is_empty() { "/" } else { uri.path() },
+        query.join("&"),
+        canonical_headers,
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, b"").as_ref()),
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+    let key = [date, region, service, "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{}", credentials.secret_access_key).into_bytes(), |key, data| {
+            hmac_sha256(&key, data.as_bytes())
+        });
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex(&hmac_sha256(&key, string_to_sign.as_bytes()))
+    )
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data).as_ref().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// e.g. `20150830T123600Z`
+fn amz_date(now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn credentials(token: Option<&str>) -> SecurityCredentials {
+        SecurityCredentials {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            token: token.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn test_amz_date() {
+        let now = UNIX_EPOCH + Duration::from_secs(1440938160);
+        assert_eq!(amz_date(now), "20150830T123600Z");
+        assert_eq!(amz_date(UNIX_EPOCH), "19700101T000000Z");
+    }
+
+    #[test]
+    fn test_authorization() {
+        // `get-vanilla` of the aws sigv4 test suite
+        let uri = Uri::from_static("https://example.amazonaws.com/");
+        let mut headers = BTreeMap::new();
+        headers.insert("host", "example.amazonaws.com");
+        headers.insert("x-amz-date", "20150830T123600Z");
+        assert_eq!(
+            authorization(
+                "GET",
+                &uri,
+                &headers,
+                "us-east-1",
+                "service",
+                &credentials(None),
+                "20150830T123600Z"
+            ),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn test_subject_token() {
+        let uri = Uri::from_static(
+            "https://sts.us-east-1.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15",
+        );
+        let now = UNIX_EPOCH + Duration::from_secs(1440938160);
+        let token =
+            subject_token(&uri, "us-east-1", &credentials(Some("session")), "//iam/xxx", now)
+                .unwrap();
+        assert!(!token.contains(['{', '"']));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&percent_decode(&token)).expect("valid json");
+        assert_eq!(json["method"], "POST");
+        let headers = json["headers"].as_array().unwrap();
+        let keys: Vec<_> = headers.iter().map(|h| h["key"].as_str().unwrap()).collect();
+        assert_eq!(keys, [
+            "Authorization",
+            "host",
+            "x-amz-date",
+            "x-amz-security-token",
+            "x-goog-cloud-target-resource"
+        ]);
+        assert_eq!(headers[4]["value"], "//iam/xxx");
+    }
+
+    fn percent_decode(s: &str) -> String {
+        let mut out = Vec::new();
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            if b == b'%' {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                out.push(u8::from_str_radix(&hex, 16).unwrap());
+            } else {
+                out.push(b);
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt, sync::Arc};
+
+use parking_lot::Mutex;
+use tracing::{debug, info, trace};
+
+use crate::auth::{self, oauth2::token};
+
+// Commits to the first fetcher whose token fetch succeeds. Once committed, fetch errors are
+// returned as is and retried by `Oauth2` rather than failing over to the other fetchers.
+#[derive(Clone)]
+pub struct Chain {
+    fetchers: Arc<Vec<Box<dyn token::Fetcher>>>,
+    committed: Arc<Mutex<Option<usize>>>,
+}
+
+impl Chain {
+    pub(crate) fn new(fetchers: Vec<Box<dyn token::Fetcher>>) -> Self {
+        Self { fetchers: Arc::new(fetchers), committed: Arc::new(Mutex::new(None)) }
+    }
+}
+
+impl fmt::Debug for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Chain")
+            .field("fetchers", &self.fetchers)
+            .field("committed", &*self.committed.lock())
+            .finish()
+    }
+}
+
+impl token::Fetcher for Chain {
+    fn fetch(&self) -> token::ResponseFuture {
+        if let Some(index) = *self.committed.lock() {
+            return self.fetchers[index].fetch();
+        }
+
+        let fetchers = self.fetchers.clone();
+        let committed = self.committed.clone();
+        Box::pin(async move {
+            let mut errors = Vec::with_capacity(fetchers.len());
+            for (index, fetcher) in fetchers.iter().enumerate() {
+                trace!(index, ?fetcher, "try fetching token");
+                match fetcher.fetch().await {
+                    Ok(resp) => {
+                        info!(index, ?fetcher, "committed to credentials");
+                        *committed.lock() = Some(index);
+                        return Ok(resp);
+                    }
+                    Err(err) => {
+                        // Falling through the chain is expected, e.g. off GCE.
+                        debug!(index, error = %err, "failed to fetch token, trying the next one");
+                        errors.push(err);
+                    }
+                }
+            }
+            Err(auth::Error::Chain(errors))
+        })
+    }
+
+    fn refresh_token(&self) -> Option<String> {
+        let index = (*self.committed.lock())?;
+        self.fetchers[index].refresh_token()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::future;
+
+    use super::*;
+    use crate::auth::oauth2::token::Fetcher as _;
+
+    #[derive(Clone, Debug)]
+    struct Fixed {
+        access_token: Option<&'static str>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl token::Fetcher for Fixed {
+        fn fetch(&self) -> token::ResponseFuture {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let resp = match self.access_token {
+                Some(access_token) => Ok(token::Response {
+                    token_type: "Bearer".into(),
+                    access_token: access_token.into(),
+                    expires_in: Some(3600),
+                    expire_time: None,
+                    refresh_token: None,
+                }),
+                None => Err(auth::Error::UnsupportedTokenType("mac".into())),
+            };
+            Box::pin(future::ready(resp))
+        }
+    }
+
+    fn fixed(access_token: Option<&'static str>) -> (Box<dyn token::Fetcher>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        (Box::new(Fixed { access_token, calls: calls.clone() }), calls)
+    }
+
+    #[tokio::test]
+    async fn test_chain() {
+        let (a, a_calls) = fixed(None);
+        let (b, b_calls) = fixed(Some("b"));
+        let (c, c_calls) = fixed(Some("c"));
+        let chain = Chain::new(vec![a, b, c]);
+
+        assert_eq!(chain.fetch().await.unwrap().access_token, "b");
+        assert_eq!(*chain.committed.lock(), Some(1));
+        assert_eq!(chain.fetch().await.unwrap().access_token, "b");
+        assert_eq!(a_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(b_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(c_calls.load(Ordering::SeqCst), 0);
+
+        let chain = Chain::new(vec![fixed(None).0, fixed(None).0]);
+        match chain.fetch().await {
+            Err(auth::Error::Chain(errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("unexpected result: {:?}", other.map(|resp| resp.access_token)),
+        }
+        assert_eq!(*chain.committed.lock(), None);
+    }
+}
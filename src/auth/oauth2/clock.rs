@@ -0,0 +1,64 @@
+use std::{
+    fmt,
+    time::{Instant, SystemTime},
+};
+
+/// The source of the current time of `Oauth2`, so that tests can control the token expiry.
+pub(crate) trait Clock: fmt::Debug + Send + Sync + 'static {
+    fn now(&self) -> SystemTime;
+
+    /// The monotonic counterpart of `now`, see `Token`.
+    fn instant(&self) -> Instant;
+}
+
+#[derive(Debug)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use parking_lot::Mutex;
+
+    use super::Clock;
+
+    // Stands still until advanced.
+    #[derive(Debug)]
+    pub(crate) struct MockClock {
+        start: (SystemTime, Instant),
+        elapsed: Mutex<Duration>,
+    }
+
+    impl MockClock {
+        pub fn new() -> Self {
+            Self { start: (SystemTime::now(), Instant::now()), elapsed: Mutex::new(Duration::ZERO) }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            *self.elapsed.lock() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> SystemTime {
+            self.start.0 + *self.elapsed.lock()
+        }
+
+        fn instant(&self) -> Instant {
+            self.start.1 + *self.elapsed.lock()
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use hyper::Uri;
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{http::Client, sts::StsExchange, subject::SubjectProvider, token},
+    },
+    credentials,
+};
+
+// https://cloud.google.com/iam/docs/workload-identity-federation
+#[derive(Clone)]
+pub struct ExternalAccount {
+    inner: Client,
+    provider: SubjectProvider,
+    audience: String,
+    subject_token_type: String,
+    token_uri: Uri,
+    scopes: &'static [&'static str],
+}
+
+impl ExternalAccount {
+    pub(crate) fn try_new(ea: credentials::ExternalAccount, client: Client) -> auth::Result<Self> {
+        let token_uri = Uri::from_maybe_shared(ea.token_url).map_err(|err| {
+            auth::Error::InvalidExternalAccount {
+                field: "token_url",
+                reason: format!("is not a valid uri: {}", err),
+            }
+        })?;
+        let provider = SubjectProvider::new(ea.credential_source, &ea.subject_token_type);
+        Ok(Self {
+            inner: client,
+            provider,
+            audience: ea.audience,
+            subject_token_type: ea.subject_token_type,
+            token_uri,
+            scopes: ea.scopes,
+        })
+    }
+}
+
+impl fmt::Debug for ExternalAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExternalAccount")
+            .field("provider", &self.provider)
+            .field("audience", &self.audience)
+            .field("subject_token_type", &self.subject_token_type)
+            .field("token_uri", &self.token_uri)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl token::Fetcher for ExternalAccount {
+    fn fetch(&self) -> token::ResponseFuture {
+        let this = self.clone();
+        Box::pin(async move {
+            let subject_token = this.provider.subject_token(&this.audience).await?;
+            let mut exchange =
+                StsExchange::new(this.audience.as_str(), subject_token, this.subject_token_type)
+                    .sts_uri(this.token_uri);
+            // The exchange defaults to `cloud-platform`.
+            if !this.scopes.is_empty() {
+                exchange = exchange.scopes(this.scopes);
+            }
+            Ok(exchange.send(&this.inner).await?.into())
+        })
+    }
+
+    fn with_scopes(&self, scopes: &'static [&'static str]) -> Option<Box<dyn token::Fetcher>> {
+        Some(Box::new(Self { scopes, ..self.clone() }))
+    }
+}
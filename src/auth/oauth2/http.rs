@@ -1,28 +1,160 @@
-use std::future::Future;
+use std::{
+    error::Error as _,
+    fmt,
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use bytes::Bytes;
 use hyper::{
-    body::aggregate,
-    client::HttpConnector,
-    header::{HeaderValue, CONTENT_TYPE, USER_AGENT},
+    body::to_bytes,
+    client::{connect::Connect, HttpConnector, ResponseFuture},
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER, USER_AGENT},
+    http::{request, response::Parts},
     Body, Method, Request, StatusCode, Uri,
 };
-use hyper_rustls::{builderstates::WantsSchemes, HttpsConnector, HttpsConnectorBuilder};
+use tracing::{debug, trace};
 
 use crate::auth;
 
-pub(super) struct Client {
-    inner: hyper::Client<HttpsConnector<HttpConnector>, Body>,
+// A connection reset while a request is in flight says nothing about the server, e.g. a pooled
+// connection was closed by the server, so it is retried right away on a new connection instead
+// of counting as a failed fetch with its backoff.
+const MAX_RECONNECTS: u32 = 1;
+
+// Erases the connector type of `hyper::Client`.
+type Inner = Arc<dyn Fn(Request<Body>) -> ResponseFuture + Send + Sync>;
+
+#[derive(Clone)]
+pub(crate) struct Client {
+    inner: Inner,
     user_agent: HeaderValue,
     content_type: HeaderValue,
 }
 
+/// The connection pool of the client that fetches tokens, which is separate from the one of the
+/// wrapped service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HttpClientConfig {
+    /// The most idle connections kept per host, unlimited by default.
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept, 90 seconds by default.
+    pub idle_timeout: Duration,
+    /// Opens a new connection for every token fetch, e.g. as tokens are fetched about hourly.
+    pub pool_disabled: bool,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        // The defaults of hyper.
+        Self {
+            max_idle_per_host: usize::MAX,
+            idle_timeout: Duration::from_secs(90),
+            pool_disabled: false,
+        }
+    }
+}
+
+// Builds the erased `hyper::Client` from the accumulated `hyper::client::Builder`.
+type MakeInner = Arc<dyn Fn(&hyper::client::Builder) -> Inner + Send + Sync>;
+
+/// Accumulates the configuration of [`Client`], so that the connector and the other settings can
+/// be set in any order.
+#[derive(Clone, Default)]
+pub(crate) struct Builder {
+    inner: hyper::client::Builder,
+    connector: Option<MakeInner>,
+    allow_http: bool,
+}
+
+impl Builder {
+    /// Uses `connector` instead of the https only connector.
+    #[must_use]
+    pub fn connector<C>(mut self, connector: C) -> Self
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        self.connector = Some(Arc::new(move |builder| make_inner(builder, connector.clone())));
+        self
+    }
+
+    /// Also allows `http` uris with the default connector, e.g. local token servers.
+    #[must_use]
+    pub fn allow_http(mut self) -> Self {
+        self.allow_http = true;
+        self
+    }
+
+    /// Sends HTTP/2 pings every `interval`, also while the connection is idle, and closes the
+    /// connection if a ping is not acknowledged within `timeout`.
+    #[must_use]
+    pub fn http2_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.inner
+            .http2_keep_alive_interval(interval)
+            .http2_keep_alive_timeout(timeout)
+            .http2_keep_alive_while_idle(true);
+        self
+    }
+
+    /// Configures the connection pool.
+    #[must_use]
+    pub fn pool(mut self, config: &HttpClientConfig) -> Self {
+        let max_idle_per_host = if config.pool_disabled { 0 } else { config.max_idle_per_host };
+        self.inner.pool_idle_timeout(config.idle_timeout).pool_max_idle_per_host(max_idle_per_host);
+        self
+    }
+
+    pub fn build(&self) -> Client {
+        let inner = match self.connector {
+            Some(ref make_inner) => make_inner(&self.inner),
+            None => make_inner(&self.inner, https_connector(self.allow_http)),
+        };
+        Client::from_inner(inner)
+    }
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("inner", &self.inner)
+            .field("connector", &self.connector.as_ref().map(|_| ".."))
+            .field("allow_http", &self.allow_http)
+            .finish()
+    }
+}
+
+fn make_inner<C>(builder: &hyper::client::Builder, connector: C) -> Inner
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let client = builder.build::<_, Body>(connector);
+    Arc::new(move |req| client.request(req))
+}
+
 impl Client {
     pub fn new() -> Client {
-        let https = connection_builder().https_only().enable_http2().build();
+        Self::builder().build()
+    }
+
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    pub fn with_connector<C>(connector: C) -> Client
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        Self::builder().connector(connector).build()
+    }
+
+    fn from_inner(inner: Inner) -> Client {
         let user_agent =
             concat!("github.com/mechiru/", env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
         Self {
-            inner: hyper::Client::builder().build(https),
+            inner,
             user_agent: HeaderValue::from_static(user_agent),
             content_type: HeaderValue::from_static("application/x-www-form-urlencoded"),
         }
@@ -40,6 +172,15 @@ impl Client {
         req.body(body).unwrap()
     }
 
+    /// Returns a `GET` request to `uri` sent with `authorization`, e.g. for Google's apis.
+    pub fn get(&self, uri: &Uri, authorization: HeaderValue) -> Request<Body> {
+        let mut req = Request::builder().uri(uri).method(Method::GET);
+        let headers = req.headers_mut().unwrap();
+        headers.insert(USER_AGENT, self.user_agent.clone());
+        headers.insert(AUTHORIZATION, authorization);
+        req.body(Body::empty()).unwrap()
+    }
+
     pub fn send<T>(
         &self,
         req: Request<Body>,
@@ -47,28 +188,284 @@ impl Client {
     where
         T: serde::de::DeserializeOwned,
     {
-        let fut = self.inner.request(req);
+        let fut = self.send_bytes(req);
         async {
-            use bytes::Buf as _;
+            let body = fut.await?;
+            serde_json::from_slice(&body).map_err(auth::Error::Deserialize)
+        }
+    }
 
-            let (parts, body) = fut.await?.into_parts();
+    /// Returns the body as is, e.g. for endpoints that do not respond with json.
+    pub fn send_bytes(
+        &self,
+        req: Request<Body>,
+    ) -> impl Future<Output = auth::Result<Bytes>> + Send + 'static {
+        let fut = self.send_raw(req);
+        async {
+            let (parts, body) = fut.await?;
             match parts.status {
-                StatusCode::OK => {
-                    let buf = aggregate(body).await?;
-                    serde_json::from_reader(buf.reader()).map_err(auth::Error::JsonDeserialize)
+                StatusCode::OK => Ok(body),
+                _ => Err(error_response(parts, body)),
+            }
+        }
+    }
+
+    /// Returns the response as is whatever its status code, e.g. to check its headers first.
+    pub fn send_raw(
+        &self,
+        req: Request<Body>,
+    ) -> impl Future<Output = auth::Result<(Parts, Bytes)>> + Send + 'static {
+        let inner = self.inner.clone();
+        async move {
+            let (parts, body) = req.into_parts();
+            let body = to_bytes(body).await?;
+            let mut reconnects = 0;
+            loop {
+                let fut = inner(copy_request(&parts, &body));
+                let result = async {
+                    let (parts, body) = fut.await?.into_parts();
+                    Ok::<_, hyper::Error>((parts, to_bytes(body).await?))
+                };
+                match result.await {
+                    Err(err) if reconnects < MAX_RECONNECTS && connection_reset(&err) => {
+                        debug!(error = %err, "connection reset, retrying on a new connection");
+                        reconnects += 1;
+                    }
+                    result => return Ok(result?),
                 }
-                _ => Err(auth::Error::StatusCode((parts, body))),
             }
         }
     }
 }
 
-#[cfg(feature = "native-certs")]
-fn connection_builder() -> HttpsConnectorBuilder<WantsSchemes> {
-    HttpsConnectorBuilder::new().with_native_roots()
+fn copy_request(parts: &request::Parts, body: &Bytes) -> Request<Body> {
+    let mut req = Request::new(Body::from(body.clone()));
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    req
+}
+
+fn connection_reset(err: &hyper::Error) -> bool {
+    // The connection was closed before the response was complete.
+    if err.is_incomplete_message() {
+        return true;
+    }
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+            );
+        }
+        source = err.source();
+    }
+    false
+}
+
+// https://datatracker.ietf.org/doc/html/rfc6749#section-5.2
+#[derive(serde::Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+pub(crate) fn error_response(parts: Parts, body: Bytes) -> auth::Error {
+    if parts.status == StatusCode::TOO_MANY_REQUESTS {
+        if let Some(retry_after) = retry_after(&parts.headers) {
+            return auth::Error::RateLimited { retry_after };
+        }
+    }
+    match serde_json::from_slice::<ErrorResponse>(&body) {
+        Ok(resp) => auth::Error::OAuth {
+            status: parts.status,
+            code: resp.error,
+            description: resp.error_description,
+        },
+        Err(_) => {
+            trace!("unexpected response: status={}, body={:?}", parts.status, body);
+            auth::Error::Http(parts.status)
+        }
+    }
+}
+
+// Either seconds or an HTTP-date, e.g. `120` or `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = http_date(value)?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+// Only the IMF-fixdate form, the obsolete ones are not to be sent anymore (RFC 9110 5.6.7).
+fn http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let fields = value.split_whitespace().collect::<Vec<_>>();
+    let (day, month, year, time) = match fields[..] {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None,
+    };
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as u64 + 1;
+    let year: u64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|v| v.parse::<u64>().ok());
+    let (h, m, s) = (time.next()??, time.next()??, time.next()??);
+    unix_time(year, month, day, h, m, s)
+}
+
+// The UTC date and time as a `SystemTime`, `None` before the unix epoch or if out of range.
+pub(super) fn unix_time(
+    year: u64,
+    month: u64,
+    day: u64,
+    h: u64,
+    m: u64,
+    s: u64,
+) -> Option<SystemTime> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || h >= 24 || m >= 60 || s >= 61 {
+        return None;
+    }
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    // The date is sent by the server, so a huge year must not overflow.
+    let (y, mp) = if month <= 2 { (year.checked_sub(1)?, month + 9) } else { (year, month - 3) };
+    let (era, yoe) = (y / 400, y % 400);
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era.checked_mul(146_097)?.checked_add(doe)?.checked_sub(719_468)?;
+    let secs = days.checked_mul(86_400)?.checked_add(h * 3600 + m * 60 + s)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client").field("user_agent", &self.user_agent).finish()
+    }
+}
+
+#[cfg(not(any(feature = "native-certs", feature = "webpki-roots", feature = "native-tls")))]
+compile_error!("a TLS backend is required, enable the `rustls-tls` or `native-tls` feature");
+
+#[cfg(any(feature = "native-certs", feature = "webpki-roots"))]
+fn https_connector(allow_http: bool) -> hyper_rustls::HttpsConnector<HttpConnector> {
+    #[cfg(feature = "native-certs")]
+    let builder = hyper_rustls::HttpsConnectorBuilder::new().with_native_roots();
+    #[cfg(not(feature = "native-certs"))]
+    let builder = hyper_rustls::HttpsConnectorBuilder::new().with_webpki_roots();
+    if allow_http {
+        builder.https_or_http().enable_http1().enable_http2().build()
+    } else {
+        builder.https_only().enable_http2().build()
+    }
+}
+
+// Links the system TLS library, i.e. OpenSSL on linux, and trusts its root certificates.
+#[cfg(all(feature = "native-tls", not(any(feature = "native-certs", feature = "webpki-roots"))))]
+fn https_connector(allow_http: bool) -> hyper_tls::HttpsConnector<HttpConnector> {
+    let mut https = hyper_tls::HttpsConnector::new();
+    https.https_only(!allow_http);
+    https
 }
 
-#[cfg(all(not(feature = "native-certs"), feature = "webpki-roots"))]
-fn connection_builder() -> HttpsConnectorBuilder<WantsSchemes> {
-    HttpsConnectorBuilder::new().with_webpki_roots()
+#[cfg(test)]
+mod test {
+    use hyper::Response;
+
+    use super::*;
+
+    #[test]
+    fn test_error_response() {
+        let parts = || Response::builder().status(400).body(()).unwrap().into_parts().0;
+
+        let body =
+            r#"{"error":"invalid_grant","error_description":"Token has been expired or revoked."}"#;
+        match error_response(parts(), Bytes::from(body)) {
+            auth::Error::OAuth { status, code, description } => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(code, "invalid_grant");
+                assert_eq!(description.as_deref(), Some("Token has been expired or revoked."));
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        match error_response(parts(), Bytes::from("<html></html>")) {
+            auth::Error::Http(status) => assert_eq!(status, StatusCode::BAD_REQUEST),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let parts = |retry_after: Option<&str>| {
+            let mut builder = Response::builder().status(429);
+            if let Some(value) = retry_after {
+                builder = builder.header(RETRY_AFTER, value);
+            }
+            builder.body(()).unwrap().into_parts().0
+        };
+        let error = |retry_after| error_response(parts(retry_after), Bytes::new());
+
+        assert!(matches!(
+            error(Some("120")),
+            auth::Error::RateLimited { retry_after } if retry_after == Duration::from_secs(120)
+        ));
+        // already passed
+        assert!(matches!(
+            error(Some("Sun, 06 Nov 1994 08:49:37 GMT")),
+            auth::Error::RateLimited { retry_after } if retry_after == Duration::ZERO
+        ));
+        assert!(matches!(error(None), auth::Error::Http(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(matches!(error(Some("soon")), auth::Error::Http(StatusCode::TOO_MANY_REQUESTS)));
+
+        let date = http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(date.duration_since(UNIX_EPOCH).unwrap(), Duration::from_secs(784_111_777));
+        assert!(http_date("Sunday, 06-Nov-94 08:49:37 GMT").is_none());
+        assert!(http_date("Sun, 06 Nov 18446744073709551615 08:49:37 GMT").is_none());
+        assert!(http_date("Sun, 06 Nov 999999999999999 08:49:37 GMT").is_none());
+        assert!(http_date("Sun, 00 Nov 1994 08:49:37 GMT").is_none());
+        assert!(http_date("Sun, 06 Nov 1994 24:00:00 GMT").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        use std::io::{Read as _, Write as _};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let mut incoming = listener.incoming();
+            // closed without a response
+            let mut stream = incoming.next().unwrap().unwrap();
+            assert!(stream.read(&mut [0; 4096]).unwrap() > 0);
+            drop(stream);
+            let mut stream = incoming.next().unwrap().unwrap();
+            assert!(stream.read(&mut [0; 4096]).unwrap() > 0);
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").unwrap();
+        });
+
+        let client = Client::with_connector(HttpConnector::new());
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let (parts, body) = client.send_raw(req).await.unwrap();
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body, "ok");
+    }
+
+    #[test]
+    fn test_builder() {
+        let builder = Client::builder()
+            .http2_keepalive(Duration::from_secs(30), Duration::from_secs(10))
+            .connector(HttpConnector::new());
+        assert!(format!("{:?}", builder).contains(r#"connector: Some("..")"#));
+        // the connector is kept when other settings are changed afterwards
+        let builder = builder.http2_keepalive(Duration::from_secs(60), Duration::from_secs(10));
+        assert!(builder.connector.is_some());
+        builder.build();
+    }
 }
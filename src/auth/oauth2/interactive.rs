@@ -0,0 +1,236 @@
+use std::net::Ipv4Addr;
+
+use hyper::Uri;
+use ring::{
+    digest,
+    rand::{SecureRandom as _, SystemRandom},
+};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+};
+use tracing::trace;
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{http::Client, token},
+    },
+    credentials::{self, DEFAULT_UNIVERSE_DOMAIN},
+};
+
+const AUTH_URI: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+
+fn authorization_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> auth::Error {
+    auth::Error::Authorization(err.into())
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7636
+struct Pkce {
+    verifier: String,
+    challenge: String,
+}
+
+impl Pkce {
+    fn new(verifier: String) -> Self {
+        let digest = digest::digest(&digest::SHA256, verifier.as_bytes());
+        Self { challenge: base64::encode_config(digest, base64::URL_SAFE_NO_PAD), verifier }
+    }
+}
+
+// 43 url safe characters, the shortest `code_verifier` allowed.
+fn random(rng: &SystemRandom) -> auth::Result<String> {
+    let mut bytes = [0; 32];
+    rng.fill(&mut bytes).map_err(|_| authorization_error("failed to generate random bytes"))?;
+    Ok(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+#[derive(serde::Serialize)]
+struct AuthQuery<'a> {
+    client_id: &'a str,
+    redirect_uri: &'a str,
+    response_type: &'a str,
+    scope: &'a str,
+    code_challenge: &'a str,
+    code_challenge_method: &'a str,
+    state: &'a str,
+    // Google only returns a refresh token for offline access, and only on the first consent
+    // unless it is prompted again.
+    access_type: &'a str,
+    prompt: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    client_id: &'a str,
+    client_secret: &'a str,
+    code: &'a str,
+    code_verifier: &'a str,
+    grant_type: &'a str,
+    redirect_uri: &'a str,
+}
+
+// The query of the redirect from the consent page.
+#[derive(Debug, serde::Deserialize)]
+struct Redirect {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+// https://developers.google.com/identity/protocols/oauth2/native-app
+pub(crate) async fn authorize(
+    client_id: String,
+    client_secret: String,
+    scopes: &'static [&'static str],
+    present_url: impl FnOnce(&str),
+) -> auth::Result<credentials::User> {
+    let listener =
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.map_err(authorization_error)?;
+    let port = listener.local_addr().map_err(authorization_error)?.port();
+    let redirect_uri = format!("http://127.0.0.1:{}", port);
+
+    let rng = SystemRandom::new();
+    let (pkce, state) = (Pkce::new(random(&rng)?), random(&rng)?);
+    let query = serde_urlencoded::to_string(AuthQuery {
+        client_id: &client_id,
+        redirect_uri: &redirect_uri,
+        response_type: "code",
+        scope: &scopes.join(" "),
+        code_challenge: &pkce.challenge,
+        code_challenge_method: "S256",
+        state: &state,
+        access_type: "offline",
+        prompt: "consent",
+    })
+    .unwrap();
+    present_url(&format!("{}?{}", AUTH_URI, query));
+
+    let code = wait_for_code(&listener, &state).await?;
+    let client = Client::new();
+    let req = client.request(&Uri::from_static("https://oauth2.googleapis.com/token"), &Payload {
+        client_id: &client_id,
+        client_secret: &client_secret,
+        code: &code,
+        code_verifier: &pkce.verifier,
+        grant_type: "authorization_code",
+        redirect_uri: &redirect_uri,
+    });
+    let resp = client.send::<token::Response>(req).await?;
+    let refresh_token = match resp.refresh_token {
+        Some(refresh_token) => refresh_token,
+        None => return Err(authorization_error("the token response has no refresh token")),
+    };
+    Ok(credentials::User {
+        scopes,
+        client_id,
+        client_secret,
+        refresh_token,
+        quota_project_id: None,
+        universe_domain: DEFAULT_UNIVERSE_DOMAIN.to_owned(),
+        on_refresh_token: None,
+        reload_path: None,
+    })
+}
+
+// Serves the redirect of the browser, other requests such as `/favicon.ico` get a 404.
+async fn wait_for_code(listener: &TcpListener, state: &str) -> auth::Result<String> {
+    loop {
+        let (mut stream, _) = listener.accept().await.map_err(authorization_error)?;
+        let redirect = request_target(&mut stream)
+            .await
+            .as_deref()
+            .and_then(|target| target.split_once('?'))
+            .and_then(|(_, query)| serde_urlencoded::from_str::<Redirect>(query).ok());
+        let redirect = match redirect {
+            Some(redirect) if redirect.code.is_some() || redirect.error.is_some() => redirect,
+            _ => {
+                respond(&mut stream, "404 Not Found", "not found").await;
+                continue;
+            }
+        };
+        trace!("received the redirect: error={:?}", redirect.error);
+
+        // Otherwise the code may have been requested by someone else (CSRF).
+        if redirect.state.as_deref() != Some(state) {
+            respond(&mut stream, "400 Bad Request", "Authorization failed: state mismatch.").await;
+            return Err(authorization_error("the state of the redirect does not match"));
+        }
+        return match redirect.code {
+            Some(code) if redirect.error.is_none() => {
+                respond(&mut stream, "200 OK", "Authorized, you can close this window.").await;
+                Ok(code)
+            }
+            _ => {
+                respond(&mut stream, "200 OK", "Authorization failed, see the application.").await;
+                let error = redirect.error.unwrap_or_default();
+                Err(authorization_error(format!("the authorization was denied: {}", error)))
+            }
+        };
+    }
+}
+
+// Returns the request target of the request line, e.g. `/?state=xxx&code=xxx`.
+async fn request_target(stream: &mut TcpStream) -> Option<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(2).any(|w| w == b"\r\n") {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 || buf.len() > 8192 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let line = std::str::from_utf8(&buf).ok()?.lines().next()?;
+    line.split(' ').nth(1).map(str::to_owned)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let resp = format!(
+        "HTTP/1.1 {}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    // Only the browser sees the response, the flow does not depend on it.
+    if let Err(err) = stream.write_all(resp.as_bytes()).await {
+        trace!("failed to respond to the browser: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pkce() {
+        // https://datatracker.ietf.org/doc/html/rfc7636#appendix-B
+        let pkce = Pkce::new("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_owned());
+        assert_eq!(pkce.challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+        assert_eq!(random(&SystemRandom::new()).unwrap().len(), 43);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_code() {
+        async fn redirect(port: u16, target: &str) {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await.unwrap();
+            let req = format!("GET {} HTTP/1.1\r\nhost: 127.0.0.1\r\n\r\n", target);
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).await.unwrap();
+        }
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let code = tokio::spawn(async move { wait_for_code(&listener, "xxx").await });
+        redirect(port, "/favicon.ico").await;
+        redirect(port, "/?state=xxx&code=yyy").await;
+        assert_eq!(code.await.unwrap().unwrap(), "yyy");
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let code = tokio::spawn(async move { wait_for_code(&listener, "xxx").await });
+        redirect(port, "/?state=zzz&code=yyy").await;
+        assert!(matches!(code.await.unwrap(), Err(auth::Error::Authorization(_))));
+    }
+}
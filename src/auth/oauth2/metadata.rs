@@ -1,34 +1,180 @@
-use std::{fmt, str::FromStr as _};
+use std::{collections::HashMap, fmt, future::Future, str::FromStr as _, time::Duration};
 
-use futures_util::TryFutureExt as _;
-use hyper::{client::HttpConnector, http::uri::PathAndQuery, Body};
+use hyper::{
+    client::HttpConnector,
+    http::{response::Parts, uri::Authority},
+    Body, Request, StatusCode, Uri,
+};
+use parking_lot::{const_mutex, Mutex};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tracing::debug;
 
 use crate::{
-    auth::{self, oauth2::token},
+    auth::{
+        self,
+        oauth2::{
+            http::{error_response, Client},
+            token,
+        },
+    },
     credentials,
 };
 
+pub(crate) const METADATA_FLAVOR: &str = "metadata-flavor";
+
+// The same address as the other client libraries, to avoid a DNS lookup.
+pub(crate) const METADATA_IP: &str = "169.254.169.254";
+
+// Transient errors, e.g. during live migrations or the startup of the instance, are retried within
+// a single request, independently of `max_retry`: 100ms, 200ms, 400ms and 800ms.
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+// The unreserved characters of RFC 3986 and `@`, which keep an email readable in a path segment.
+const PATH_SEGMENT: &AsciiSet =
+    &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~').remove(b'@');
+
+// Something other than the metadata server may answer, e.g. a proxy or a captive portal, whose
+// html would otherwise fail as an obscure deserialize error.
+fn check_flavor(parts: &Parts) -> auth::Result<()> {
+    match parts.headers.get(METADATA_FLAVOR) {
+        Some(v) if v == "Google" => Ok(()),
+        _ => Err(auth::Error::MetadataFlavorMissing),
+    }
+}
+
+// The client set with `credentials::Metadata::with_connector`, or the default one.
+fn client(meta: &credentials::Metadata) -> Client {
+    if let Some(ref client) = meta.client {
+        return client.clone();
+    }
+    // Fails fast if the metadata server has gone, instead of waiting for the TCP timeout.
+    let mut http = HttpConnector::new();
+    http.set_connect_timeout(Some(meta.timeout));
+    Client::with_connector(http)
+}
+
+// The `host:port` of the metadata server, overridden by `GCE_METADATA_HOST` or
+// `credentials::Metadata::with_host`. The literal IP needs no DNS, which minimal containers may
+// not have.
+fn authority(meta: &credentials::Metadata) -> auth::Result<Authority> {
+    let host = meta.host.as_deref().unwrap_or(METADATA_IP);
+    match Authority::from_str(host) {
+        // Userinfo would be sent to the host, and is never part of a metadata server address.
+        Ok(authority) if !authority.as_str().contains('@') => Ok(authority),
+        _ => Err(auth::Error::InvalidMetadataHost(host.to_owned())),
+    }
+}
+
+/// Returns the path of the service account `account` below `instance/`, `default` if `None`.
+pub(crate) fn service_account_path(account: Option<&str>) -> String {
+    let account = account.unwrap_or("default");
+    format!("instance/service-accounts/{}", utf8_percent_encode(account, PATH_SEGMENT))
+}
+
+/// Reads `path` of the metadata server, e.g. `project/project-id`. The values never change, so
+/// they are cached for the lifetime of the process.
+pub(crate) async fn get_value(meta: &credentials::Metadata, path: &str) -> auth::Result<String> {
+    static CACHE: Mutex<Option<HashMap<String, String>>> = const_mutex(None);
+
+    let uri = format!("http://{}/computeMetadata/v1/{}", authority(meta)?, path);
+    if let Some(value) = CACHE.lock().as_ref().and_then(|cache| cache.get(&uri)) {
+        return Ok(value.clone());
+    }
+    let parsed = Uri::from_str(&uri).map_err(gcemeta::Error::Uri)?;
+    let client = client(meta);
+    let value = retry_transient(|| {
+        let req =
+            Request::get(&parsed).header(METADATA_FLAVOR, "Google").body(Body::empty()).unwrap();
+        let fut = client.send_raw(req);
+        async move {
+            let (parts, body) = fut.await?;
+            check_flavor(&parts)?;
+            if parts.status != StatusCode::OK {
+                return Err(error_response(parts, body));
+            }
+            Ok(String::from_utf8_lossy(&body).trim().to_owned())
+        }
+    })
+    .await?;
+    CACHE.lock().get_or_insert_with(HashMap::new).insert(uri, value.clone());
+    Ok(value)
+}
+
+async fn retry_transient<T, F, Fut>(mut f: F) -> auth::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = auth::Result<T>>,
+{
+    let (mut attempts, mut backoff) = (1, INITIAL_BACKOFF);
+    loop {
+        match f().await {
+            Err(err) if attempts < MAX_ATTEMPTS && transient(&err) => {
+                debug!("retrying metadata server error in {:?}: {}", backoff, err);
+                tokio::time::sleep(backoff).await;
+                attempts += 1;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn transient(err: &auth::Error) -> bool {
+    match err {
+        auth::Error::Http(status) | auth::Error::OAuth { status, .. } => {
+            matches!(*status, StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS)
+        }
+        auth::Error::RateLimited { .. } => true,
+        // e.g. a reset connection, but not a refused one when this is not running on GCE.
+        auth::Error::Io(err) => !err.is_connect(),
+        _ => false,
+    }
+}
+
 #[derive(serde::Serialize)]
 struct Query<'a> {
     scopes: &'a str,
 }
 
+// `format=full` adds the claims of the instance, like the other client libraries.
+#[derive(serde::Serialize)]
+struct IdentityQuery<'a> {
+    audience: &'a str,
+    format: &'a str,
+}
+
+#[derive(Clone)]
 pub struct Metadata {
-    inner: gcemeta::Client<HttpConnector, Body>,
-    path_and_query: PathAndQuery,
+    inner: Client,
+    uri: Uri,
+    account: String,
+    scopes: &'static [&'static str],
+    // Fetches ID tokens from the `identity` endpoint instead of access tokens.
+    id_token: bool,
 }
 
 impl Metadata {
-    pub(crate) fn new(meta: Box<credentials::Metadata>) -> Self {
-        let path_and_query = path_and_query(meta.account, meta.scopes);
-        let path_and_query = PathAndQuery::from_str(&path_and_query).unwrap();
-        Self { inner: meta.client, path_and_query }
+    pub(crate) fn new(meta: Box<credentials::Metadata>) -> auth::Result<Self> {
+        let inner = client(&meta);
+        let authority = authority(&meta)?;
+        debug!(endpoint = %authority, "fetching tokens from the metadata server");
+        let path_and_query = path_and_query(meta.account.as_deref(), meta.scopes);
+        // Both parts are valid, the account is percent-encoded.
+        let uri = Uri::builder()
+            .scheme("http")
+            .authority(authority)
+            .path_and_query(path_and_query)
+            .build()
+            .unwrap();
+        let account = meta.account.unwrap_or_else(|| "default".to_owned());
+        Ok(Self { inner, uri, account, scopes: meta.scopes, id_token: false })
     }
 }
 
-fn path_and_query(account: Option<String>, scopes: &'static [&'static str]) -> String {
-    let mut path_and_query = "/computeMetadata/v1/instance/service-accounts/".to_owned();
-    path_and_query.push_str(account.as_ref().map_or("default", String::as_str));
+fn path_and_query(account: Option<&str>, scopes: &'static [&'static str]) -> String {
+    let mut path_and_query = "/computeMetadata/v1/".to_owned();
+    path_and_query.push_str(&service_account_path(account));
     path_and_query.push_str("/token");
     if !scopes.is_empty() {
         path_and_query.push('?');
@@ -40,21 +186,76 @@ fn path_and_query(account: Option<String>, scopes: &'static [&'static str]) -> S
 
 impl fmt::Debug for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Metadata").finish()
+        f.debug_struct("Metadata")
+            .field("host", &self.uri.authority())
+            .field("account", &self.account)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl Metadata {
+    fn identity_uri(&self, audience: &str) -> Option<Uri> {
+        let query = IdentityQuery { audience, format: "full" };
+        let uri = format!(
+            "http://{}/computeMetadata/v1/{}/identity?{}",
+            self.uri.authority()?,
+            service_account_path(Some(&self.account)),
+            serde_urlencoded::to_string(&query).ok()?
+        );
+        Uri::from_str(&uri).ok()
+    }
+
+    fn fetch_once(&self) -> impl Future<Output = auth::Result<token::Response>> + Send + 'static {
+        // Already checked that this process is running on GCE.
+        let req =
+            Request::get(&self.uri).header(METADATA_FLAVOR, "Google").body(Body::empty()).unwrap();
+        let (account, scopes, id_token) = (self.account.clone(), self.scopes, self.id_token);
+        let fut = self.inner.send_raw(req);
+        async move {
+            let (parts, body) = fut.await?;
+            check_flavor(&parts)?;
+            match parts.status {
+                // The `identity` endpoint returns the token as is.
+                StatusCode::OK if id_token => Ok(token::Response::from_id_token(
+                    String::from_utf8_lossy(&body).trim().to_owned(),
+                )),
+                StatusCode::OK => Ok(serde_json::from_slice(&body)?),
+                StatusCode::NOT_FOUND => Err(auth::Error::ServiceAccountNotAttached(account)),
+                StatusCode::BAD_REQUEST if !scopes.is_empty() => Err(auth::Error::MetadataScopes(
+                    scopes.iter().map(|&scope| scope.to_owned()).collect(),
+                )),
+                _ => Err(error_response(parts, body)),
+            }
+        }
     }
 }
 
 impl token::Fetcher for Metadata {
     fn fetch(&self) -> token::ResponseFuture {
-        // Already checked that this process is running on GCE.
-        let fut = self.inner.get_as(self.path_and_query.clone()).map_err(auth::Error::Gcemeta);
-        Box::pin(fut)
+        let this = self.clone();
+        Box::pin(async move { retry_transient(|| this.fetch_once()).await })
+    }
+
+    fn with_scopes(&self, scopes: &'static [&'static str]) -> Option<Box<dyn token::Fetcher>> {
+        if self.id_token {
+            return None;
+        }
+        let authority = self.uri.authority()?;
+        let uri = format!("http://{}{}", authority, path_and_query(Some(&self.account), scopes));
+        Some(Box::new(Self { uri: Uri::from_str(&uri).ok()?, scopes, ..self.clone() }))
+    }
+
+    fn id_token(&self, audience: &str) -> Option<Box<dyn token::Fetcher>> {
+        let uri = self.identity_uri(audience)?;
+        Some(Box::new(Self { uri, scopes: &[], id_token: true, ..self.clone() }))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::auth::mock::{response, serve_responses, FLAVOR};
 
     #[test]
     fn test_path_and_query() {
@@ -72,5 +273,179 @@ mod test {
             &path_and_query(None, &["scope1", "scope2"]),
             "/computeMetadata/v1/instance/service-accounts/default/token?scopes=scope1%2Cscope2"
         );
+
+        assert_eq!(
+            &path_and_query(Some("sa@project.iam.gserviceaccount.com"), &[]),
+            "/computeMetadata/v1/instance/service-accounts/sa@project.iam.gserviceaccount.com/token"
+        );
+    }
+
+    #[test]
+    fn test_uri() {
+        let meta = || credentials::Metadata {
+            scopes: &[],
+            account: None,
+            quota_project_id: None,
+            universe_domain: "googleapis.com".into(),
+            host: None,
+            timeout: std::time::Duration::from_secs(1),
+            client: None,
+        };
+        assert_eq!(
+            Metadata::new(meta().into()).unwrap().uri,
+            "http://169.254.169.254/computeMetadata/v1/instance/service-accounts/default/token"
+        );
+
+        let host = credentials::Metadata { host: Some("localhost:8080".into()), ..meta() }
+            .with_account("sa@project.iam.gserviceaccount.com");
+        assert_eq!(
+            Metadata::new(host.into()).unwrap().uri,
+            "http://localhost:8080/computeMetadata/v1/instance/service-accounts/sa@project.iam.gserviceaccount.com/token"
+        );
+
+        let sa = credentials::Metadata::for_service_account("sa@project.iam.gserviceaccount.com");
+        assert_eq!(
+            Metadata::new(sa.into()).unwrap().uri.path(),
+            "/computeMetadata/v1/instance/service-accounts/sa@project.iam.gserviceaccount.com/token"
+        );
+
+        let meta = meta().with_scopes(&["scope1", "scope2"]);
+        assert_eq!(Metadata::new(meta.into()).unwrap().uri.query(), Some("scopes=scope1%2Cscope2"));
+
+        let sa = credentials::Metadata::for_service_account("sa/../x?y z");
+        assert_eq!(
+            Metadata::new(sa.into()).unwrap().uri.path(),
+            "/computeMetadata/v1/instance/service-accounts/sa%2F..%2Fx%3Fy%20z/token"
+        );
+
+        for host in ["local host", "localhost/path", "user@localhost", ""] {
+            let meta = credentials::Metadata::new().with_host(host);
+            assert!(
+                matches!(Metadata::new(meta.into()), Err(auth::Error::InvalidMetadataHost(_))),
+                "{}",
+                host
+            );
+        }
+    }
+
+    fn meta(host: String) -> credentials::Metadata {
+        credentials::Metadata {
+            scopes: &[],
+            account: None,
+            quota_project_id: None,
+            universe_domain: "googleapis.com".into(),
+            host: Some(host),
+            timeout: Duration::from_secs(1),
+            client: None,
+        }
+    }
+
+    fn fetcher(host: String) -> Metadata {
+        Metadata::new(meta(host).into()).unwrap()
+    }
+
+    const TOKEN: &str = r#"{"access_token":"xxx","expires_in":3600,"token_type":"Bearer"}"#;
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        use token::Fetcher as _;
+
+        let responses = vec![
+            response("503 Service Unavailable", &[FLAVOR], ""),
+            response("429 Too Many Requests", &[FLAVOR], ""),
+            response("200 OK", &[FLAVOR], TOKEN),
+        ];
+        assert_eq!(fetcher(serve_responses(responses)).fetch().await.unwrap().access_token, "xxx");
+
+        assert!(!transient(&auth::Error::Http(StatusCode::NOT_FOUND)));
+    }
+
+    #[tokio::test]
+    async fn test_id_token() {
+        use token::Fetcher as _;
+
+        let meta = meta("localhost:8080".into()).with_account("sa@project.iam.gserviceaccount.com");
+        let meta = Metadata::new(meta.into()).unwrap();
+        assert_eq!(
+            meta.identity_uri("https://example.com?x=1").unwrap(),
+            "http://localhost:8080/computeMetadata/v1/instance/service-accounts/\
+             sa@project.iam.gserviceaccount.com/identity\
+             ?audience=https%3A%2F%2Fexample.com%3Fx%3D1&format=full"
+        );
+        assert!(meta.id_token("https://example.com").unwrap().with_scopes(&["scope1"]).is_none());
+
+        let responses = vec![response("200 OK", &[FLAVOR], "header.e30.sig\n")];
+        let fetcher = fetcher(serve_responses(responses)).id_token("https://example.com").unwrap();
+        let response = fetcher.fetch().await.unwrap();
+        assert_eq!(response.access_token, "header.e30.sig");
+        assert_eq!(response.expires_in, None);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_flavor() {
+        use token::Fetcher as _;
+
+        let responses = vec![response("200 OK", &[], "<html>login</html>")];
+        assert!(matches!(
+            fetcher(serve_responses(responses)).fetch().await,
+            Err(auth::Error::MetadataFlavorMissing)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_value() {
+        let meta = meta(serve_responses(vec![response("200 OK", &[FLAVOR], "project-xxx\n")]));
+        assert_eq!(get_value(&meta, "project/project-id").await.unwrap(), "project-xxx");
+        // cached, the server only answers once
+        assert_eq!(get_value(&meta, "project/project-id").await.unwrap(), "project-xxx");
+
+        // during the startup of the instance
+        let responses = vec![
+            response("503 Service Unavailable", &[FLAVOR], ""),
+            response("200 OK", &[FLAVOR], "123\n"),
+        ];
+        let starting = self::meta(serve_responses(responses));
+        assert_eq!(get_value(&starting, "project/numeric-project-id").await.unwrap(), "123");
+    }
+
+    #[tokio::test]
+    async fn test_connector() {
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            task::{self, Poll},
+        };
+
+        use futures_util::{future::BoxFuture, FutureExt as _, TryFutureExt as _};
+        use token::Fetcher as _;
+        use tower_service::Service;
+
+        type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+        #[derive(Clone)]
+        struct Counting(HttpConnector, Arc<AtomicUsize>);
+
+        impl Service<Uri> for Counting {
+            type Response = tokio::net::TcpStream;
+            type Error = BoxError;
+            type Future = BoxFuture<'static, Result<Self::Response, BoxError>>;
+
+            fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), BoxError>> {
+                self.0.poll_ready(cx).map_err(Into::into)
+            }
+
+            fn call(&mut self, uri: Uri) -> Self::Future {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.call(uri).map_err(Into::into).boxed()
+            }
+        }
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let host = serve_responses(vec![response("200 OK", &[FLAVOR], TOKEN)]);
+        let meta = meta(host).with_connector(Counting(HttpConnector::new(), connects.clone()));
+        assert_eq!(Metadata::new(meta.into()).unwrap().fetch().await.unwrap().access_token, "xxx");
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
     }
 }
@@ -0,0 +1,175 @@
+use std::{
+    collections::VecDeque,
+    io::{Read as _, Write as _},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+use crate::auth::{self, oauth2::token};
+
+// The header that the metadata server adds to every response.
+pub(crate) const FLAVOR: (&str, &str) = ("metadata-flavor", "Google");
+
+// A raw HTTP/1.1 response that closes the connection.
+pub(crate) fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let headers: String =
+        headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+    format!(
+        "HTTP/1.1 {}\r\n{}content-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body
+    )
+}
+
+// Serves a single request with `status` and `body` on a local port. Returns the base url, and
+// a handle that returns the request as received.
+pub(crate) fn serve(status: &str, body: &str) -> (String, thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let resp = response(status, &[], body);
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut req = Vec::new();
+        let mut buf = [0; 4096];
+        // Reads the head, then the body of `content-length`.
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            req.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&req).into_owned();
+            let complete = text.find("\r\n\r\n").is_some_and(|end| {
+                let len = text[..end]
+                    .lines()
+                    .find_map(|line| {
+                        line.to_ascii_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(|len| len.parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                req.len() >= end + 4 + len
+            });
+            if n == 0 || complete {
+                break;
+            }
+        }
+        stream.write_all(resp.as_bytes()).unwrap();
+        String::from_utf8(req).unwrap()
+    });
+    (base, handle)
+}
+
+// Serves every request until the process exits, with the body of the first route whose path
+// prefix matches, as the metadata server does. Returns the `host:port`.
+pub(crate) fn serve_routes(routes: Vec<(&'static str, String)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf).unwrap();
+            let req = String::from_utf8_lossy(&buf[..n]);
+            let path = req.split(' ').nth(1).unwrap_or_default();
+            let resp = match routes.iter().find(|(prefix, _)| path.starts_with(prefix)) {
+                Some((_, body)) => response("200 OK", &[FLAVOR], body),
+                None => response("404 Not Found", &[FLAVOR], ""),
+            };
+            stream.write_all(resp.as_bytes()).unwrap();
+        }
+    });
+    host
+}
+
+// Serves `responses` in order, one per connection. Returns the `host:port`.
+pub(crate) fn serve_responses(responses: Vec<String>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for (resp, stream) in responses.into_iter().zip(listener.incoming()) {
+            let mut stream = stream.unwrap();
+            assert!(stream.read(&mut [0; 4096]).unwrap() > 0);
+            stream.write_all(resp.as_bytes()).unwrap();
+        }
+    });
+    host
+}
+
+#[derive(Debug)]
+struct Step {
+    delay: Duration,
+    result: auth::Result<token::Response>,
+}
+
+// Returns scripted responses in order, panicking if the script runs out.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MockFetcher {
+    script: Arc<Mutex<VecDeque<Step>>>,
+    calls: Arc<AtomicUsize>,
+}
+
+impl MockFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn token(self, access_token: &str, expires_in: u64) -> Self {
+        self.push(Ok(token::Response {
+            token_type: "Bearer".into(),
+            access_token: access_token.into(),
+            expires_in: Some(expires_in),
+            expire_time: None,
+            refresh_token: None,
+        }))
+    }
+
+    // A token response that rotates the refresh token, as Google does for user credentials.
+    pub fn rotated(self, access_token: &str, refresh_token: &str) -> Self {
+        self.push(Ok(token::Response {
+            token_type: "Bearer".into(),
+            access_token: access_token.into(),
+            expires_in: Some(3600),
+            expire_time: None,
+            refresh_token: Some(refresh_token.into()),
+        }))
+    }
+
+    pub fn error(self, err: auth::Error) -> Self {
+        self.push(Err(err))
+    }
+
+    // Delays the last scripted response.
+    pub fn delay(self, delay: Duration) -> Self {
+        self.script.lock().back_mut().expect("no scripted response").delay = delay;
+        self
+    }
+
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    fn push(self, result: auth::Result<token::Response>) -> Self {
+        self.script.lock().push_back(Step { delay: Duration::ZERO, result });
+        self
+    }
+}
+
+impl token::Fetcher for MockFetcher {
+    fn fetch(&self) -> token::ResponseFuture {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let step = self.script.lock().pop_front().expect("no more scripted responses");
+        Box::pin(async move {
+            if !step.delay.is_zero() {
+                tokio::time::sleep(step.delay).await;
+            }
+            step.result
+        })
+    }
+}
@@ -1,54 +1,437 @@
 use std::{
-    convert::TryFrom as _,
-    fmt,
+    collections::HashMap,
+    fmt, mem,
     sync::Arc,
     task::{self, Poll},
-    time::Instant,
+    time::{Duration, Instant, SystemTime},
 };
 
-use hyper::{
-    header::{self, AUTHORIZATION},
-    Request,
-};
-use parking_lot::RwLock;
-use tracing::{info, trace};
+use futures_util::future::Either;
+use hyper::{header, Request};
+use parking_lot::{Mutex, RwLock};
+use ring::rand::{SecureRandom as _, SystemRandom};
+use store::{CachedToken, TokenCallback};
+use tokio::sync::watch;
+use tracing::{debug, error, info, trace, warn};
 
-use crate::{auth, sync::RefGuard};
+use crate::{
+    auth::{self, Config},
+    sync::{RefGuard, Waiters},
+};
 
-mod http;
+pub(super) mod clock;
+pub(super) mod http;
+pub(super) mod signer;
+pub(super) mod store;
 pub(super) mod token;
 
-mod metadata;
+mod aws;
+mod chain;
+mod external_account;
+pub(super) mod interactive;
+pub(super) mod metadata;
+#[cfg(test)]
+pub(crate) mod mock;
+mod reload;
+pub(super) mod revoke;
 mod service_account;
+mod static_token;
+pub(super) mod sts;
+mod subject;
 mod user;
 
+pub use chain::Chain;
+pub use external_account::ExternalAccount;
 pub use metadata::Metadata;
+pub use reload::Reload;
 pub use service_account::ServiceAccount;
-pub use user::User;
+pub use static_token::StaticToken;
+pub use user::{User, UserInfo};
+
+/// Accumulates the configuration of [`Oauth2`]; `fetcher` must be set before `build`.
+///
+/// Defaults: `max_retry` 3, `refresh_buffer` 10s, `backoff` 200ms, `max_backoff` 30s,
+/// `max_retry_after` 60s, `fetch_timeout` 10s and `default_expires_in` 1h. Nonsensical values
+/// panic, as they are programming errors.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Oauth2Builder {
+    fetcher: Option<Box<dyn token::Fetcher>>,
+    scopes: &'static [&'static str],
+    credential_type: &'static str,
+    pub config: Config,
+}
+
+impl Oauth2Builder {
+    #[must_use]
+    pub fn fetcher(mut self, fetcher: Box<dyn token::Fetcher>) -> Self {
+        self.fetcher = Some(fetcher);
+        self
+    }
+
+    #[must_use]
+    pub fn max_retry(mut self, max_retry: u8) -> Self {
+        self.config.max_retry = max_retry;
+        self
+    }
+
+    #[must_use]
+    pub fn refresh_buffer(mut self, refresh_buffer: Duration) -> Self {
+        self.config.refresh_buffer = refresh_buffer;
+        self
+    }
+
+    #[must_use]
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.config.backoff = backoff;
+        self
+    }
+
+    #[must_use]
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.config.max_backoff = max_backoff;
+        self
+    }
+
+    #[must_use]
+    pub fn max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.config.max_retry_after = max_retry_after;
+        self
+    }
+
+    #[must_use]
+    pub fn fetch_timeout(mut self, fetch_timeout: Duration) -> Self {
+        assert!(fetch_timeout > Duration::ZERO, "fetch_timeout must not be zero");
+        self.config.fetch_timeout = Some(fetch_timeout);
+        self
+    }
+
+    #[must_use]
+    pub fn background_refresh(mut self, enabled: bool) -> Self {
+        self.config.background_refresh = enabled;
+        self
+    }
+
+    #[must_use]
+    pub fn stale_grace(mut self, grace: Duration) -> Self {
+        self.config.stale_grace = Some(grace);
+        self
+    }
+
+    #[must_use]
+    pub fn default_expires_in(mut self, expires_in: Duration) -> Self {
+        assert!(expires_in > Duration::ZERO, "default_expires_in must not be zero");
+        self.config.default_expires_in = expires_in;
+        self
+    }
+
+    /// Keeps the HTTP/2 connections to the token endpoint alive, see `http::Builder`.
+    #[must_use]
+    pub fn with_http2_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.config.http = self.config.http.http2_keepalive(interval, timeout);
+        self
+    }
+
+    /// Configures the connection pool of the token endpoint connections, see `http::Builder`.
+    #[must_use]
+    pub fn with_http_client_config(mut self, config: &http::HttpClientConfig) -> Self {
+        self.config.http = self.config.http.pool(config);
+        self
+    }
+
+    /// The fetcher already requests these scopes, they are used for logging and to tell apart
+    /// tokens in the token store.
+    #[must_use]
+    pub fn scopes(mut self, scopes: &'static [&'static str]) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// Recorded as the `credential_type` field of log events, e.g. `service_account`.
+    #[must_use]
+    pub fn credential_type(mut self, credential_type: &'static str) -> Self {
+        self.credential_type = credential_type;
+        self
+    }
+
+    pub fn build(self) -> Oauth2 {
+        self.try_build().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like [`build`](Oauth2Builder::build), but returns an error if the settings are invalid.
+    pub fn try_build(self) -> auth::Result<Oauth2> {
+        // Otherwise a token without `expires_in` would be refetched on every request.
+        if self.config.refresh_buffer >= self.config.default_expires_in {
+            return Err(auth::Error::InvalidConfig(
+                "refresh_buffer must be shorter than default_expires_in",
+            ));
+        }
+        let (shutdown, rx) = watch::channel(false);
+        let scopes = self.scopes;
+        let inner = self.build_inner(rx);
+        if inner.read().config.background_refresh {
+            spawn_refresh(&inner, shutdown.subscribe());
+        }
+        let views = std::iter::once((scopes, inner.clone())).collect::<HashMap<_, _>>();
+        Ok(Oauth2 { inner, views: Arc::new(Mutex::new(views)), shutdown: Arc::new(shutdown) })
+    }
+
+    fn build_inner(self, shutdown: watch::Receiver<bool>) -> Arc<RwLock<Inner>> {
+        let Oauth2Builder { fetcher, scopes, credential_type, config } = self;
+        let fetcher = fetcher.expect("fetcher must be set");
+        let state = match config.token_store.as_ref().and_then(|store| store.load()) {
+            Some(cached) if !cached.has_scopes(scopes) => {
+                debug!(
+                    credential_type,
+                    scopes = ?cached.scopes,
+                    "ignored cached token of other scopes"
+                );
+                State::NotFetched
+            }
+            Some(cached) => match cached.into_token() {
+                Some(token) if !token.expired(&*config.clock, config.refresh_buffer) => {
+                    debug!(credential_type, expiry = ?token.expiry(), "loaded cached token");
+                    State::Fetched { current: token }
+                }
+                _ => State::NotFetched,
+            },
+            None => State::NotFetched,
+        };
+        Arc::new(RwLock::new(Inner {
+            state,
+            fetcher,
+            scopes,
+            credential_type,
+            config,
+            shutdown,
+            waiters: Default::default(),
+            on_token: None,
+        }))
+    }
+}
+
+// The token state of each scopes requested with `Oauth2::for_scopes`. Only `Inner` is kept so
+// that there is no reference cycle.
+type Views = Arc<Mutex<HashMap<&'static [&'static str], Arc<RwLock<Inner>>>>>;
 
 #[derive(Clone)]
-pub(super) struct Oauth2 {
+pub(crate) struct Oauth2 {
     inner: Arc<RwLock<Inner>>,
+    views: Views,
+    // Shared by the views, see `shutdown`.
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl Oauth2 {
-    pub fn new(fetcher: Box<dyn token::Fetcher>, max_retry: u8) -> Self {
-        Self {
-            inner: Arc::new(RwLock::new(Inner { state: State::NotFetched, fetcher, max_retry })),
+    /// Creates a new instance with a copy of the fetcher that shares no token state with this one.
+    pub fn fork(&self) -> Self {
+        let inner = self.inner.read();
+        Oauth2Builder {
+            fetcher: Some(inner.fetcher.clone()),
+            scopes: inner.scopes,
+            credential_type: inner.credential_type,
+            config: inner.config.clone(),
+        }
+        .build()
+    }
+
+    /// Returns a view whose tokens have `scopes`. It fetches with a copy of the fetcher, and its
+    /// token is cached and refreshed independently of this one, but shared by every view of the
+    /// same scopes. `None` if the credentials can not request other scopes.
+    pub fn for_scopes(&self, scopes: &'static [&'static str]) -> Option<Self> {
+        let inner = self.inner.read();
+        if inner.scopes == scopes {
+            return Some(self.clone());
+        }
+        let mut views = self.views.lock();
+        if let Some(view) = views.get(scopes) {
+            return Some(Self {
+                inner: view.clone(),
+                views: self.views.clone(),
+                shutdown: self.shutdown.clone(),
+            });
+        }
+        let fetcher = inner.fetcher.with_scopes(scopes)?;
+        // The token store holds a single token, which is kept for the scopes of the credentials.
+        let config = Config { token_store: None, ..inner.config.clone() };
+        let view = Oauth2Builder {
+            fetcher: Some(fetcher),
+            scopes,
+            credential_type: inner.credential_type,
+            config,
+        }
+        .build_inner(self.shutdown.subscribe());
+        if view.read().config.background_refresh {
+            spawn_refresh(&view, self.shutdown.subscribe());
         }
+        views.insert(scopes, view.clone());
+        Some(Self { inner: view, views: self.views.clone(), shutdown: self.shutdown.clone() })
     }
 
+    /// Returns true while the last token is served past its expiry, see `stale_grace`.
+    pub fn is_degraded(&self) -> bool {
+        matches!(self.inner.read().state, State::Stale { .. })
+    }
+
+    // The locks block instead of failing under contention; they are never held across an await,
+    // and the write lock only while the fetch future is polled.
     pub fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<auth::Result<()>> {
         if self.inner.read().can_skip_poll_ready() {
             return Poll::Ready(Ok(()));
         }
-        self.inner.write().poll_ready(cx)
+        with_write(&self.inner, |inner| inner.poll_ready(cx))
     }
 
     #[inline]
-    pub fn add_header<B>(&self, mut req: Request<B>) -> Request<B> {
-        req.headers_mut().insert(AUTHORIZATION, self.inner.read().value());
-        req
+    pub fn add_header<B>(&self, mut req: Request<B>) -> auth::Result<Request<B>> {
+        let inner = self.inner.read();
+        let value = inner.value().ok_or(auth::Error::TokenNotFetched)?;
+        req.headers_mut().insert(inner.config.header_name.clone(), value);
+        Ok(req)
+    }
+
+    /// Returns the token sent with requests without refreshing it, `None` if none is fetched yet.
+    pub fn token_snapshot(&self) -> Option<token::TokenSnapshot> {
+        self.inner.read().token().map(token::Token::snapshot)
+    }
+
+    /// Returns how long the token sent with requests remains valid, without refreshing it.
+    pub fn remaining_lifetime(&self) -> token::TokenLifetime {
+        let inner = self.inner.read();
+        let clock = &*inner.config.clock;
+        match inner.token() {
+            None => token::TokenLifetime::Unknown,
+            Some(token) if token.expired(clock, inner.config.refresh_buffer) => {
+                token::TokenLifetime::Expiring(token.remaining(clock))
+            }
+            Some(token) => token::TokenLifetime::Valid(token.remaining(clock)),
+        }
+    }
+
+    /// Waits for a valid token and returns the header value, e.g. `Bearer xxx`.
+    pub async fn header_value(&mut self) -> auth::Result<header::HeaderValue> {
+        futures_util::future::poll_fn(|cx| self.poll_ready(cx)).await?;
+        self.inner.read().value().ok_or(auth::Error::TokenNotFetched)
+    }
+
+    /// Waits for a valid token and returns it without the scheme, e.g. `xxx` of `Bearer xxx`.
+    pub async fn access_token(&mut self) -> auth::Result<String> {
+        let value = self.header_value().await?;
+        let value = String::from_utf8_lossy(value.as_bytes());
+        let token = value.split_once(' ').map_or(&*value, |(_, token)| token);
+        Ok(token.to_owned())
+    }
+
+    /// Returns the Google account of the current token from the userinfo endpoint, usually for
+    /// user credentials that were granted the `openid`, `email` or `profile` scopes.
+    pub async fn userinfo(&mut self) -> auth::Result<UserInfo> {
+        self.userinfo_at(&hyper::Uri::from_static(user::USERINFO_URI)).await
+    }
+
+    async fn userinfo_at(&mut self, uri: &hyper::Uri) -> auth::Result<UserInfo> {
+        let token = self.access_token().await?;
+        // The token comes from a valid header value, so it is one with the `Bearer` scheme too.
+        let value = header::HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| auth::Error::TokenNotFetched)?;
+        let client = self.inner.read().config.http.build();
+        client.send(client.get(uri, value)).await
+    }
+
+    /// Fetches the first token of every instance concurrently, e.g. one per tenant at startup,
+    /// rather than one after the other. Returns the result of each instance in order.
+    pub async fn prefetch_all(instances: Vec<&mut Oauth2>) -> Vec<auth::Result<()>> {
+        let fetches = instances
+            .into_iter()
+            .map(|oauth2| futures_util::future::poll_fn(move |cx| oauth2.poll_ready(cx)));
+        futures_util::future::join_all(fetches).await
+    }
+
+    /// Discards the current token, even if it has not expired, so that the next `poll_ready`
+    /// fetches a new one, e.g. after a 401 response. The fetch in flight is kept if there is one,
+    /// so that concurrent invalidations result in a single fetch.
+    pub fn invalidate(&self) {
+        let mut inner = self.inner.write();
+        inner.state = match mem::replace(&mut inner.state, State::NotFetched) {
+            State::Fetched { .. } | State::Stale { .. } => {
+                debug!(credential_type = inner.credential_type, "discarding token");
+                State::NotFetched
+            }
+            State::Refetching { future, attempts, .. } => State::Fetching { future, attempts },
+            state => state,
+        };
+    }
+
+    /// Discards the current token, even if it has not expired, and waits for a new one, e.g. to
+    /// stop using a token that may have leaked. Waits for the fetch in flight instead if there
+    /// is one.
+    pub async fn force_refresh(&mut self) -> auth::Result<()> {
+        if self.inner.read().is_shutdown() {
+            return Err(auth::Error::Shutdown);
+        }
+        self.invalidate();
+        futures_util::future::poll_fn(|cx| self.poll_ready(cx)).await
+    }
+
+    /// Like `force_refresh`, but returns the expiry of the new token.
+    pub async fn refresh(&mut self) -> auth::Result<SystemTime> {
+        self.force_refresh().await?;
+        self.token_snapshot().map(|token| token.expiry).ok_or(auth::Error::TokenNotFetched)
+    }
+
+    /// Revokes the refresh token of user credentials, or the current access token otherwise, e.g.
+    /// on logout. Afterwards, no token is fetched anymore by any view, and `poll_ready` fails with
+    /// `Error::Revoked` instead of retrying.
+    pub async fn revoke(&self) -> auth::Result<()> {
+        self.revoke_at(&hyper::Uri::from_static(revoke::REVOKE_URI)).await
+    }
+
+    async fn revoke_at(&self, uri: &hyper::Uri) -> auth::Result<()> {
+        let (client, token) = {
+            let inner = self.inner.read();
+            // An expired access token can not be used anymore, there is nothing to revoke.
+            let access_token = || {
+                let token = inner.token().filter(|token| !token.is_expired())?;
+                let value = token.value().to_str().ok()?;
+                Some(value.split_once(' ').map_or(value, |(_, token)| token).to_owned())
+            };
+            (inner.config.http.build(), inner.fetcher.refresh_token().or_else(access_token))
+        };
+        if let Some(token) = token {
+            revoke::revoke(&client, uri, &token).await?;
+        }
+        let views = self.views.lock().values().cloned().collect::<Vec<_>>();
+        for inner in views {
+            let mut inner = inner.write();
+            info!(credential_type = inner.credential_type, "revoked credentials");
+            inner.state = State::Revoked;
+            inner.waiters.wake_all();
+        }
+        Ok(())
+    }
+
+    /// Stops fetching new tokens and waits for the fetches in flight of every view to complete,
+    /// e.g. so that they are not dropped in the middle of a request when the runtime shuts down.
+    /// Each fetch is waited for at most `fetch_timeout`. Afterwards, `poll_ready` fails with
+    /// `Error::Shutdown` once there is no token left to serve.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        let views = self.views.lock().values().cloned().collect::<Vec<_>>();
+        for inner in views {
+            let (credential_type, timeout) = {
+                let inner = inner.read();
+                (inner.credential_type, inner.config.fetch_timeout)
+            };
+            let drain = futures_util::future::poll_fn(|cx| {
+                with_write(&inner, |inner| inner.poll_in_flight(cx))
+            });
+            match timeout {
+                Some(timeout) => {
+                    if tokio::time::timeout(timeout, drain).await.is_err() {
+                        warn!(credential_type, "token fetch did not complete before shutdown");
+                    }
+                }
+                None => drain.await,
+            }
+        }
     }
 }
 
@@ -61,42 +444,174 @@ impl fmt::Debug for Oauth2 {
 struct Inner {
     state: State,
     fetcher: Box<dyn token::Fetcher>,
-    max_retry: u8,
+    scopes: &'static [&'static str],
+    credential_type: &'static str,
+    config: Config,
+    shutdown: watch::Receiver<bool>,
+    // The tasks waiting for the fetch in flight, and the waker that the fetch is polled with.
+    waiters: Arc<Waiters>,
+    // The `on_token` call of the last fetched token, made once the lock is released.
+    on_token: Option<Box<dyn FnOnce() + Send + Sync>>,
 }
 
 impl Inner {
     #[inline]
     fn can_skip_poll_ready(&self) -> bool {
-        matches!(self.state, State::Fetched { ref current } if !current.expired(Instant::now()))
+        match self.state {
+            State::Fetched { ref current } => {
+                !current.expired(&*self.config.clock, self.config.refresh_buffer)
+            }
+            _ => false,
+        }
+    }
+
+    // Starts the `attempts`-th fetch, after the backoff delay if it is a retry, or `retry_after`
+    // if the server asked for a longer one.
+    fn fetch(
+        &self,
+        attempts: u8,
+        retry_after: Option<Duration>,
+    ) -> RefGuard<token::ResponseFuture> {
+        debug!(
+            credential_type = self.credential_type,
+            attempts,
+            scopes = ?self.scopes,
+            "fetching token"
+        );
+        let fut = self.fetcher.fetch();
+        let delay = jitter(backoff(self.config.backoff, attempts).min(self.config.max_backoff))
+            .max(retry_after.unwrap_or_default());
+        let timeout = self.config.fetch_timeout;
+        if delay.is_zero() && timeout.is_none() {
+            return RefGuard::new(fut);
+        }
+        let fut: token::ResponseFuture = Box::pin(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, fut)
+                    .await
+                    .unwrap_or_else(|_| Err(auth::Error::Timeout)),
+                None => fut.await,
+            }
+        });
+        RefGuard::new(fut)
     }
 
-    #[inline]
     fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<auth::Result<()>> {
+        // Registered before polling, so that a wake-up in between is not missed. The fetch is
+        // polled with a waker of every waiting task rather than of the current one, which may be
+        // dropped before the fetch completes.
+        self.waiters.register(cx.waker());
+        let waker = task::Waker::from(self.waiters.clone());
+        let poll = self.poll_state(&mut task::Context::from_waker(&waker));
+        if poll.is_ready() {
+            self.waiters.wake_others(cx.waker());
+        }
+        poll
+    }
+
+    #[inline]
+    fn poll_state(&mut self, cx: &mut task::Context<'_>) -> Poll<auth::Result<()>> {
         macro_rules! poll {
             ($variant:ident, $future:expr, $attempts:ident) => {
                 poll!($variant, $future, $attempts,)
             };
             ($variant:ident, $future:expr, $attempts:ident, $($field:ident),*) => {
                 match $future.get_mut().as_mut().poll(cx) {
-                    Poll::Ready(resp) => match resp.and_then(token::Token::try_from) {
-                        Ok(token) => {
-                            trace!("fetched token: expiry={:?}", token.expiry);
+                    Poll::Ready(resp) => match resp.and_then(|resp| {
+                        let refresh_token = resp.refresh_token.clone();
+                        let token = token::Token::from_response(
+                            resp,
+                            self.config.header_scheme.as_deref(),
+                            self.config.default_expires_in,
+                            &*self.config.clock,
+                        )?;
+                        Ok((token, refresh_token))
+                    }) {
+                        Ok((token, refresh_token)) => {
+                            debug!(
+                                credential_type = self.credential_type,
+                                expires_in = ?token.expires_in(),
+                                "fetched token"
+                            );
+                            // Tells how close to `max_retry` fetches routinely get, before the
+                            // retries are exhausted.
+                            if $attempts > 1 {
+                                warn!(
+                                    credential_type = self.credential_type,
+                                    attempts = $attempts,
+                                    max_retry = self.config.max_retry,
+                                    "fetched token after retrying"
+                                );
+                            }
+                            if let Some(ref store) = self.config.token_store {
+                                if let Some(cached) = CachedToken::from_token(&token, self.scopes) {
+                                    store.store(&cached);
+                                }
+                            }
+                            if let Some(TokenCallback(ref f)) = self.config.on_token {
+                                if let Some(cached) = CachedToken::from_token(&token, self.scopes) {
+                                    let f = f.clone();
+                                    self.on_token = Some(Box::new(move || {
+                                        f(&cached, refresh_token.as_deref())
+                                    }));
+                                }
+                            }
                             self.state = State::Fetched { current: token };
                             break Poll::Ready(Ok(()));
                         }
                         Err(err) => {
-                            if $attempts > self.max_retry {
-                                break Poll::Ready(Err(err));
+                            let retry_after = err.retry_after();
+                            let max_retry_after = self.config.max_retry_after;
+                            let retryable = err.is_retryable()
+                                && !matches!(retry_after, Some(d) if d > max_retry_after)
+                                && !self.is_shutdown();
+                            if $attempts > self.config.max_retry || !retryable {
+                                if let Some(last) = self.stale(&err) {
+                                    self.state = State::Stale {
+                                        last,
+                                        retry_at: self.config.clock.instant()
+                                            + STALE_RETRY_INTERVAL,
+                                    };
+                                    break Poll::Ready(Ok(()));
+                                }
+                                error!(
+                                    credential_type = self.credential_type,
+                                    attempts = $attempts,
+                                    max_retry = self.config.max_retry,
+                                    error = %err,
+                                    retryable,
+                                    "failed to fetch token, giving up"
+                                );
+                                // Starts over on the next call, the future has already completed.
+                                self.state = State::Failed {
+                                    retry_at: self.config.clock.instant() + self.config.backoff,
+                                };
+                                if !retryable {
+                                    break Poll::Ready(Err(err));
+                                }
+                                break Poll::Ready(Err(auth::Error::MaxRetriesExceeded {
+                                    attempts: $attempts,
+                                    source: Box::new(err),
+                                }));
                             }
-                            info!("an error occurred during token fetching: attempts={}, err={:?}", $attempts, err);
+                            warn!(
+                                credential_type = self.credential_type,
+                                attempts = $attempts,
+                                error = %err,
+                                "failed to fetch token, retrying"
+                            );
                             self.state = State::$variant {
-                                future: RefGuard::new(self.fetcher.fetch()),
+                                future: self.fetch($attempts + 1, retry_after),
                                 attempts: $attempts + 1,
                                 $(
                                     $field: $field.clone(),
                                 )*
                             };
-                            break Poll::Pending;
+                            // Polls the new future so that the task is woken up when it completes.
+                            continue;
                         }
                     },
                     Poll::Pending => break Poll::Pending,
@@ -106,25 +621,68 @@ impl Inner {
 
         loop {
             match self.state {
+                State::Revoked => break Poll::Ready(Err(auth::Error::Revoked)),
                 State::NotFetched => {
-                    trace!("token is not fetched");
-                    self.state = State::Fetching {
-                        future: RefGuard::new(self.fetcher.fetch()),
-                        attempts: 1,
-                    };
+                    if self.is_shutdown() {
+                        break Poll::Ready(Err(auth::Error::Shutdown));
+                    }
+                    trace!(credential_type = self.credential_type, "token is not fetched");
+                    self.state = State::Fetching { future: self.fetch(1, None), attempts: 1 };
+                    continue;
+                }
+                State::Failed { retry_at } => {
+                    if self.is_shutdown() {
+                        break Poll::Ready(Err(auth::Error::Shutdown));
+                    }
+                    // Waits at least `backoff` so that callers retrying right away after an error
+                    // do not hammer the token endpoint.
+                    let cool_down = retry_at.saturating_duration_since(self.config.clock.instant());
+                    debug!(credential_type = self.credential_type, "starting over a failed fetch");
+                    self.state =
+                        State::Fetching { future: self.fetch(1, Some(cool_down)), attempts: 1 };
                     continue;
                 }
                 State::Fetching { ref mut future, attempts } => poll!(Fetching, future, attempts),
                 State::Refetching { ref mut future, attempts, ref last } => {
                     poll!(Refetching, future, attempts, last)
                 }
+                State::Stale { ref last, retry_at } => {
+                    if !self.within_grace(last) {
+                        warn!(
+                            credential_type = self.credential_type,
+                            "the grace period of the last token is over"
+                        );
+                        self.state = State::NotFetched;
+                        continue;
+                    }
+                    if self.config.clock.instant() < retry_at || self.is_shutdown() {
+                        break Poll::Ready(Ok(()));
+                    }
+                    self.state = State::Refetching {
+                        future: self.fetch(1, None),
+                        attempts: 1,
+                        last: last.clone(),
+                    };
+                    continue;
+                }
                 State::Fetched { ref current } => {
-                    if !current.expired(Instant::now()) {
+                    if !current.expired(&*self.config.clock, self.config.refresh_buffer) {
                         break Poll::Ready(Ok(()));
                     }
-                    trace!("token will expire: expiry={:?}", current.expiry);
+                    if self.is_shutdown() {
+                        // Served until it actually expires.
+                        if current.expired(&*self.config.clock, Duration::ZERO) {
+                            break Poll::Ready(Err(auth::Error::Shutdown));
+                        }
+                        break Poll::Ready(Ok(()));
+                    }
+                    debug!(
+                        credential_type = self.credential_type,
+                        expiry = ?current.expiry(),
+                        "token will expire, refetching"
+                    );
                     self.state = State::Refetching {
-                        future: RefGuard::new(self.fetcher.fetch()),
+                        future: self.fetch(1, None),
                         attempts: 1,
                         last: current.clone(),
                     };
@@ -134,14 +692,81 @@ impl Inner {
         }
     }
 
+    // Completes the fetch in flight, if any. No new fetch is started after `Oauth2::shutdown`.
+    fn poll_in_flight(&mut self, cx: &mut task::Context<'_>) -> Poll<()> {
+        match self.state {
+            State::Fetching { .. } | State::Refetching { .. } => self.poll_ready(cx).map(|_| ()),
+            _ => Poll::Ready(()),
+        }
+    }
+
+    // How long until the token must be refreshed, zero if there is none or it is being fetched.
+    // Returns how long until the next refresh of the background task, `None` once revoked. It is
+    // pending until a request fetches the first token, or while a fetch is in flight.
+    fn poll_refresh_in(&self, cx: &mut task::Context<'_>) -> Poll<Option<Duration>> {
+        let now = self.config.clock.instant();
+        match self.state {
+            // Rounded up, so that the token is due by then.
+            State::Fetched { ref current } => Poll::Ready(Some(
+                current.refresh_in(&*self.config.clock, self.config.refresh_buffer)
+                    + Duration::from_millis(1),
+            )),
+            State::Failed { retry_at } | State::Stale { retry_at, .. } => {
+                Poll::Ready(Some(retry_at.saturating_duration_since(now)))
+            }
+            State::Revoked => Poll::Ready(None),
+            State::NotFetched | State::Fetching { .. } | State::Refetching { .. } => {
+                self.waiters.register(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn is_shutdown(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    // Returns the last token if refetching it failed but it can still be served, see
+    // `Config::stale_grace`.
+    fn stale(&self, err: &auth::Error) -> Option<token::Token> {
+        let last = match self.state {
+            State::Refetching { ref last, .. } if self.within_grace(last) => last.clone(),
+            _ => return None,
+        };
+        warn!(
+            credential_type = self.credential_type,
+            expiry = ?last.expiry(),
+            expired = last.expired(&*self.config.clock, Duration::ZERO),
+            error = %err,
+            "failed to refetch token, serving the last token"
+        );
+        Some(last)
+    }
+
+    fn within_grace(&self, last: &token::Token) -> bool {
+        match self.config.stale_grace {
+            Some(grace) => last.expiry() + grace > self.config.clock.now(),
+            None => false,
+        }
+    }
+
+    // The token sent with requests, `None` until a token is fetched, i.e. if `poll_ready` has
+    // not completed.
     #[inline]
-    fn value(&self) -> header::HeaderValue {
+    fn token(&self) -> Option<&token::Token> {
         match self.state {
-            State::Fetched { ref current } => current.value.clone(),
-            State::Refetching { ref last, .. } => last.value.clone(),
-            _ => unreachable!("invalid state: {:?}", self.state),
+            State::Fetched { ref current } => Some(current),
+            State::Refetching { ref last, .. } | State::Stale { ref last, .. } => Some(last),
+            State::NotFetched | State::Failed { .. } | State::Fetching { .. } | State::Revoked => {
+                None
+            }
         }
     }
+
+    #[inline]
+    fn value(&self) -> Option<header::HeaderValue> {
+        self.token().map(|token| token.value().clone())
+    }
 }
 
 impl fmt::Debug for Inner {
@@ -149,25 +774,782 @@ impl fmt::Debug for Inner {
         f.debug_struct("Inner")
             .field("state", &self.state)
             .field("fetcher", &self.fetcher)
-            .field("max_retry", &self.max_retry)
+            .field("scopes", &self.scopes)
+            .field("credential_type", &self.credential_type)
+            .field("config", &self.config)
+            .field("shutdown", &self.is_shutdown())
             .finish()
     }
 }
 
+// How long a stale token is served before refetching is tried again, so that requests are not
+// held up by retries on every call during an outage.
+const STALE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// Refreshes the token of `inner` before it expires, until `Oauth2::shutdown` or until the last
+// `Oauth2` is dropped, which drops the sender of `shutdown`. Only a weak reference is kept
+// meanwhile, the token state is not kept alive by the task. The first token is fetched by the
+// first request, so that building does not fetch on its own.
+fn spawn_refresh(inner: &Arc<RwLock<Inner>>, mut shutdown: watch::Receiver<bool>) {
+    let weak = Arc::downgrade(inner);
+    tokio::spawn(async move {
+        // The consecutive failed refreshes, each one followed by a longer delay.
+        let mut failures = 0u8;
+        loop {
+            let max_backoff = match weak.upgrade() {
+                Some(inner) => inner.read().config.max_backoff,
+                None => return,
+            };
+            // Zero until a refresh fails.
+            let backoff =
+                jitter(backoff(STALE_RETRY_INTERVAL, failures.saturating_add(1)).min(max_backoff));
+            let due = async {
+                tokio::time::sleep(backoff).await;
+                let delay = futures_util::future::poll_fn(|cx| match weak.upgrade() {
+                    Some(inner) => inner.read().poll_refresh_in(cx),
+                    None => Poll::Ready(None),
+                })
+                .await?;
+                tokio::time::sleep(delay).await;
+                weak.upgrade()
+            };
+            let changed = shutdown.changed();
+            futures_util::pin_mut!(due, changed);
+            let inner = match futures_util::future::select(due, changed).await {
+                Either::Left((Some(inner), _)) => inner,
+                _ => return,
+            };
+            let result = futures_util::future::poll_fn(|cx| {
+                with_write(&inner, |inner| inner.poll_ready(cx))
+            })
+            .await;
+            let credential_type = inner.read().credential_type;
+            match result {
+                Err(auth::Error::Revoked) => return,
+                // The last token is served meanwhile, see `Config::stale_grace`.
+                Ok(()) if matches!(inner.read().state, State::Stale { .. }) => {
+                    failures = failures.saturating_add(1);
+                }
+                Ok(()) => failures = 0,
+                // Requests keep fetching on demand, and return the error.
+                Err(err) if !err.is_retryable() => {
+                    warn!(
+                        credential_type,
+                        error = %err,
+                        "failed to refresh token in the background, stopping"
+                    );
+                    return;
+                }
+                Err(err) => {
+                    warn!(
+                        credential_type,
+                        error = %err,
+                        "failed to refresh token in the background"
+                    );
+                    failures = failures.saturating_add(1);
+                }
+            }
+        }
+    });
+}
+
+// Calls `f` with the write lock held, then the `on_token` callback once it is released, so that
+// the callback can call back into the library without deadlocking.
+fn with_write<T>(inner: &RwLock<Inner>, f: impl FnOnce(&mut Inner) -> T) -> T {
+    let (result, on_token) = {
+        let mut inner = inner.write();
+        let result = f(&mut inner);
+        (result, inner.on_token.take())
+    };
+    if let Some(on_token) = on_token {
+        on_token();
+    }
+    result
+}
+
+// Returns the delay before the `attempts`-th fetch, `base` doubled on every retry.
+fn backoff(base: Duration, attempts: u8) -> Duration {
+    match attempts {
+        0 | 1 => Duration::ZERO,
+        n => base.saturating_mul(1 << u32::from(n - 2).min(16)),
+    }
+}
+
+// Full jitter: a random delay up to `delay`, so that the clients that failed together do not
+// retry together.
+fn jitter(delay: Duration) -> Duration {
+    let mut bytes = [0; 4];
+    if delay.is_zero() || SystemRandom::new().fill(&mut bytes).is_err() {
+        return delay;
+    }
+    delay.mul_f64(f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX))
+}
+
 enum State {
     NotFetched,
+    // Fetching failed, it starts over at `retry_at`.
+    Failed { retry_at: Instant },
     Fetching { future: RefGuard<token::ResponseFuture>, attempts: u8 },
     Refetching { future: RefGuard<token::ResponseFuture>, attempts: u8, last: token::Token },
     Fetched { current: token::Token },
+    // Refetching failed, `last` is served until `Config::stale_grace` past its expiry.
+    Stale { last: token::Token, retry_at: Instant },
+    // See `Oauth2::revoke`, no token is fetched anymore.
+    Revoked,
 }
 
 impl fmt::Debug for State {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::NotFetched => write!(f, "NotFetched"),
-            Self::Fetching { .. } => write!(f, "Fetching"),
-            Self::Refetching { .. } => write!(f, "Refetching"),
-            Self::Fetched { .. } => write!(f, "Fetched"),
+            Self::Failed { .. } => write!(f, "Failed"),
+            Self::Fetching { attempts, .. } => {
+                f.debug_struct("Fetching").field("attempts", attempts).finish()
+            }
+            Self::Refetching { attempts, last, .. } => f
+                .debug_struct("Refetching")
+                .field("attempts", attempts)
+                .field("last", last)
+                .finish(),
+            Self::Fetched { current } => {
+                f.debug_struct("Fetched").field("current", current).finish()
+            }
+            Self::Stale { last, .. } => f.debug_struct("Stale").field("last", last).finish(),
+            Self::Revoked => write!(f, "Revoked"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use futures_util::{future, task::noop_waker};
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::auth::oauth2::{
+        clock::MockClock,
+        mock::{self, MockFetcher},
+    };
+
+    fn oauth2(fetcher: &MockFetcher, max_retry: u8, backoff: Duration) -> Oauth2 {
+        Oauth2Builder::default()
+            .fetcher(Box::new(fetcher.clone()))
+            .max_retry(max_retry)
+            .backoff(backoff)
+            .build()
+    }
+
+    fn state(oauth2: &Oauth2) -> String {
+        format!("{:?}", oauth2.inner.read().state)
+    }
+
+    fn header(oauth2: &Oauth2) -> header::HeaderValue {
+        let req = oauth2.add_header(Request::new(())).unwrap();
+        req.headers()[header::AUTHORIZATION].clone()
+    }
+
+    fn server_error() -> auth::Error {
+        auth::Error::Http(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    #[tokio::test]
+    async fn test_poll_ready() {
+        let fetcher = MockFetcher::new()
+            .token("a", 3600)
+            .delay(Duration::from_millis(10))
+            .token("b", 3600)
+            .delay(Duration::from_millis(10));
+        let mut oauth2 = oauth2(&fetcher, 0, Duration::ZERO);
+        assert_eq!(state(&oauth2), "NotFetched");
+        assert!(matches!(oauth2.add_header(Request::new(())), Err(auth::Error::TokenNotFetched)));
+
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        assert!(oauth2.poll_ready(&mut cx).is_pending());
+        assert!(state(&oauth2).starts_with("Fetching { attempts: 1 }"));
+
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert!(state(&oauth2).starts_with("Fetched"));
+        assert_eq!(header(&oauth2), "Bearer a");
+
+        // the token has expired
+        let last = token::Token::new(header(&oauth2), Duration::ZERO);
+        oauth2.inner.write().state = State::Fetched { current: last };
+        assert!(oauth2.poll_ready(&mut cx).is_pending());
+        assert!(state(&oauth2).starts_with("Refetching { attempts: 1"));
+        // the last token is used until the new one is fetched
+        assert_eq!(header(&oauth2), "Bearer a");
+
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer b");
+        assert_eq!(fetcher.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_access_token() {
+        let fetcher = MockFetcher::new().token("a", 3600);
+        let mut oauth2 = oauth2(&fetcher, 0, Duration::ZERO);
+        assert_eq!(oauth2.access_token().await.unwrap(), "a");
+        assert_eq!(oauth2.header_value().await.unwrap(), "Bearer a");
+        assert_eq!(oauth2.token_snapshot().unwrap().token_type, "Bearer");
+        assert_eq!(fetcher.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let fetcher = MockFetcher::new().token("a", 3600).delay(Duration::from_millis(50));
+        let mut oauth2 = oauth2(&fetcher, 0, Duration::ZERO);
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        assert!(oauth2.poll_ready(&mut cx).is_pending());
+
+        // the fetch in flight completes, although nobody polls it
+        oauth2.clone().shutdown().await;
+        assert!(state(&oauth2).starts_with("Fetched"));
+        assert_eq!(header(&oauth2), "Bearer a");
+        assert!(oauth2.poll_ready(&mut cx).is_ready());
+
+        // but no new token is fetched
+        oauth2.inner.write().state = State::NotFetched;
+        assert!(matches!(oauth2.poll_ready(&mut cx), Poll::Ready(Err(auth::Error::Shutdown))));
+        assert_eq!(fetcher.calls(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_contention() {
+        let fetcher = MockFetcher::new().token("a", 3600).delay(Duration::from_millis(10));
+        let oauth2 = oauth2(&fetcher, 0, Duration::ZERO);
+        let tasks = (0..32)
+            .map(|_| {
+                let mut oauth2 = oauth2.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+                        assert_eq!(header(&oauth2), "Bearer a");
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(fetcher.calls(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_single_flight_refresh() {
+        let clock = Arc::new(MockClock::new());
+        let fetcher =
+            MockFetcher::new().token("a", 3600).token("b", 3600).delay(Duration::from_millis(50));
+        let mut builder = Oauth2Builder::default().fetcher(Box::new(fetcher.clone()));
+        builder.config.clock = clock.clone();
+        let mut oauth2 = builder.build();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+
+        clock.advance(Duration::from_secs(3595));
+        let tasks = (0..16)
+            .map(|_| {
+                let mut oauth2 = oauth2.clone();
+                tokio::spawn(async move {
+                    future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+                    header(&oauth2)
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "Bearer b");
+        }
+        assert_eq!(fetcher.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_force_refresh() {
+        let fetcher =
+            MockFetcher::new().token("a", 3600).token("b", 3600).delay(Duration::from_millis(10));
+        let mut oauth2 = oauth2(&fetcher, 0, Duration::ZERO);
+        oauth2.force_refresh().await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer a");
+
+        // the token has not expired
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        oauth2.inner.write().state = State::Fetched {
+            current: token::Token::new(header(&oauth2), Duration::from_secs(3600)),
+        };
+        oauth2.force_refresh().await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer b");
+        assert!(oauth2.poll_ready(&mut cx).is_ready());
+        assert_eq!(fetcher.calls(), 2);
+
+        // waits for the fetch in flight
+        let fetcher = MockFetcher::new().token("a", 3600).delay(Duration::from_millis(10));
+        let mut oauth2 = self::oauth2(&fetcher, 0, Duration::ZERO);
+        assert!(oauth2.poll_ready(&mut cx).is_pending());
+        oauth2.force_refresh().await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer a");
+        assert_eq!(fetcher.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_remaining_lifetime() {
+        let clock = Arc::new(MockClock::new());
+        let fetcher = MockFetcher::new()
+            .token("a", 3600)
+            .delay(Duration::from_millis(10))
+            .token("b", 3600)
+            .delay(Duration::from_millis(10));
+        let mut builder = Oauth2Builder::default().fetcher(Box::new(fetcher));
+        builder.config.clock = clock.clone();
+        let mut oauth2 = builder.build();
+        assert_eq!(oauth2.remaining_lifetime(), token::TokenLifetime::Unknown);
+
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        assert!(oauth2.poll_ready(&mut cx).is_pending());
+        assert_eq!(oauth2.remaining_lifetime(), token::TokenLifetime::Unknown);
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        let lifetime = Duration::from_secs(3600);
+        assert_eq!(oauth2.remaining_lifetime(), token::TokenLifetime::Valid(lifetime));
+
+        // within the refresh buffer, also while it is refetched
+        clock.advance(Duration::from_secs(3595));
+        let expiring = token::TokenLifetime::Expiring(Duration::from_secs(5));
+        assert_eq!(oauth2.remaining_lifetime(), expiring);
+        // pending until the next token is fetched, the current one still counts meanwhile
+        assert!(oauth2.poll_ready(&mut cx).is_pending());
+        assert!(matches!(oauth2.inner.read().state, State::Refetching { .. }));
+        assert_eq!(oauth2.remaining_lifetime(), expiring);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_all() {
+        let delay = Duration::from_millis(100);
+        let fetchers = [
+            MockFetcher::new().token("a", 3600).delay(delay),
+            MockFetcher::new().error(server_error()).delay(delay),
+            MockFetcher::new().token("c", 3600).delay(delay),
+        ];
+        let mut instances =
+            fetchers.iter().map(|fetcher| oauth2(fetcher, 0, Duration::ZERO)).collect::<Vec<_>>();
+
+        let start = tokio::time::Instant::now();
+        let results = Oauth2::prefetch_all(instances.iter_mut().collect()).await;
+        // the fetches overlap
+        assert!(start.elapsed() < delay * 2);
+        assert!(matches!(results[..], [Ok(()), Err(_), Ok(())]));
+        assert_eq!(header(&instances[0]), "Bearer a");
+        assert_eq!(header(&instances[2]), "Bearer c");
+    }
+
+    #[tokio::test]
+    async fn test_on_token() {
+        let fetcher = MockFetcher::new().token("a", 3600).rotated("b", "refresh-b");
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let oauth2 = Arc::new(Mutex::new(None::<Oauth2>));
+        let mut builder = Oauth2Builder::default().fetcher(Box::new(fetcher));
+        builder.config.on_token = Some(TokenCallback(Arc::new({
+            let (calls, oauth2) = (calls.clone(), oauth2.clone());
+            move |token: &CachedToken, refresh_token: Option<&str>| {
+                // the lock is released, so this does not deadlock
+                let snapshot = oauth2.lock().as_ref().and_then(Oauth2::token_snapshot);
+                assert_eq!(snapshot.map(|snapshot| snapshot.expiry), Some(token.expiry));
+                calls.lock().push((token.value.clone(), refresh_token.map(str::to_owned)));
+            }
+        })));
+        let mut view = builder.build();
+        *oauth2.lock() = Some(view.clone());
+
+        future::poll_fn(|cx| view.poll_ready(cx)).await.unwrap();
+        view.force_refresh().await.unwrap();
+        assert_eq!(*calls.lock(), vec![
+            ("Bearer a".to_owned(), None),
+            ("Bearer b".to_owned(), Some("refresh-b".to_owned())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let clock = Arc::new(MockClock::new());
+        let fetcher =
+            MockFetcher::new().token("a", 3600).token("b", 3600).delay(Duration::from_millis(10));
+        let mut builder = Oauth2Builder::default().fetcher(Box::new(fetcher.clone()));
+        builder.config.clock = clock.clone();
+        let mut oauth2 = builder.build();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer a");
+
+        clock.advance(Duration::from_secs(3595));
+        // the refetch in flight is kept, but the last token is not served anymore
+        let waker = noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        assert!(oauth2.poll_ready(&mut cx).is_pending());
+        assert!(state(&oauth2).starts_with("Refetching"));
+        oauth2.invalidate();
+        oauth2.clone().invalidate();
+        assert_eq!(state(&oauth2), "Fetching { attempts: 1 }");
+        assert!(matches!(oauth2.add_header(Request::new(())), Err(auth::Error::TokenNotFetched)));
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer b");
+        assert_eq!(fetcher.calls(), 2);
+
+        oauth2.invalidate();
+        assert_eq!(state(&oauth2), "NotFetched");
+
+        let fetcher = MockFetcher::new().token("c", 3600);
+        let mut oauth2 = self::oauth2(&fetcher, 0, Duration::ZERO);
+        let expiry = oauth2.refresh().await.unwrap();
+        assert_eq!(Some(expiry), oauth2.token_snapshot().map(|token| token.expiry));
+        assert!(expiry > SystemTime::now() + Duration::from_secs(3500));
+    }
+
+    #[tokio::test]
+    async fn test_userinfo() {
+        let body = r#"{"sub":"1234","email":"user@example.com","email_verified":true}"#;
+        let (base, server) = mock::serve("200 OK", body);
+        let uri = format!("{}/oauth2/v3/userinfo", base);
+
+        let fetcher = MockFetcher::new().token("a", 3600);
+        let mut builder = Oauth2Builder::default().fetcher(Box::new(fetcher));
+        builder.config.http = builder.config.http.allow_http();
+        let mut oauth2 = builder.build();
+        let info = oauth2.userinfo_at(&uri.parse().unwrap()).await.unwrap();
+        assert_eq!(info, UserInfo {
+            sub: "1234".into(),
+            email: Some("user@example.com".into()),
+            email_verified: Some(true),
+            name: None,
+            picture: None,
+        });
+
+        let req = server.join().unwrap().to_ascii_lowercase();
+        assert!(req.starts_with("get /oauth2/v3/userinfo "));
+        assert!(req.contains("authorization: bearer a\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let (base, server) = mock::serve("200 OK", "");
+        let fetcher = MockFetcher::new().token("a", 3600);
+        let mut builder = Oauth2Builder::default().fetcher(Box::new(fetcher.clone()));
+        builder.config.http = builder.config.http.allow_http();
+        let mut oauth2 = builder.build();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+
+        oauth2.revoke_at(&format!("{}/revoke", base).parse().unwrap()).await.unwrap();
+        // the access token, as the fetcher has no refresh token
+        assert!(server.join().unwrap().ends_with("\r\n\r\ntoken=a"));
+        assert_eq!(state(&oauth2), "Revoked");
+        for _ in 0..2 {
+            let result = future::poll_fn(|cx| oauth2.poll_ready(cx)).await;
+            assert!(matches!(result, Err(auth::Error::Revoked)));
+        }
+        oauth2.invalidate();
+        assert!(matches!(oauth2.force_refresh().await, Err(auth::Error::Revoked)));
+        assert_eq!(fetcher.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_background_refresh() {
+        let fetcher = MockFetcher::new().token("a", 1).token("b", 3600);
+        let mut oauth2 = Oauth2Builder::default()
+            .fetcher(Box::new(fetcher.clone()))
+            .background_refresh(true)
+            .build();
+        // the first token is fetched by the first request
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(fetcher.calls(), 0);
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer a");
+        // refreshed within the refresh buffer, clamped to 500ms, without any `poll_ready`
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(header(&oauth2), "Bearer b");
+        assert!(oauth2.inner.read().can_skip_poll_ready());
+        assert_eq!(fetcher.calls(), 2);
+
+        // the task stops with the last clone
+        let inner = Arc::downgrade(&oauth2.inner);
+        drop(oauth2);
+        tokio::task::yield_now().await;
+        assert!(inner.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_background_refresh_permanent_error() {
+        let invalid_grant = auth::Error::OAuth {
+            status: StatusCode::BAD_REQUEST,
+            code: "invalid_grant".into(),
+            description: None,
+        };
+        let fetcher = MockFetcher::new().token("a", 1).error(invalid_grant);
+        let mut oauth2 = Oauth2Builder::default()
+            .fetcher(Box::new(fetcher.clone()))
+            .backoff(Duration::ZERO)
+            .background_refresh(true)
+            .build();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(fetcher.calls(), 2);
+        assert_eq!(state(&oauth2), "Failed");
+
+        // the task has stopped instead of retrying
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(fetcher.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_retry() {
+        let fetcher = MockFetcher::new()
+            .error(server_error())
+            .error(server_error())
+            .error(server_error())
+            .token("a", 3600);
+        let mut oauth2 = oauth2(&fetcher, 2, Duration::ZERO);
+
+        match future::poll_fn(|cx| oauth2.poll_ready(cx)).await {
+            Err(auth::Error::MaxRetriesExceeded { attempts, source }) => {
+                assert_eq!(attempts, 3);
+                assert!(matches!(*source, auth::Error::Http(StatusCode::INTERNAL_SERVER_ERROR)));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(fetcher.calls(), 3);
+        assert_eq!(state(&oauth2), "Failed");
+
+        // starts over
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer a");
+        assert_eq!(fetcher.calls(), 4);
+
+        // after a cool-down
+        let fetcher = MockFetcher::new().error(server_error()).token("a", 3600);
+        let mut oauth2 = self::oauth2(&fetcher, 0, Duration::from_millis(50));
+        assert!(future::poll_fn(|cx| oauth2.poll_ready(cx)).await.is_err());
+        let start = Instant::now();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(fetcher.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_wakes_up() {
+        // both attempts are pending first, so the task is only woken up by the retry future
+        let fetcher = MockFetcher::new()
+            .error(server_error())
+            .delay(Duration::from_millis(10))
+            .token("a", 3600)
+            .delay(Duration::from_millis(10));
+        let mut oauth2 = oauth2(&fetcher, 1, Duration::ZERO);
+        let ready = future::poll_fn(|cx| oauth2.poll_ready(cx));
+        tokio::time::timeout(Duration::from_secs(1), ready).await.unwrap().unwrap();
+        assert_eq!(header(&oauth2), "Bearer a");
+    }
+
+    #[tokio::test]
+    async fn test_driving_task_dropped() {
+        let fetcher = MockFetcher::new().token("a", 3600).delay(Duration::from_millis(10));
+        let oauth2 = oauth2(&fetcher, 0, Duration::ZERO);
+        let mut waiter = oauth2.clone();
+        let waiting =
+            tokio::spawn(async move { future::poll_fn(|cx| waiter.poll_ready(cx)).await });
+        tokio::task::yield_now().await;
+
+        // the fetch is polled last by a task that is gone before it completes
+        let mut driver = oauth2.clone();
+        let waker = noop_waker();
+        assert!(driver.poll_ready(&mut task::Context::from_waker(&waker)).is_pending());
+        drop(driver);
+
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap().unwrap();
+        assert_eq!(header(&oauth2), "Bearer a");
+        assert_eq!(fetcher.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_error() {
+        let invalid_grant = auth::Error::OAuth {
+            status: StatusCode::BAD_REQUEST,
+            code: "invalid_grant".into(),
+            description: None,
+        };
+        let fetcher = MockFetcher::new().error(invalid_grant).token("a", 3600);
+        let mut oauth2 = oauth2(&fetcher, 3, Duration::ZERO);
+
+        // fails without retrying
+        let result = future::poll_fn(|cx| oauth2.poll_ready(cx)).await;
+        assert!(
+            matches!(result, Err(auth::Error::OAuth { ref code, .. }) if code == "invalid_grant")
+        );
+        assert_eq!(fetcher.calls(), 1);
+        assert_eq!(state(&oauth2), "Failed");
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let rate_limited =
+            |millis| auth::Error::RateLimited { retry_after: Duration::from_millis(millis) };
+        let fetcher = MockFetcher::new().error(rate_limited(50)).token("a", 3600);
+        let mut oauth2 = oauth2(&fetcher, 3, Duration::ZERO);
+        let start = Instant::now();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(fetcher.calls(), 2);
+
+        // longer than `max_retry_after`
+        let fetcher = MockFetcher::new().error(rate_limited(120_000)).token("a", 3600);
+        let mut oauth2 = self::oauth2(&fetcher, 3, Duration::ZERO);
+        let result = future::poll_fn(|cx| oauth2.poll_ready(cx)).await;
+        assert_eq!(result.unwrap_err().retry_after(), Some(Duration::from_secs(120)));
+        assert_eq!(fetcher.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_grace() {
+        let clock = Arc::new(MockClock::new());
+        let fetcher = MockFetcher::new()
+            .token("a", 3600)
+            .error(server_error())
+            .error(server_error())
+            .token("b", 3600);
+        let mut builder = Oauth2Builder::default()
+            .fetcher(Box::new(fetcher.clone()))
+            .max_retry(0)
+            .stale_grace(Duration::from_secs(60));
+        builder.config.clock = clock.clone();
+        let mut stale = builder.build();
+
+        future::poll_fn(|cx| stale.poll_ready(cx)).await.unwrap();
+        assert!(!stale.is_degraded());
+        // within the refresh buffer
+        clock.advance(Duration::from_secs(3595));
+        future::poll_fn(|cx| stale.poll_ready(cx)).await.unwrap();
+        assert!(stale.is_degraded());
+        assert_eq!(header(&stale), "Bearer a");
+        // not refetched until the retry interval has passed
+        future::poll_fn(|cx| stale.poll_ready(cx)).await.unwrap();
+        assert_eq!(fetcher.calls(), 2);
+        clock.advance(STALE_RETRY_INTERVAL);
+        future::poll_fn(|cx| stale.poll_ready(cx)).await.unwrap();
+        assert_eq!(fetcher.calls(), 3);
+        assert_eq!(header(&stale), "Bearer a");
+        // the grace period is over
+        clock.advance(Duration::from_secs(60));
+        future::poll_fn(|cx| stale.poll_ready(cx)).await.unwrap();
+        assert!(!stale.is_degraded());
+        assert_eq!(header(&stale), "Bearer b");
+
+        let clock = Arc::new(MockClock::new());
+        let fetcher = MockFetcher::new().token("a", 3600).error(server_error());
+        let mut builder = Oauth2Builder::default().fetcher(Box::new(fetcher)).max_retry(0);
+        builder.config.clock = clock.clone();
+        let mut oauth2 = builder.build();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        clock.advance(Duration::from_secs(3595));
+        assert!(future::poll_fn(|cx| oauth2.poll_ready(cx)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retry_backoff() {
+        let fetcher =
+            MockFetcher::new().error(server_error()).error(server_error()).token("a", 3600);
+        let mut oauth2 = oauth2(&fetcher, 3, Duration::from_millis(10));
+
+        let start = Instant::now();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        // up to 10ms before the 2nd attempt and up to 20ms before the 3rd one
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(fetcher.calls(), 3);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let delay = Duration::from_millis(100);
+        let delays = (0..100).map(|_| jitter(delay)).collect::<Vec<_>>();
+        assert!(delays.iter().all(|&d| d <= delay));
+        assert!(delays.iter().any(|&d| d != delays[0]));
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let base = Duration::from_millis(100);
+        assert_eq!(backoff(base, 1), Duration::ZERO);
+        assert_eq!(backoff(base, 2), base);
+        assert_eq!(backoff(base, 3), base * 2);
+        assert_eq!(backoff(base, 4), base * 4);
+        assert_eq!(backoff(Duration::ZERO, 4), Duration::ZERO);
+        assert_eq!(backoff(base, u8::MAX), base * (1 << 16));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_timeout() {
+        let fetcher = MockFetcher::new().token("a", 3600).delay(Duration::from_secs(1));
+        let mut oauth2 = Oauth2Builder::default()
+            .fetcher(Box::new(fetcher.clone()))
+            .max_retry(0)
+            .fetch_timeout(Duration::from_millis(50))
+            .build();
+        assert!(format!("{:?}", oauth2).contains("fetch_timeout: Some(50ms)"));
+
+        match future::poll_fn(|cx| oauth2.poll_ready(cx)).await {
+            Err(auth::Error::MaxRetriesExceeded { source, .. }) => {
+                assert!(matches!(*source, auth::Error::Timeout));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "fetch_timeout must not be zero")]
+    fn test_zero_fetch_timeout() {
+        let _ = Oauth2Builder::default().fetch_timeout(Duration::ZERO);
+    }
+
+    #[test]
+    #[should_panic(expected = "refresh_buffer must be shorter than default_expires_in")]
+    fn test_refresh_buffer() {
+        let fetcher = MockFetcher::new();
+        let _ = Oauth2Builder::default()
+            .fetcher(Box::new(fetcher))
+            .refresh_buffer(Duration::from_secs(60))
+            .default_expires_in(Duration::from_secs(60))
+            .build();
+    }
+
+    #[tokio::test]
+    async fn test_for_scopes() {
+        #[derive(Clone, Debug)]
+        struct Scoped(MockFetcher);
+
+        impl token::Fetcher for Scoped {
+            fn fetch(&self) -> token::ResponseFuture {
+                self.0.fetch()
+            }
+
+            fn with_scopes(&self, _: &'static [&'static str]) -> Option<Box<dyn token::Fetcher>> {
+                Some(Box::new(self.clone()))
+            }
+        }
+
+        let fetcher = MockFetcher::new().token("a", 3600).token("b", 3600);
+        let mut oauth2 = Oauth2Builder::default()
+            .fetcher(Box::new(Scoped(fetcher.clone())))
+            .scopes(&["a"])
+            .build();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+
+        let mut view = oauth2.for_scopes(&["b"]).unwrap();
+        assert_eq!(state(&view), "NotFetched");
+        future::poll_fn(|cx| view.poll_ready(cx)).await.unwrap();
+        assert_eq!(header(&oauth2), "Bearer a");
+        assert_eq!(header(&view), "Bearer b");
+
+        // views of the same scopes share the token
+        assert_eq!(header(&oauth2.for_scopes(&["b"]).unwrap()), "Bearer b");
+        assert_eq!(header(&view.for_scopes(&["a"]).unwrap()), "Bearer a");
+        assert_eq!(fetcher.calls(), 2);
+
+        let user = Oauth2Builder::default().fetcher(Box::new(fetcher)).build();
+        assert!(user.for_scopes(&["b"]).is_none());
+    }
+}
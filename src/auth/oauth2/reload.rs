@@ -0,0 +1,243 @@
+use std::{error::Error as StdError, fmt, fs, path::Path, sync::Arc, time::SystemTime};
+
+use parking_lot::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{http::Client, token, ExternalAccount, ServiceAccount, User},
+    },
+    credentials::{Credentials, ReloadFile},
+};
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn fetcher(
+    credentials: Credentials,
+    client: Client,
+) -> Result<Box<dyn token::Fetcher>, Box<dyn StdError + Send + Sync>> {
+    match credentials {
+        Credentials::User(user) => Ok(Box::new(User::new(user, client))),
+        Credentials::ServiceAccount(sa) => Ok(Box::new(ServiceAccount::try_new(sa, client)?)),
+        Credentials::ExternalAccount(ea) => Ok(Box::new(ExternalAccount::try_new(*ea, client)?)),
+        _ => Err(
+            "credentials file must contain user, service account or external account credentials"
+                .into(),
+        ),
+    }
+}
+
+struct State {
+    modified: Option<SystemTime>,
+    // Set when the token endpoint rejected the credentials.
+    force: bool,
+    fetcher: Box<dyn token::Fetcher>,
+}
+
+// Re-reads the credentials file when it has changed on disk, e.g. a rotated kubernetes secret.
+#[derive(Clone)]
+pub struct Reload {
+    file: ReloadFile,
+    client: Client,
+    state: Arc<Mutex<State>>,
+}
+
+impl Reload {
+    pub(crate) fn new(file: ReloadFile, fetcher: Box<dyn token::Fetcher>, client: Client) -> Self {
+        let state = State { modified: modified(&file.path), force: false, fetcher };
+        Self { file, client, state: Arc::new(Mutex::new(state)) }
+    }
+
+    // Returns true if the fetcher has been replaced. `modified` is read before taking the lock,
+    // so that the lock is not held while waiting for the file system.
+    fn reload(&self, state: &mut State, modified: Option<SystemTime>) -> bool {
+        if !state.force && modified == state.modified {
+            return false;
+        }
+        state.force = false;
+
+        // The settings that do not come from the file, e.g. the token endpoint, are kept.
+        let result = self
+            .file
+            .load()
+            .map_err(Into::into)
+            .and_then(|credentials| fetcher(credentials, self.client.clone()));
+        match result {
+            Ok(fetcher) => {
+                info!(path = ?self.file.path, "reloaded credentials file");
+                state.modified = modified;
+                state.fetcher = fetcher;
+                true
+            }
+            Err(err) => {
+                warn!(
+                    path = ?self.file.path,
+                    error = %err,
+                    "failed to reload credentials file, keeping the previous credentials"
+                );
+                false
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Reload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reload").field("path", &self.file.path).finish()
+    }
+}
+
+impl token::Fetcher for Reload {
+    fn fetch(&self) -> token::ResponseFuture {
+        let modified = modified(&self.file.path);
+        let fut = {
+            let mut state = self.state.lock();
+            self.reload(&mut state, modified);
+            state.fetcher.fetch()
+        };
+
+        let state = self.state.clone();
+        Box::pin(async move {
+            let resp = fut.await;
+            let status = match resp {
+                Err(auth::Error::Http(status)) => Some(status),
+                Err(auth::Error::OAuth { status, .. }) => Some(status),
+                _ => None,
+            };
+            if matches!(status, Some(status) if status.is_client_error()) {
+                state.lock().force = true;
+            }
+            resp
+        })
+    }
+
+    fn with_scopes(&self, scopes: &'static [&'static str]) -> Option<Box<dyn token::Fetcher>> {
+        let fetcher = self.state.lock().fetcher.with_scopes(scopes)?;
+        let reload = Reload::new(self.file.with_scopes(scopes), fetcher, self.client.clone());
+        Some(Box::new(reload))
+    }
+
+    fn refresh_token(&self) -> Option<String> {
+        self.state.lock().fetcher.refresh_token()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, time::Duration};
+
+    use hyper::client::HttpConnector;
+
+    use super::*;
+    use crate::{
+        auth::oauth2::{mock, token::Fetcher as _},
+        credentials,
+    };
+
+    fn write(path: &Path, contents: &str, modified: SystemTime) {
+        fs::write(path, contents).unwrap();
+        fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn user(client_id: &str) -> String {
+        format!(
+            r#"{{
+  "client_id": "{}",
+  "client_secret": "secret-xxx",
+  "refresh_token": "refresh-xxx",
+  "type": "authorized_user"
+}}"#,
+            client_id
+        )
+    }
+
+    fn reload(mut credentials: Credentials) -> Reload {
+        let client = Client::with_connector(HttpConnector::new());
+        let file = credentials.take_reload_file().unwrap();
+        Reload::new(file, fetcher(credentials, client.clone()).unwrap(), client)
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join("google-authz-test-reload.json");
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        write(&path, &user("old"), t0);
+
+        let credentials = Credentials::builder().json_file(&path).reload_on_change(true);
+        let reload = reload(credentials.build().await.unwrap());
+        let mut state = reload.state.lock();
+        let check = |state: &mut State| reload.reload(state, modified(&path));
+
+        // not changed
+        assert!(!check(&mut state));
+
+        // swapped
+        let t1 = t0 + Duration::from_secs(1);
+        write(&path, &user("new"), t1);
+        assert!(check(&mut state));
+        assert_eq!(state.modified, Some(t1));
+        assert!(!check(&mut state));
+
+        // broken file keeps the previous credentials
+        write(&path, "{", t1 + Duration::from_secs(1));
+        assert!(!check(&mut state));
+        assert_eq!(state.modified, Some(t1));
+
+        // rejected by the token endpoint
+        write(&path, &user("new"), t1);
+        assert!(!check(&mut state));
+        state.force = true;
+        assert!(check(&mut state));
+        assert!(!state.force);
+
+        drop(state);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_settings() {
+        let key = |id: &str| {
+            serde_json::json!({
+                "type": "service_account",
+                "client_email": "sa@project.iam.gserviceaccount.com",
+                "private_key_id": id,
+                "private_key": include_str!("testdata/rsa.pem"),
+                "token_uri": "https://oauth2.googleapis.com/token",
+            })
+            .to_string()
+        };
+        let path = std::env::temp_dir().join("google-authz-test-reload-keeps-settings.json");
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        write(&path, &key("key-1"), t0);
+
+        let body = r#"{"access_token":"xxx","expires_in":3600,"token_type":"Bearer"}"#;
+        let (base, handle) = mock::serve("200 OK", body);
+        let sa = credentials::ServiceAccount::watch(&path)
+            .unwrap()
+            .with_token_uri(format!("{}/token", base))
+            .with_assertion_lifetime(Duration::from_secs(600));
+        let reload = reload(sa.into());
+
+        // the rotated key is sent to the overridden token endpoint
+        write(&path, &key("key-2"), t0 + Duration::from_secs(1));
+        reload.fetch().await.unwrap();
+        let req = handle.join().unwrap();
+        let (_, body) = req.split_once("\r\n\r\n").unwrap();
+        let form: HashMap<String, String> = serde_urlencoded::from_str(body).unwrap();
+        let assertion = &form["assertion"];
+        let header = jsonwebtoken::decode_header(assertion).unwrap();
+        assert_eq!(header.kid.as_deref(), Some("key-2"));
+        let claims = assertion.split('.').nth(1).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(
+            &base64::decode_config(claims, base64::URL_SAFE_NO_PAD).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(claims["aud"], format!("{}/token", base));
+        assert_eq!(claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap(), 600);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
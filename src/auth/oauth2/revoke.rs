@@ -0,0 +1,58 @@
+use hyper::Uri;
+use tracing::debug;
+
+use crate::auth::{self, oauth2::http::Client};
+
+pub(super) const REVOKE_URI: &str = "https://oauth2.googleapis.com/revoke";
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    token: &'a str,
+}
+
+/// Revokes an access token or a refresh token, e.g. on logout. Revoking a refresh token also
+/// revokes the access tokens issued with it. A token that is already revoked or has expired
+/// counts as revoked.
+pub async fn revoke_token(token: &str) -> auth::Result<()> {
+    revoke(&Client::new(), &Uri::from_static(REVOKE_URI), token).await
+}
+
+pub(super) async fn revoke(client: &Client, uri: &Uri, token: &str) -> auth::Result<()> {
+    // Google responds 200 with an empty body on success, so the body is not deserialized.
+    match client.send_bytes(client.request(uri, &Payload { token })).await {
+        Ok(_) => Ok(()),
+        Err(auth::Error::OAuth { ref code, .. }) if code == "invalid_token" => {
+            debug!("token is already revoked or has expired");
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::auth::oauth2::mock;
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let client = Client::builder().allow_http().build();
+
+        let uri = |base: String| format!("{}/revoke", base).parse::<Uri>().unwrap();
+
+        let (base, server) = mock::serve("200 OK", "");
+        revoke(&client, &uri(base), "xxx").await.unwrap();
+        let req = server.join().unwrap();
+        assert!(req.starts_with("POST /revoke "));
+        assert!(req.ends_with("\r\n\r\ntoken=xxx"));
+
+        let (base, _) = mock::serve("400 Bad Request", r#"{"error":"invalid_token"}"#);
+        revoke(&client, &uri(base), "xxx").await.unwrap();
+
+        let (base, _) = mock::serve("503 Service Unavailable", "");
+        let err = revoke(&client, &uri(base), "xxx").await.unwrap_err();
+        assert!(matches!(err, auth::Error::Http(StatusCode::SERVICE_UNAVAILABLE)));
+    }
+}
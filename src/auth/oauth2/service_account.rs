@@ -1,33 +1,78 @@
-use std::{fmt, time::SystemTime};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
-use hyper::Uri;
-use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use hyper::{StatusCode, Uri};
+use tracing::warn;
 
 use crate::{
-    auth::oauth2::{http::Client, token},
+    auth::{
+        self,
+        oauth2::{
+            http::Client,
+            signer::{JwtSigner, PrivateKey},
+            token,
+        },
+    },
     credentials,
 };
 
 // If client machine's time is in the future according
 // to Google servers, an access token will not be issued.
-fn issued_at() -> u64 {
-    SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs() - 10
+fn issued_at(skew: Duration) -> u64 {
+    SystemTime::UNIX_EPOCH.elapsed().unwrap().saturating_sub(skew).as_secs()
 }
 
-// https://cloud.google.com/iot/docs/concepts/device-security#security_standards
-fn header(typ: impl Into<String>, key_id: impl Into<String>) -> Header {
-    Header {
-        typ: Some(typ.into()),
-        alg: Algorithm::RS256,
-        kid: Some(key_id.into()),
-        ..Default::default()
+fn invalid(field: &'static str, reason: impl Into<String>) -> auth::Error {
+    auth::Error::InvalidServiceAccount { field, reason: reason.into() }
+}
+
+fn parse_key(key_id: String, pem: &str, field: &'static str) -> auth::Result<Arc<dyn JwtSigner>> {
+    if pem.is_empty() {
+        return Err(invalid(field, "is empty"));
+    }
+    let key = PrivateKey::from_pem(pem.as_bytes(), key_id).map_err(|err| {
+        invalid(field, format!("is not a PEM encoded RSA or EC private key: {}", err))
+    })?;
+    Ok(Arc::new(key))
+}
+
+// The token endpoint did not accept the assertion, e.g. signed by a deleted key.
+fn is_rejected(err: &auth::Error) -> bool {
+    match err {
+        auth::Error::Http(status) => *status == StatusCode::UNAUTHORIZED,
+        auth::Error::OAuth { status, code, .. } => {
+            *status == StatusCode::UNAUTHORIZED || code == "invalid_grant"
+        }
+        _ => false,
     }
 }
 
+fn encode_part(part: &impl serde::Serialize) -> String {
+    base64::encode_config(serde_json::to_vec(part).unwrap(), base64::URL_SAFE_NO_PAD)
+}
+
+// https://cloud.google.com/iot/docs/concepts/device-security#security_standards
+#[derive(serde::Serialize)]
+struct Header<'a> {
+    typ: &'a str,
+    alg: &'a str,
+    kid: &'a str,
+}
+
+// An assertion with `target_audience` instead of `scope` is exchanged for an ID token.
 #[derive(serde::Serialize)]
 struct Claims<'a> {
     iss: &'a str,
-    scope: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_audience: Option<&'a str>,
     aud: &'a str,
     iat: u64,
     exp: u64,
@@ -40,27 +85,86 @@ struct Payload<'a> {
 }
 
 // https://cloud.google.com/docs/authentication/production
+#[derive(Clone)]
 pub struct ServiceAccount {
     inner: Client,
-    header: Header,
-    private_key: EncodingKey,
+    // The key of the service account, then the additional keys.
+    signers: Arc<[Arc<dyn JwtSigner>]>,
+    // The index of the signer that last succeeded, shared by the clones.
+    current: Arc<AtomicUsize>,
     token_uri: Uri,
     token_uri_str: String,
     scopes: String,
+    // Fetches ID tokens for this audience instead of access tokens.
+    target_audience: Option<String>,
     client_email: String,
+    assertion_lifetime: Duration,
+    clock_skew: Duration,
 }
 
 impl ServiceAccount {
-    pub(crate) fn new(sa: credentials::ServiceAccount) -> Self {
-        Self {
-            inner: Client::new(),
-            header: header("JWT", sa.private_key_id),
-            private_key: EncodingKey::from_rsa_pem(sa.private_key.as_bytes()).unwrap(),
-            token_uri: Uri::from_maybe_shared(sa.token_uri.clone()).unwrap(),
+    pub(crate) fn try_new(sa: credentials::ServiceAccount, client: Client) -> auth::Result<Self> {
+        if sa.client_email.is_empty() {
+            return Err(invalid("client_email", "is empty"));
+        }
+        let token_uri = Uri::from_maybe_shared(sa.token_uri.clone())
+            .map_err(|err| invalid("token_uri", format!("is not a valid uri: {}", err)))?;
+        let mut signers = vec![match sa.signer {
+            Some(signer) => signer.0,
+            None => parse_key(sa.private_key_id, &sa.private_key, "private_key")?,
+        }];
+        for key in sa.additional_keys {
+            signers.push(parse_key(key.private_key_id, &key.private_key, "additional_keys")?);
+        }
+        Ok(Self {
+            inner: client,
+            signers: signers.into(),
+            current: Arc::new(AtomicUsize::new(0)),
+            token_uri,
             token_uri_str: sa.token_uri,
             scopes: sa.scopes.join(" "),
+            target_audience: None,
             client_email: sa.client_email,
-        }
+            assertion_lifetime: sa.assertion_lifetime,
+            clock_skew: sa.clock_skew,
+        })
+    }
+
+    fn signing_input(&self, signer: &dyn JwtSigner, iat: u64) -> String {
+        let header = Header { typ: "JWT", alg: signer.alg(), kid: signer.key_id() };
+        let claims = Claims {
+            iss: &self.client_email,
+            scope: Some(&*self.scopes).filter(|_| self.target_audience.is_none()),
+            target_audience: self.target_audience.as_deref(),
+            aud: &self.token_uri_str,
+            iat,
+            exp: iat + self.assertion_lifetime.as_secs(),
+        };
+        format!("{}.{}", encode_part(&header), encode_part(&claims))
+    }
+
+    fn fetch_with(&self, signer: &dyn JwtSigner) -> token::ResponseFuture {
+        let signing_input = self.signing_input(signer, issued_at(self.clock_skew));
+        let signature = signer.sign(signing_input.as_bytes());
+        let (client, token_uri) = (self.inner.clone(), self.token_uri.clone());
+        let id_token = self.target_audience.is_some();
+        Box::pin(async move {
+            let signature = signature.await?;
+            let assertion = format!(
+                "{}.{}",
+                signing_input,
+                base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+            );
+            let req = client.request(&token_uri, &Payload {
+                grant_type: "urn:ietf:params:oauth:grant-type:jwt-bearer",
+                assertion: &assertion,
+            });
+            if id_token {
+                let response: token::IdTokenResponse = client.send(req).await?;
+                return Ok(token::Response::from_id_token(response.id_token));
+            }
+            client.send(req).await
+        })
     }
 }
 
@@ -72,21 +176,151 @@ impl fmt::Debug for ServiceAccount {
 
 impl token::Fetcher for ServiceAccount {
     fn fetch(&self) -> token::ResponseFuture {
-        const EXPIRE: u64 = 60 * 60;
+        let sa = self.clone();
+        Box::pin(async move {
+            let start = sa.current.load(Ordering::Relaxed);
+            let mut index = start;
+            loop {
+                let signer = &*sa.signers[index];
+                let result = sa.fetch_with(signer).await;
+                let next = (index + 1) % sa.signers.len();
+                match result {
+                    Err(ref err) if is_rejected(err) && next != start => {
+                        warn!(
+                            key_id = signer.key_id(),
+                            error = %err,
+                            "service account key was rejected, trying the next key"
+                        );
+                        index = next;
+                    }
+                    Ok(_) => {
+                        sa.current.store(index, Ordering::Relaxed);
+                        return result;
+                    }
+                    Err(_) => return result,
+                }
+            }
+        })
+    }
 
-        let iat = issued_at();
-        let claims = Claims {
-            iss: &self.client_email,
-            scope: &self.scopes,
-            aud: &self.token_uri_str,
-            iat,
-            exp: iat + EXPIRE,
-        };
+    fn with_scopes(&self, scopes: &'static [&'static str]) -> Option<Box<dyn token::Fetcher>> {
+        Some(Box::new(Self { scopes: scopes.join(" "), ..self.clone() }))
+    }
+
+    fn id_token(&self, audience: &str) -> Option<Box<dyn token::Fetcher>> {
+        Some(Box::new(Self { target_audience: Some(audience.to_owned()), ..self.clone() }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key() -> credentials::ServiceAccount {
+        credentials::ServiceAccount {
+            scopes: &[],
+            client_email: "sa@project.iam.gserviceaccount.com".into(),
+            project_id: None,
+            private_key_id: "key-1".into(),
+            private_key: include_str!("testdata/rsa.pem").into(),
+            token_uri: "https://oauth2.googleapis.com/token".into(),
+            quota_project_id: None,
+            universe_domain: "googleapis.com".into(),
+            signer: None,
+            reload_path: None,
+            assertion_lifetime: Duration::from_secs(3600),
+            clock_skew: Duration::from_secs(10),
+            additional_keys: Vec::new(),
+        }
+    }
+
+    fn invalid_field(sa: credentials::ServiceAccount) -> Option<&'static str> {
+        match ServiceAccount::try_new(sa, Client::new()) {
+            Err(auth::Error::InvalidServiceAccount { field, .. }) => Some(field),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert!(ServiceAccount::try_new(key(), Client::new()).is_ok());
+
+        let pem = include_str!("testdata/rsa.pem");
+        let truncated = credentials::ServiceAccount { private_key: pem[..100].into(), ..key() };
+        assert_eq!(invalid_field(truncated), Some("private_key"));
+        let empty = credentials::ServiceAccount { private_key: String::new(), ..key() };
+        assert_eq!(invalid_field(empty), Some("private_key"));
+        let email = credentials::ServiceAccount { client_email: String::new(), ..key() };
+        assert_eq!(invalid_field(email), Some("client_email"));
+        let token_uri = credentials::ServiceAccount { token_uri: "not a uri".into(), ..key() };
+        assert_eq!(invalid_field(token_uri), Some("token_uri"));
+    }
+
+    fn claims(sa: credentials::ServiceAccount) -> serde_json::Value {
+        let sa = ServiceAccount::try_new(sa, Client::new()).unwrap();
+        let input = sa.signing_input(&*sa.signers[0], 1_000);
+        let claims = input.split('.').nth(1).unwrap();
+        serde_json::from_slice(&base64::decode_config(claims, base64::URL_SAFE_NO_PAD).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_signing_input() {
+        let claims = claims(key().with_assertion_lifetime(Duration::from_secs(600)));
+        assert_eq!(claims["iat"], 1_000);
+        assert_eq!(claims["exp"], 1_600);
+        assert_eq!(claims["aud"], "https://oauth2.googleapis.com/token");
+
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+        assert!(issued_at(Duration::from_secs(30)) <= now - 30);
+    }
+
+    #[test]
+    fn test_target_audience() {
+        let key = || credentials::ServiceAccount { scopes: &["scope1"], ..key() };
+        assert_eq!(claims(key())["scope"], "scope1");
+        assert!(claims(key()).get("target_audience").is_none());
+
+        let sa = ServiceAccount::try_new(key(), Client::new()).unwrap();
+        let sa = ServiceAccount { target_audience: Some("https://example.com".into()), ..sa };
+        let input = sa.signing_input(&*sa.signers[0], 1_000);
+        let claims = input.split('.').nth(1).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(
+            &base64::decode_config(claims, base64::URL_SAFE_NO_PAD).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(claims["target_audience"], "https://example.com");
+        assert!(claims.get("scope").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_id_token() {
+        use token::Fetcher as _;
+
+        let (base, handle) =
+            crate::auth::oauth2::mock::serve("200 OK", r#"{"id_token":"header.e30.sig"}"#);
+        let sa = key().with_token_uri(format!("{}/token", base));
+        let client = Client::with_connector(hyper::client::HttpConnector::new());
+        let sa = ServiceAccount::try_new(sa, client).unwrap();
+        let response = sa.id_token("https://example.com").unwrap().fetch().await.unwrap();
+        assert_eq!(response.access_token, "header.e30.sig");
+        assert_eq!(response.token_type, "Bearer");
+        assert!(handle.join().unwrap().contains("grant_type="));
+    }
+
+    #[test]
+    fn test_token_uri() {
+        let sa = key().with_token_uri("http://localhost:8080/token");
+        assert_eq!(claims(sa)["aud"], "http://localhost:8080/token");
+        let sa = key().with_token_uri("http://localhost:8080/token");
+        let sa = ServiceAccount::try_new(sa, Client::new()).unwrap();
+        assert_eq!(sa.token_uri, "http://localhost:8080/token");
+        assert_eq!(invalid_field(key().with_token_uri("")), Some("token_uri"));
+    }
 
-        let req = self.inner.request(&self.token_uri, &Payload {
-            grant_type: "urn:ietf:params:oauth:grant-type:jwt-bearer",
-            assertion: &encode(&self.header, &claims, &self.private_key).unwrap(),
-        });
-        Box::pin(self.inner.send(req))
+    #[test]
+    #[should_panic(expected = "assertion lifetime must be between 0 and 1 hour")]
+    fn test_assertion_lifetime() {
+        let _ = key().with_assertion_lifetime(Duration::from_secs(7200));
     }
 }
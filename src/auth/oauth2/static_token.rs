@@ -0,0 +1,93 @@
+use std::time::SystemTime;
+
+use crate::{
+    auth::{self, oauth2::token},
+    credentials,
+};
+
+// Serves the token of `credentials::StaticToken` without a network call.
+#[derive(Clone, Debug)]
+pub struct StaticToken {
+    inner: credentials::StaticToken,
+}
+
+impl StaticToken {
+    pub(crate) fn new(token: credentials::StaticToken) -> Self {
+        Self { inner: token }
+    }
+}
+
+fn response(token: credentials::StaticToken) -> auth::Result<token::Response> {
+    let expires_in = match token.expires_at {
+        Some(expires_at) => match expires_at.duration_since(SystemTime::now()) {
+            Ok(remaining) if remaining.as_secs() > 0 => Some(remaining.as_secs()),
+            _ => return Err(auth::Error::StaticTokenExpired),
+        },
+        None => None,
+    };
+    Ok(token::Response {
+        token_type: "Bearer".into(),
+        access_token: token.token,
+        expires_in,
+        expire_time: None,
+        refresh_token: None,
+    })
+}
+
+impl token::Fetcher for StaticToken {
+    fn fetch(&self) -> token::ResponseFuture {
+        let result = match self.inner.source {
+            Some(ref source) => source().map_err(auth::Error::TokenSource).and_then(response),
+            None => response(self.inner.clone()),
+        };
+        Box::pin(futures_util::future::ready(result))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::auth::oauth2::token::Fetcher as _;
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let resp = StaticToken::new(credentials::StaticToken::new("xxx")).fetch().await.unwrap();
+        assert_eq!((resp.token_type.as_str(), resp.access_token.as_str()), ("Bearer", "xxx"));
+        assert_eq!(resp.expires_in, None);
+
+        let expires_at = SystemTime::now() + Duration::from_secs(120);
+        let token = credentials::StaticToken::new("xxx").with_expiry(expires_at);
+        let resp = StaticToken::new(token).fetch().await.unwrap();
+        assert!(matches!(resp.expires_in, Some(119..=120)));
+
+        let expires_at = SystemTime::now() - Duration::from_secs(1);
+        let token = credentials::StaticToken::new("xxx").with_expiry(expires_at);
+        let err = StaticToken::new(token).fetch().await.unwrap_err();
+        assert!(matches!(err, auth::Error::StaticTokenExpired));
+    }
+
+    #[tokio::test]
+    async fn test_source() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let token = credentials::StaticToken::from_source({
+            let calls = calls.clone();
+            move || match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(credentials::StaticToken::new("a")),
+                _ => Err("sidecar is down".into()),
+            }
+        });
+        let fetcher = StaticToken::new(token);
+        assert_eq!(fetcher.fetch().await.unwrap().access_token, "a");
+        let err = fetcher.fetch().await.unwrap_err();
+        assert!(matches!(err, auth::Error::TokenSource(_)));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
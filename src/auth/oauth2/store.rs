@@ -0,0 +1,218 @@
+use std::{
+    fmt, fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use hyper::header::HeaderValue;
+use tracing::trace;
+
+use crate::auth::oauth2::token::Token;
+
+/// A token in a serializable form.
+///
+/// Only the wall-clock expiry is kept, the monotonic one is meaningless across processes.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CachedToken {
+    /// The `authorization` header value, e.g. `Bearer xxx`.
+    pub value: String,
+    pub expiry: SystemTime,
+    /// The scopes that the token was requested with, so that a token is not reused for others.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl CachedToken {
+    pub(crate) fn from_token(token: &Token, scopes: &[&str]) -> Option<Self> {
+        let value = token.value().to_str().ok()?.to_owned();
+        let scopes = scopes.iter().map(|&scope| scope.to_owned()).collect();
+        Some(Self { value, expiry: token.expiry(), scopes })
+    }
+
+    pub(crate) fn has_scopes(&self, scopes: &[&str]) -> bool {
+        self.scopes.len() == scopes.len() && self.scopes.iter().zip(scopes).all(|(a, b)| a == b)
+    }
+
+    pub(crate) fn into_token(self) -> Option<Token> {
+        let value = HeaderValue::from_str(&self.value).ok()?;
+        let remaining = self.expiry.duration_since(SystemTime::now()).ok()?;
+        Some(Token::new(value, remaining))
+    }
+}
+
+type OnToken = dyn Fn(&CachedToken, Option<&str>) + Send + Sync;
+
+/// Called with every fetched token and the refresh token if the response rotated it.
+#[derive(Clone)]
+pub(crate) struct TokenCallback(pub Arc<OnToken>);
+
+impl fmt::Debug for TokenCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenCallback").finish()
+    }
+}
+
+/// Replaces the file at `path` with `contents` by renaming a temporary file next to it, so that
+/// a concurrent reader or a crash never sees a partially written file.
+///
+/// The file keeps the permissions of the file it replaces, or is only readable by the owner if
+/// there is none, as it holds secrets like tokens.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    // Unique per write, so that concurrent writers do not write to the same temporary file.
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let tmp = PathBuf::from(tmp);
+    let result = write_new(&tmp, path, contents).and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_new(tmp: &Path, path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _};
+        let mode = fs::metadata(path).map_or(0o600, |meta| meta.permissions().mode() & 0o777);
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    let mut file = options.open(tmp)?;
+    file.write_all(contents)?;
+    // Flushed before the rename, so that a crash does not leave an empty file behind.
+    file.sync_all()
+}
+
+/// Persists fetched tokens so that they can be reused by later processes.
+pub trait TokenStore: fmt::Debug + Send + Sync + 'static {
+    fn load(&self) -> Option<CachedToken>;
+    fn store(&self, token: &CachedToken);
+}
+
+/// A [`TokenStore`] that keeps the token as json in a file.
+#[derive(Debug)]
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> Option<CachedToken> {
+        trace!("try loading cached token from {:?}", self.path);
+        let json = fs::read(&self.path)
+            .map_err(|err| trace!("failed to read token cache file: {:?}", err))
+            .ok()?;
+        serde_json::from_slice(&json)
+            .map_err(|err| trace!("failed to deserialize cached token: {:?}", err))
+            .ok()
+    }
+
+    fn store(&self, token: &CachedToken) {
+        trace!("try storing token to {:?}", self.path);
+        let json = serde_json::to_vec(token).unwrap();
+        if let Err(err) = write_atomic(&self.path, &json) {
+            trace!("failed to write token cache file: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_file_token_store() {
+        let path = std::env::temp_dir().join("google-authz-test-file-token-store.json");
+        let _ = fs::remove_file(&path);
+
+        let store = FileTokenStore::new(&path);
+        assert_eq!(store.load(), None);
+
+        let token = Token::new(HeaderValue::from_static("Bearer xxx"), Duration::from_secs(60));
+        store.store(&CachedToken::from_token(&token, &["scope1"]).unwrap());
+
+        let loaded = store.load().unwrap();
+        assert!(loaded.has_scopes(&["scope1"]));
+        assert!(!loaded.has_scopes(&["scope1", "scope2"]));
+        assert!(!loaded.has_scopes(&[]));
+        let loaded = loaded.into_token().unwrap();
+        assert_eq!(loaded.value(), token.value());
+        assert!(!loaded.is_expired());
+
+        let expired = CachedToken {
+            value: "Bearer xxx".into(),
+            expiry: SystemTime::UNIX_EPOCH,
+            scopes: vec![],
+        };
+        assert!(expired.into_token().is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let path = std::env::temp_dir().join("google-authz-test-write-atomic.json");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        // the temporary file is renamed over the target
+        let dir = fs::read_dir(std::env::temp_dir()).unwrap();
+        assert!(!dir.flatten().any(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("google-authz-test-write-atomic.json.") && name.ends_with(".tmp")
+        }));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_permissions() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let path = std::env::temp_dir().join("google-authz-test-write-atomic-permissions.json");
+        let _ = fs::remove_file(&path);
+        write_atomic(&path, b"secret").unwrap();
+        assert_eq!(mode(&path), 0o600);
+
+        // e.g. a file shared with a group is not made readable by others
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path, b"rotated").unwrap();
+        assert_eq!(mode(&path), 0o640);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic_concurrent() {
+        let path = std::env::temp_dir().join("google-authz-test-write-atomic-concurrent.json");
+        let writers = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || write_atomic(&path, format!("{}", i).as_bytes()))
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.parse::<u8>().unwrap() < 8);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use std::{fmt, future::Future};
+
+use hyper::Uri;
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{http::Client, token},
+    },
+    redacted::Redacted,
+};
+
+// https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token
+const STS_URI: &str = "https://sts.googleapis.com/v1/token";
+const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const CLOUD_PLATFORM: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    grant_type: &'a str,
+    audience: &'a str,
+    scope: &'a str,
+    requested_token_type: &'a str,
+    subject_token: &'a str,
+    subject_token_type: &'a str,
+}
+
+/// A token issued by the STS token exchange.
+#[derive(Clone, serde::Deserialize)]
+pub struct StsToken {
+    pub access_token: String,
+    /// e.g. `urn:ietf:params:oauth:token-type:access_token`
+    pub issued_token_type: String,
+    pub token_type: String,
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+impl fmt::Debug for StsToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StsToken")
+            .field("access_token", &Redacted::new(&self.access_token))
+            .field("issued_token_type", &self.issued_token_type)
+            .field("token_type", &self.token_type)
+            .field("expires_in", &self.expires_in)
+            .finish()
+    }
+}
+
+impl From<StsToken> for token::Response {
+    fn from(token: StsToken) -> Self {
+        Self {
+            token_type: token.token_type,
+            access_token: token.access_token,
+            expires_in: token.expires_in,
+            expire_time: None,
+            refresh_token: None,
+        }
+    }
+}
+
+/// Exchanges a subject token, e.g. an OIDC ID token or a signed AWS request, for a Google access
+/// token with the [STS API](https://cloud.google.com/iam/docs/reference/sts/rest) (RFC 8693).
+#[derive(Clone)]
+pub struct StsExchange {
+    uri: Uri,
+    audience: String,
+    subject_token: String,
+    subject_token_type: String,
+    scopes: Vec<String>,
+    requested_token_type: String,
+}
+
+impl StsExchange {
+    /// `audience` is usually the full resource name of the workload identity pool provider, e.g.
+    /// `//iam.googleapis.com/projects/<number>/locations/global/workloadIdentityPools/<pool>/providers/<provider>`.
+    pub fn new(
+        audience: impl Into<String>,
+        subject_token: impl Into<String>,
+        subject_token_type: impl Into<String>,
+    ) -> Self {
+        Self {
+            uri: Uri::from_static(STS_URI),
+            audience: audience.into(),
+            subject_token: subject_token.into(),
+            subject_token_type: subject_token_type.into(),
+            scopes: vec![CLOUD_PLATFORM.to_owned()],
+            requested_token_type: ACCESS_TOKEN_TYPE.to_owned(),
+        }
+    }
+
+    /// Sets the token exchange endpoint, `https://sts.googleapis.com/v1/token` by default.
+    #[must_use]
+    pub fn sts_uri(mut self, uri: Uri) -> Self {
+        self.uri = uri;
+        self
+    }
+
+    /// Sets the scopes of the issued token, `cloud-platform` by default.
+    #[must_use]
+    pub fn scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = scopes.iter().map(|&scope| scope.to_owned()).collect();
+        self
+    }
+
+    /// Sets the type of the issued token, an access token by default.
+    #[must_use]
+    pub fn requested_token_type(mut self, token_type: impl Into<String>) -> Self {
+        self.requested_token_type = token_type.into();
+        self
+    }
+
+    pub fn exchange(&self) -> impl Future<Output = Result<StsToken, auth::Error>> + Send + 'static {
+        self.send(&Client::new())
+    }
+
+    pub(crate) fn send(
+        &self,
+        client: &Client,
+    ) -> impl Future<Output = auth::Result<StsToken>> + Send + 'static {
+        let scope = self.scopes.join(" ");
+        let req = client.request(&self.uri, &Payload {
+            grant_type: GRANT_TYPE,
+            audience: &self.audience,
+            scope: &scope,
+            requested_token_type: &self.requested_token_type,
+            subject_token: &self.subject_token,
+            subject_token_type: &self.subject_token_type,
+        });
+        client.send(req)
+    }
+}
+
+impl fmt::Debug for StsExchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StsExchange")
+            .field("uri", &self.uri)
+            .field("audience", &self.audience)
+            .field("subject_token", &Redacted::new(&self.subject_token))
+            .field("subject_token_type", &self.subject_token_type)
+            .field("scopes", &self.scopes)
+            .field("requested_token_type", &self.requested_token_type)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_debug() {
+        let exchange = StsExchange::new(
+            "//iam.googleapis.com/xxx",
+            "subject-xxx",
+            "urn:ietf:params:oauth:token-type:jwt",
+        )
+        .scopes(&["scope1", "scope2"]);
+        let debug = format!("{:?}", exchange);
+        assert!(debug.contains(r#"scopes: ["scope1", "scope2"]"#));
+        assert!(!debug.contains("subject-xxx"));
+
+        let token: StsToken = serde_json::from_str(
+            r#"{"access_token":"xxx","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#,
+        )
+        .unwrap();
+        assert!(!format!("{:?}", token).contains("xxx"));
+        let resp = token::Response::from(token);
+        assert_eq!((resp.access_token.as_str(), resp.expires_in), ("xxx", Some(3600)));
+    }
+}
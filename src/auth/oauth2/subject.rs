@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    process::{Output, Stdio},
+    time::{Duration, SystemTime},
+};
+
+use futures_util::future::BoxFuture;
+use hyper::{Body, Request, Uri};
+use tokio::process::Command;
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{aws::AwsSubjectProvider, http::Client},
+    },
+    credentials::{CredentialSource, ExecutableCredentialSource, SubjectTokenFormat},
+};
+
+fn subject_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> auth::Error {
+    auth::Error::SubjectToken(err.into())
+}
+
+impl SubjectTokenFormat {
+    fn parse(&self, body: &[u8]) -> auth::Result<String> {
+        let token = match self {
+            Self::Text => String::from_utf8(body.to_vec()).map_err(subject_error)?,
+            Self::Json { subject_token_field_name } => {
+                let json: serde_json::Value = serde_json::from_slice(body)?;
+                match json.get(subject_token_field_name).and_then(|v| v.as_str()) {
+                    Some(token) => token.to_owned(),
+                    None => {
+                        let message = format!("missing `{}`", subject_token_field_name);
+                        return Err(subject_error(message));
+                    }
+                }
+            }
+        };
+        Ok(token.trim().to_owned())
+    }
+}
+
+// The subject token source of `credential_source`.
+#[derive(Clone, Debug)]
+pub enum SubjectProvider {
+    Aws(AwsSubjectProvider),
+    File(FileSubjectProvider),
+    Url(UrlSubjectProvider),
+    Executable(ExecutableSubjectProvider),
+}
+
+impl SubjectProvider {
+    pub(crate) fn new(source: CredentialSource, subject_token_type: &str) -> Self {
+        match source {
+            CredentialSource::Aws(aws) => Self::Aws(AwsSubjectProvider::new(aws)),
+            CredentialSource::File { file, format } => {
+                Self::File(FileSubjectProvider { path: file, format })
+            }
+            CredentialSource::Url { url, headers, format } => {
+                Self::Url(UrlSubjectProvider::new(url, headers, format))
+            }
+            CredentialSource::Executable { executable } => {
+                Self::Executable(ExecutableSubjectProvider::new(executable, subject_token_type))
+            }
+        }
+    }
+
+    pub fn subject_token(&self, audience: &str) -> BoxFuture<'static, auth::Result<String>> {
+        match self {
+            Self::Aws(aws) => Box::pin(aws.subject_token(audience)),
+            Self::File(file) => file.subject_token(),
+            Self::Url(url) => url.subject_token(),
+            Self::Executable(executable) => executable.subject_token(audience),
+        }
+    }
+}
+
+// Reads the subject token from a file, e.g. a projected kubernetes service account token.
+#[derive(Clone, Debug)]
+pub struct FileSubjectProvider {
+    path: PathBuf,
+    format: SubjectTokenFormat,
+}
+
+impl FileSubjectProvider {
+    // The file is re-read every time as it is usually rotated.
+    fn subject_token(&self) -> BoxFuture<'static, auth::Result<String>> {
+        let (path, format) = (self.path.clone(), self.format.clone());
+        Box::pin(async move {
+            let body = tokio::fs::read(&path).await.map_err(subject_error)?;
+            format.parse(&body)
+        })
+    }
+}
+
+// Fetches the subject token from a url, e.g. the Azure instance metadata.
+#[derive(Clone, Debug)]
+pub struct UrlSubjectProvider {
+    inner: Client,
+    url: String,
+    headers: HashMap<String, String>,
+    format: SubjectTokenFormat,
+}
+
+impl UrlSubjectProvider {
+    fn new(url: String, headers: HashMap<String, String>, format: SubjectTokenFormat) -> Self {
+        // The url is usually a local endpoint without tls.
+        Self { inner: Client::builder().allow_http().build(), url, headers, format }
+    }
+
+    fn subject_token(&self) -> BoxFuture<'static, auth::Result<String>> {
+        let req = self.url.parse::<Uri>().map_err(subject_error).and_then(|uri| {
+            let mut req = Request::get(uri);
+            for (name, value) in &self.headers {
+                req = req.header(name.as_str(), value.as_str());
+            }
+            req.body(Body::empty()).map_err(subject_error)
+        });
+        let (client, format) = (self.inner.clone(), self.format.clone());
+        Box::pin(async move {
+            let body = client.send_bytes(req?).await?;
+            format.parse(&body)
+        })
+    }
+}
+
+const ALLOW_EXECUTABLES: &str = "GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES";
+const DEFAULT_EXECUTABLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// https://google.aip.dev/auth/4117#executable-sourced-credentials
+#[derive(serde::Deserialize)]
+struct ExecutableResponse {
+    version: u32,
+    success: bool,
+    token_type: Option<String>,
+    id_token: Option<String>,
+    saml_response: Option<String>,
+    expiration_time: Option<u64>,
+    code: Option<String>,
+    message: Option<String>,
+}
+
+impl ExecutableResponse {
+    // Returns `None` if the token has expired.
+    fn into_token(self, now: SystemTime) -> auth::Result<Option<String>> {
+        if self.version != 1 {
+            return Err(subject_error(format!("unsupported executable version: {}", self.version)));
+        }
+        if !self.success {
+            return Err(subject_error(format!(
+                "executable failed: code={:?}, message={:?}",
+                self.code, self.message
+            )));
+        }
+        let now = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        if matches!(self.expiration_time, Some(expiry) if expiry <= now) {
+            return Ok(None);
+        }
+        let token = match self.token_type.as_deref() {
+            Some("urn:ietf:params:oauth:token-type:saml2") => self.saml_response,
+            _ => self.id_token,
+        };
+        token.map(Some).ok_or_else(|| subject_error("executable response has no token"))
+    }
+}
+
+// A failed executable may still print a response with the reason, which is preferred over the
+// exit status.
+fn parse_output(output: Output, now: SystemTime) -> auth::Result<String> {
+    let response = serde_json::from_slice::<ExecutableResponse>(&output.stdout);
+    if !output.status.success() {
+        return match response {
+            Ok(response) if !response.success => response.into_token(now).map(|_| String::new()),
+            _ => Err(subject_error(format!("executable failed: {}", output.status))),
+        };
+    }
+    response?.into_token(now)?.ok_or_else(|| subject_error("executable returned an expired token"))
+}
+
+// Runs a command and reads the subject token from its json output. The command is split on
+// whitespace and run without a shell.
+#[derive(Clone, Debug)]
+pub struct ExecutableSubjectProvider {
+    command: String,
+    timeout: Duration,
+    output_file: Option<PathBuf>,
+    subject_token_type: String,
+}
+
+impl ExecutableSubjectProvider {
+    fn new(source: ExecutableCredentialSource, subject_token_type: &str) -> Self {
+        Self {
+            command: source.command,
+            timeout: source
+                .timeout_millis
+                .map_or(DEFAULT_EXECUTABLE_TIMEOUT, Duration::from_millis),
+            output_file: source.output_file,
+            subject_token_type: subject_token_type.to_owned(),
+        }
+    }
+
+    // A still valid token cached in `output_file` by a previous run.
+    async fn cached(output_file: Option<PathBuf>) -> Option<String> {
+        let body = tokio::fs::read(output_file?).await.ok()?;
+        let response = serde_json::from_slice::<ExecutableResponse>(&body).ok()?;
+        response.into_token(SystemTime::now()).ok().flatten()
+    }
+
+    fn subject_token(&self, audience: &str) -> BoxFuture<'static, auth::Result<String>> {
+        if env::var(ALLOW_EXECUTABLES).as_deref() != Ok("1") {
+            let message = format!("executables are not allowed, set `{}=1`", ALLOW_EXECUTABLES);
+            return Box::pin(async { Err(subject_error(message)) });
+        }
+        let mut args = self.command.split_whitespace();
+        let mut command = Command::new(args.next().unwrap_or_default());
+        command
+            .args(args)
+            .env("GOOGLE_EXTERNAL_ACCOUNT_AUDIENCE", audience)
+            .env("GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE", &self.subject_token_type)
+            .env("GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE", "0")
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true);
+        if let Some(ref path) = self.output_file {
+            command.env("GOOGLE_EXTERNAL_ACCOUNT_OUTPUT_FILE", path);
+        }
+        let (timeout, output_file) = (self.timeout, self.output_file.clone());
+        Box::pin(async move {
+            if let Some(token) = Self::cached(output_file).await {
+                return Ok(token);
+            }
+            let output = tokio::time::timeout(timeout, command.output())
+                .await
+                .map_err(|_| auth::Error::Timeout)?
+                .map_err(subject_error)?;
+            parse_output(output, SystemTime::now())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(SubjectTokenFormat::Text.parse(b"xxx\n").unwrap(), "xxx");
+        let json = SubjectTokenFormat::Json { subject_token_field_name: "access_token".into() };
+        assert_eq!(json.parse(br#"{"access_token":"xxx"}"#).unwrap(), "xxx");
+        assert!(matches!(json.parse(br#"{"id_token":"xxx"}"#), Err(auth::Error::SubjectToken(_))));
+    }
+
+    #[tokio::test]
+    async fn test_file() {
+        use std::fs;
+
+        let path = env::temp_dir().join("google-authz-test-subject-token");
+        fs::write(&path, "xxx").unwrap();
+        let file = FileSubjectProvider { path: path.clone(), format: SubjectTokenFormat::Text };
+        assert_eq!(file.subject_token().await.unwrap(), "xxx");
+        fs::remove_file(&path).unwrap();
+        assert!(file.subject_token().await.is_err());
+    }
+
+    #[test]
+    fn test_executable_response() {
+        let parse = |json: &str| serde_json::from_str::<ExecutableResponse>(json).unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let ok = r#"{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:jwt","id_token":"xxx","expiration_time":2000}"#;
+        assert_eq!(parse(ok).into_token(now).unwrap().as_deref(), Some("xxx"));
+        let expired = SystemTime::UNIX_EPOCH + Duration::from_secs(3000);
+        assert_eq!(parse(ok).into_token(expired).unwrap(), None);
+
+        let saml = r#"{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:saml2","saml_response":"yyy"}"#;
+        assert_eq!(parse(saml).into_token(now).unwrap().as_deref(), Some("yyy"));
+
+        let failed = r#"{"version":1,"success":false,"code":"401","message":"denied"}"#;
+        assert!(parse(failed).into_token(now).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_executable_output() {
+        use std::os::unix::process::ExitStatusExt as _;
+
+        let output = |code: i32, stdout: &str| Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.into(),
+            stderr: Vec::new(),
+        };
+        let error = |output| match parse_output(output, SystemTime::UNIX_EPOCH) {
+            Err(auth::Error::SubjectToken(err)) => err.to_string(),
+            result => panic!("unexpected result: {:?}", result),
+        };
+        let ok = r#"{"version":1,"success":true,"token_type":"urn:ietf:params:oauth:token-type:jwt","id_token":"xxx"}"#;
+        assert_eq!(parse_output(output(0, ok), SystemTime::UNIX_EPOCH).unwrap(), "xxx");
+
+        // a successful response with a failed exit status
+        assert!(error(output(1, ok)).contains("exit status: 1"));
+        assert!(error(output(1, "")).contains("exit status: 1"));
+        let failed = r#"{"version":1,"success":false,"code":"401","message":"denied"}"#;
+        assert!(error(output(1, failed)).contains("denied"));
+    }
+}
@@ -1,58 +1,475 @@
 use std::{
-    convert::TryFrom,
     fmt,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use futures_util::future::BoxFuture;
 use hyper::header::HeaderValue;
+use tracing::debug;
 
-use crate::auth;
+use super::{
+    clock::{Clock, SystemClock},
+    http,
+};
+use crate::{auth, redacted::Redacted};
 
 #[derive(Clone)]
 pub(crate) struct Token {
-    pub value: HeaderValue,
-    pub expiry: Instant,
+    value: HeaderValue,
+    expiry: SystemTime,
+    // The wall clock can jump backward, so the expiry is also kept as a monotonic time.
+    // `Instant` alone is not enough as it does not advance while the machine is suspended.
+    deadline: Instant,
+    lifetime: Duration,
+}
+
+/// The current token without its secret, e.g. to report its freshness in a health check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenSnapshot {
+    /// The scheme of the header value, e.g. `Bearer`.
+    pub token_type: String,
+    pub expiry: SystemTime,
+}
+
+/// How long the current token remains valid, e.g. for a health check to report that requests
+/// are about to fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenLifetime {
+    /// No token has been fetched, or it is being fetched.
+    Unknown,
+    /// The token is due for a refresh, and expires after the duration.
+    Expiring(Duration),
+    /// The token is valid for the duration.
+    Valid(Duration),
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("value", &Redacted::new(&self.value))
+            .field("expiry", &self.expiry)
+            .finish()
+    }
 }
 
 impl Token {
-    pub fn new(value: HeaderValue, expiry: Instant) -> Self {
-        Self { value, expiry }
+    pub fn new(value: HeaderValue, expires_in: Duration) -> Self {
+        Self::issued_at(value, expires_in, &SystemClock)
+    }
+
+    fn issued_at(value: HeaderValue, expires_in: Duration, clock: &dyn Clock) -> Self {
+        Self {
+            value,
+            expiry: clock.now() + expires_in,
+            deadline: clock.instant() + expires_in,
+            lifetime: expires_in,
+        }
+    }
+
+    /// The `authorization` header value, e.g. `Bearer xxx`.
+    pub fn value(&self) -> &HeaderValue {
+        &self.value
+    }
+
+    pub fn expiry(&self) -> SystemTime {
+        self.expiry
+    }
+
+    /// Returns the remaining lifetime, `None` if the token has already expired.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.expiry.duration_since(SystemTime::now()).ok()
+    }
+
+    pub fn snapshot(&self) -> TokenSnapshot {
+        let value = String::from_utf8_lossy(self.value.as_bytes());
+        let token_type = value.split(' ').next().unwrap_or_default().to_owned();
+        TokenSnapshot { token_type, expiry: self.expiry }
+    }
+
+    /// Returns true if the token has expired, without any refresh buffer.
+    pub fn is_expired(&self) -> bool {
+        self.expired(&SystemClock, Duration::ZERO)
     }
 
-    pub fn expired(&self, at: Instant) -> bool {
-        const EXPIRY_DELTA: Duration = Duration::from_secs(10);
-        self.expiry.checked_duration_since(at).map(|dur| dur < EXPIRY_DELTA).unwrap_or(true)
+    /// Returns true if the token expires within `buffer` from now.
+    ///
+    /// `buffer` is clamped to half of the lifetime of the token, so that a token shorter lived than
+    /// twice the buffer is still used for a while instead of being refetched right away.
+    pub fn expired(&self, clock: &dyn Clock, buffer: Duration) -> bool {
+        self.expired_at(clock.now(), clock.instant(), buffer)
+    }
+
+    /// Returns how long until the token expires within `buffer`, see `expired`.
+    pub fn refresh_in(&self, clock: &dyn Clock, buffer: Duration) -> Duration {
+        let buffer = buffer.min(self.lifetime / 2);
+        self.remaining(clock).saturating_sub(buffer)
+    }
+
+    /// Returns how long until the token expires, zero if it has.
+    pub fn remaining(&self, clock: &dyn Clock) -> Duration {
+        let remaining = self.expiry.duration_since(clock.now()).unwrap_or_default();
+        remaining.min(self.deadline.saturating_duration_since(clock.instant()))
+    }
+
+    fn expired_at(&self, now: SystemTime, at: Instant, buffer: Duration) -> bool {
+        let buffer = buffer.min(self.lifetime / 2);
+        let expired = |remaining: Option<Duration>| remaining.map(|d| d < buffer).unwrap_or(true);
+        // If the wall clock jumps backward the wall-clock expiry looks further away than it is,
+        // but the monotonic deadline still catches it.
+        expired(self.expiry.duration_since(now).ok())
+            || expired(self.deadline.checked_duration_since(at))
     }
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Response {
+    // The IAM credentials API omits this, its tokens are bearer tokens.
+    #[serde(default = "bearer")]
     pub token_type: String,
+    #[serde(alias = "accessToken")]
     pub access_token: String,
-    pub expires_in: u64,
+    // Some endpoints omit this.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    // The expiry as an RFC 3339 timestamp instead of `expires_in`, e.g. of the IAM credentials
+    // API that impersonates service accounts.
+    #[serde(default, rename = "expireTime")]
+    pub expire_time: Option<String>,
+    // Only returned for user credentials when Google rotates the refresh token.
+    #[serde(default)]
+    pub refresh_token: Option<String>,
 }
 
-impl TryFrom<Response> for Token {
-    type Error = auth::Error;
+fn bearer() -> String {
+    "Bearer".to_owned()
+}
+
+/// The response of the token endpoint to an assertion with `target_audience`.
+#[derive(serde::Deserialize)]
+pub(crate) struct IdTokenResponse {
+    pub id_token: String,
+}
+
+impl Response {
+    /// Wraps an ID token as a bearer token that expires at its `exp` claim, or after
+    /// `default_expires_in` if it has none.
+    pub fn from_id_token(id_token: String) -> Self {
+        #[derive(serde::Deserialize)]
+        struct Claims {
+            exp: u64,
+        }
+
+        let exp = id_token
+            .split('.')
+            .nth(1)
+            .and_then(|claims| base64::decode_config(claims, base64::URL_SAFE_NO_PAD).ok())
+            .and_then(|claims| serde_json::from_slice::<Claims>(&claims).ok())
+            .map(|claims| claims.exp);
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap_or_default().as_secs();
+        Self {
+            token_type: bearer(),
+            access_token: id_token,
+            expires_in: exp.map(|exp| exp.saturating_sub(now)),
+            expire_time: None,
+            refresh_token: None,
+        }
+    }
+}
+
+// e.g. `2014-10-02T15:01:23Z` or `2014-10-02T15:01:23.045123456+09:00`.
+fn rfc3339(value: &str) -> Option<SystemTime> {
+    let (date, time) = value.split_once(['T', 't'])?;
+    let mut date = date.splitn(3, '-').map(|v| v.parse::<u64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    let (time, offset) = time.split_at(time.find(['Z', 'z', '+', '-'])?);
+    let offset = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let (h, m) = offset[1..].split_once(':')?;
+            let (h, m) = (h.parse::<i64>().ok()?, m.parse::<i64>().ok()?);
+            if !(0..24).contains(&h) || !(0..60).contains(&m) {
+                return None;
+            }
+            let secs = h * 3600 + m * 60;
+            if offset.starts_with('-') {
+                -secs
+            } else {
+                secs
+            }
+        }
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(|v| v.parse::<u64>().ok());
+    let (h, m, s) = (time.next()??, time.next()??, time.next()??);
+    // ascii digits only, so that the fraction can be cut at any byte
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = match fraction {
+        "" => 0,
+        _ => format!("{:0<9}", &fraction[..fraction.len().min(9)]).parse().ok()?,
+    };
+
+    let local = http::unix_time(year, month, day, h, m, s)?;
+    let local = local.checked_add(Duration::from_nanos(nanos))?;
+    if offset >= 0 {
+        local.checked_sub(Duration::from_secs(offset as u64))
+    } else {
+        local.checked_add(Duration::from_secs(offset.unsigned_abs()))
+    }
+}
 
-    fn try_from(response: Response) -> Result<Self, Self::Error> {
+impl Token {
+    /// Builds the token from the response, using `scheme` instead of `token_type` if specified.
+    /// The expiry is `expires_in`, else `expireTime`, else `default_expires_in`.
+    pub fn from_response(
+        response: Response,
+        scheme: Option<&str>,
+        default_expires_in: Duration,
+        clock: &dyn Clock,
+    ) -> auth::Result<Self> {
+        let expire_time = response.expire_time.as_deref().map(rfc3339);
+        let expires_in = match (response.expires_in, expire_time) {
+            (Some(expires_in), _) => Duration::from_secs(expires_in),
+            // An expiry in the past is rejected below.
+            (None, Some(Some(expiry))) => expiry.duration_since(clock.now()).unwrap_or_default(),
+            (None, Some(None)) => return Err(auth::Error::TokenFormat(response)),
+            (None, None) => {
+                debug!("token response has no `expires_in`, using {:?}", default_expires_in);
+                default_expires_in
+            }
+        };
         if !response.token_type.is_empty()
             && !response.access_token.is_empty()
-            && response.expires_in > 0
+            && expires_in > Duration::ZERO
         {
-            let value = format!("{} {}", response.token_type, response.access_token);
+            // Only bearer tokens can be sent as is, e.g. `mac` tokens require signing each request.
+            if !response.token_type.eq_ignore_ascii_case("Bearer") {
+                return Err(auth::Error::UnsupportedTokenType(response.token_type));
+            }
+            let scheme = scheme.unwrap_or(&response.token_type);
+            let value = format!("{} {}", scheme, response.access_token);
             if let Ok(value) = HeaderValue::from_str(&value) {
-                let expiry = Instant::now() + Duration::from_secs(response.expires_in);
-                return Ok(Token::new(value, expiry));
+                return Ok(Token::issued_at(value, expires_in, clock));
             }
         }
         Err(auth::Error::TokenFormat(response))
     }
 }
 
+/// A future that resolves to the token response, i.e.
+/// `Pin<Box<dyn Future<Output = auth::Result<Response>> + Send>>`.
 pub(crate) type ResponseFuture = BoxFuture<'static, auth::Result<Response>>;
 
-pub(crate) trait Fetcher: fmt::Debug + Send + Sync + 'static {
+/// Fetches a new token from the authorization server.
+///
+/// `Send + Sync + 'static` is required because the fetcher is shared by every clone of
+/// `Oauth2` through an `Arc<RwLock<_>>`, and those clones may be polled from any thread.
+///
+/// `fetch` returns a boxed future rather than being an `async fn`, as the fetcher is used as
+/// `Box<dyn Fetcher>` and an `async fn` in a trait is not object safe. `async_trait` would box
+/// the future all the same; the allocation is one per fetch, next to an HTTP request.
+pub(crate) trait Fetcher: FetcherClone + fmt::Debug + Send + Sync + 'static {
     fn fetch(&self) -> ResponseFuture;
+
+    /// Returns a copy that fetches tokens with `scopes`, `None` if the credentials can not
+    /// request other scopes, e.g. user credentials keep the scopes they were granted.
+    fn with_scopes(&self, _scopes: &'static [&'static str]) -> Option<Box<dyn Fetcher>> {
+        None
+    }
+
+    /// Returns a fetcher of ID tokens for `audience`, served like access tokens, `None` if the
+    /// credentials can not issue ID tokens.
+    fn id_token(&self, _audience: &str) -> Option<Box<dyn Fetcher>> {
+        None
+    }
+
+    /// Returns the refresh token that tokens are fetched with, revoked instead of the access
+    /// token by `Oauth2::revoke`.
+    fn refresh_token(&self) -> Option<String> {
+        None
+    }
+}
+
+pub(crate) trait FetcherClone {
+    fn clone_box(&self) -> Box<dyn Fetcher>;
+}
+
+impl<T: Fetcher + Clone> FetcherClone for T {
+    fn clone_box(&self) -> Box<dyn Fetcher> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Fetcher> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::auth::oauth2::clock::MockClock;
+
+    #[test]
+    fn test_expired() {
+        let token = Token::new(HeaderValue::from_static("Bearer xxx"), Duration::from_secs(60));
+        let (now, at, buffer) = (SystemTime::now(), Instant::now(), Duration::from_secs(10));
+        assert!(!token.expired_at(now, at, buffer));
+        assert!(token.expired_at(now, at + Duration::from_secs(55), buffer));
+        assert!(!token.expired_at(now, at + Duration::from_secs(55), Duration::ZERO));
+        // the buffer is clamped to half of the lifetime
+        assert!(!token.expired_at(now, at, Duration::from_secs(60)));
+        assert!(!token.expired_at(now, at + Duration::from_secs(29), Duration::from_secs(60)));
+        assert!(token.expired_at(now, at + Duration::from_secs(31), Duration::from_secs(60)));
+        // suspended: the monotonic clock did not advance but the wall clock did
+        assert!(token.expired_at(now + Duration::from_secs(120), at, buffer));
+        // the wall clock jumped backward
+        assert!(!token.expired_at(now - Duration::from_secs(3600), at, buffer));
+        assert!(token.expired_at(
+            now - Duration::from_secs(3600),
+            at + Duration::from_secs(120),
+            buffer
+        ));
+
+        assert!(token.expires_in().unwrap() <= Duration::from_secs(60));
+        let expired = Token { expiry: SystemTime::UNIX_EPOCH, ..token.clone() };
+        assert_eq!(expired.expires_in(), None);
+        let snapshot = token.snapshot();
+        assert_eq!(snapshot.token_type, "Bearer");
+        assert_eq!(snapshot.expiry, token.expiry);
+        assert!(!format!("{:?}", snapshot).contains("xxx"));
+        assert!(expired.is_expired());
+        assert!(!token.is_expired());
+    }
+
+    #[test]
+    fn test_from_response() {
+        let response = || Response {
+            token_type: "Bearer".into(),
+            access_token: "xxx".into(),
+            expires_in: Some(3600),
+            expire_time: None,
+            refresh_token: None,
+        };
+        const DEFAULT: Duration = Duration::from_secs(3600);
+        assert_eq!(
+            Token::from_response(response(), None, DEFAULT, &SystemClock).unwrap().value(),
+            "Bearer xxx"
+        );
+        assert_eq!(
+            Token::from_response(response(), Some("Token"), DEFAULT, &SystemClock).unwrap().value(),
+            "Token xxx"
+        );
+        assert!(Token::from_response(
+            Response { expires_in: Some(0), ..response() },
+            None,
+            DEFAULT,
+            &SystemClock
+        )
+        .is_err());
+
+        let missing = Response { expires_in: None, ..response() };
+        let token = Token::from_response(missing, None, DEFAULT, &SystemClock).unwrap();
+        assert!(token.expires_in().unwrap() > Duration::from_secs(3000));
+        assert!(Token::from_response(
+            Response { expires_in: None, ..response() },
+            None,
+            Duration::ZERO,
+            &SystemClock
+        )
+        .is_err());
+
+        let bearer = Response { token_type: "bearer".into(), ..response() };
+        assert_eq!(
+            Token::from_response(bearer, None, DEFAULT, &SystemClock).unwrap().value(),
+            "bearer xxx"
+        );
+        let mac = Response { token_type: "mac".into(), ..response() };
+        assert!(matches!(
+            Token::from_response(mac, None, DEFAULT, &SystemClock),
+            Err(auth::Error::UnsupportedTokenType(token_type)) if token_type == "mac"
+        ));
+    }
+
+    #[test]
+    fn test_expire_time() {
+        const DEFAULT: Duration = Duration::from_secs(3600);
+        let clock = MockClock::new();
+        let expiry = |json: &str| {
+            let response = serde_json::from_str::<Response>(json).unwrap();
+            Token::from_response(response, None, DEFAULT, &clock).map(|token| token.expiry())
+        };
+
+        // the standard `expires_in`
+        let json = r#"{"access_token":"xxx","token_type":"Bearer","expires_in":60}"#;
+        assert_eq!(expiry(json).unwrap(), clock.now() + Duration::from_secs(60));
+        // `expireTime` of the IAM credentials API
+        let json = r#"{"accessToken":"xxx","expireTime":"2100-01-01T00:00:00Z"}"#;
+        assert_eq!(expiry(json).unwrap(), UNIX_EPOCH + Duration::from_secs(4_102_444_800));
+        // neither
+        let json = r#"{"access_token":"xxx","token_type":"Bearer"}"#;
+        assert_eq!(expiry(json).unwrap(), clock.now() + DEFAULT);
+
+        let json = r#"{"accessToken":"xxx","expireTime":"tomorrow"}"#;
+        assert!(matches!(expiry(json), Err(auth::Error::TokenFormat(_))));
+        // already expired
+        let json = r#"{"accessToken":"xxx","expireTime":"2014-10-02T15:01:23Z"}"#;
+        assert!(matches!(expiry(json), Err(auth::Error::TokenFormat(_))));
+    }
+
+    #[test]
+    fn test_from_id_token() {
+        let encode = |claims: &str| base64::encode_config(claims, base64::URL_SAFE_NO_PAD);
+        let exp = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs() + 600;
+        let id_token = format!("{}.{}.sig", encode("{}"), encode(&format!(r#"{{"exp":{}}}"#, exp)));
+        let response = Response::from_id_token(id_token.clone());
+        assert!(matches!(response.expires_in, Some(599..=600)));
+        let token = Token::from_response(response, None, Duration::ZERO, &SystemClock).unwrap();
+        assert_eq!(token.value(), &*format!("Bearer {}", id_token));
+
+        assert_eq!(Response::from_id_token("not a jwt".into()).expires_in, None);
+        let expired = format!("{}.{}.sig", encode("{}"), encode(r#"{"exp":1000}"#));
+        assert_eq!(Response::from_id_token(expired).expires_in, Some(0));
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        let at = |secs, nanos| Some(UNIX_EPOCH + Duration::new(secs, nanos));
+        assert_eq!(rfc3339("2014-10-02T15:01:23Z"), at(1_412_262_083, 0));
+        assert_eq!(rfc3339("2014-10-02t15:01:23z"), at(1_412_262_083, 0));
+        assert_eq!(rfc3339("2014-10-02T15:01:23.045Z"), at(1_412_262_083, 45_000_000));
+        assert_eq!(rfc3339("2014-10-02T15:01:23.045123456789Z"), at(1_412_262_083, 45_123_456));
+        assert_eq!(rfc3339("2014-10-03T00:01:23+09:00"), at(1_412_262_083, 0));
+        assert_eq!(rfc3339("2014-10-02T10:31:23-04:30"), at(1_412_262_083, 0));
+        assert_eq!(rfc3339("2014-10-02T15:01:23"), None);
+        assert_eq!(rfc3339("2014-10-02 15:01:23Z"), None);
+        assert_eq!(rfc3339("1969-12-31T23:59:59Z"), None);
+        assert_eq!(rfc3339("2014-10-02T15:01:23.00000000éZ"), None);
+        assert_eq!(rfc3339("2014-10-02T15:01:23+9223372036854775807:00"), None);
+        assert_eq!(rfc3339("2014-10-02T15:01:23+24:00"), None);
+        assert_eq!(rfc3339("2014-10-02T25:01:23Z"), None);
+        assert_eq!(rfc3339("18446744073709551615-10-02T15:01:23Z"), None);
+        assert_eq!(rfc3339("999999999999999-10-02T15:01:23Z"), None);
+    }
+
+    #[test]
+    fn test_debug() {
+        let token = Token::new(HeaderValue::from_static("Bearer xxx"), Duration::from_secs(60));
+        let debug = format!("{:?}", token);
+        assert!(debug.contains("<redacted>"));
+        assert!(!debug.contains("xxx"));
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: ?Sized + Send + Sync + 'static>() {}
+        assert_send_sync::<dyn Fetcher>();
+        assert_send_sync::<Box<dyn Fetcher>>();
+        assert_send_sync::<super::super::Oauth2>();
+    }
 }
@@ -1,12 +1,28 @@
-use std::fmt;
+use std::{convert::TryFrom as _, fmt, sync::Arc};
 
 use hyper::Uri;
+use parking_lot::RwLock;
+use tracing::{trace, warn};
 
 use crate::{
     auth::oauth2::{http::Client, token},
-    credentials,
+    credentials::{self, RefreshTokenCallback},
 };
 
+pub(super) const USERINFO_URI: &str = "https://www.googleapis.com/oauth2/v3/userinfo";
+
+/// The Google account that an access token was issued for, see [`User`].
+///
+/// `email` and `email_verified` need the `email` scope, `name` and `picture` the `profile` one.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct UserInfo {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+}
+
 #[derive(serde::Serialize)]
 struct Payload<'a> {
     client_id: &'a str,
@@ -16,23 +32,38 @@ struct Payload<'a> {
 }
 
 // https://cloud.google.com/docs/authentication/end-user
+#[derive(Clone)]
 pub struct User {
     inner: Client,
     token_uri: Uri,
-    credentials: credentials::User,
+    client_id: String,
+    client_secret: String,
+    // Shared between clones so that a rotated refresh token is used by all of them.
+    refresh_token: Arc<RwLock<String>>,
+    on_refresh_token: Option<RefreshTokenCallback>,
 }
 
 impl User {
-    pub(crate) fn new(user: credentials::User) -> Self {
+    pub(crate) fn new(user: credentials::User, client: Client) -> Self {
         Self {
-            inner: Client::new(),
+            inner: client,
             // https://github.com/golang/oauth2/blob/0f29369cfe4552d0e4bcddc57cc75f4d7e672a33/google/google.go#L24
-            token_uri: Uri::from_static("https://oauth2.googleapis.com/token"),
-            credentials: user,
+            token_uri: token_uri(&user.universe_domain),
+            client_id: user.client_id,
+            client_secret: user.client_secret,
+            refresh_token: Arc::new(RwLock::new(user.refresh_token)),
+            on_refresh_token: user.on_refresh_token,
         }
     }
 }
 
+fn token_uri(universe_domain: &str) -> Uri {
+    Uri::try_from(credentials::token_uri(universe_domain)).unwrap_or_else(|err| {
+        warn!("invalid universe domain, using the default: {:?}", err);
+        Uri::from_static("https://oauth2.googleapis.com/token")
+    })
+}
+
 impl fmt::Debug for User {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("User").finish()
@@ -42,13 +73,35 @@ impl fmt::Debug for User {
 impl token::Fetcher for User {
     fn fetch(&self) -> token::ResponseFuture {
         let req = self.inner.request(&self.token_uri, &Payload {
-            client_id: &self.credentials.client_id,
-            client_secret: &self.credentials.client_secret,
+            client_id: &self.client_id,
+            client_secret: &self.client_secret,
             grant_type: "refresh_token",
-            // The reflesh token is not included in the response from google's server,
-            // so it always uses the specified refresh token from the file.
-            refresh_token: &self.credentials.refresh_token,
+            refresh_token: &self.refresh_token.read(),
         });
-        Box::pin(self.inner.send(req))
+        let fut = self.inner.send::<token::Response>(req);
+
+        let refresh_token = self.refresh_token.clone();
+        let on_refresh_token = self.on_refresh_token.clone();
+        Box::pin(async move {
+            let resp = fut.await?;
+            // The refresh token is usually not included in the response from google's server,
+            // but when it is, the token has been rotated and the old one will stop working.
+            if let Some(ref rotated) = resp.refresh_token {
+                let mut current = refresh_token.write();
+                if *current != *rotated {
+                    trace!("refresh token has been rotated");
+                    *current = rotated.clone();
+                    drop(current);
+                    if let Some(RefreshTokenCallback(ref f)) = on_refresh_token {
+                        f(rotated);
+                    }
+                }
+            }
+            Ok(resp)
+        })
+    }
+
+    fn refresh_token(&self) -> Option<String> {
+        Some(self.refresh_token.read().clone())
     }
 }
@@ -0,0 +1,118 @@
+use std::fmt;
+
+use tower_layer::Layer;
+
+use crate::{
+    auth::{self, Auth},
+    service::Builder,
+    GoogleAuthz,
+};
+
+/// A [`Layer`] that wraps services with [`GoogleAuthz`], e.g. the upstream client of an axum
+/// reverse proxy or API gateway.
+///
+/// Every wrapped service shares the same token.
+#[derive(Clone)]
+pub struct GoogleAuthzLayer {
+    auth: Auth,
+}
+
+impl GoogleAuthzLayer {
+    pub async fn new() -> Self {
+        Self::builder().build_layer().await
+    }
+
+    pub fn builder() -> Builder<()> {
+        Builder::new(())
+    }
+}
+
+impl Builder<()> {
+    /// # Panics
+    ///
+    /// Panics if no default credentials are found, the credentials are invalid, e.g. a service
+    /// account key with a malformed private key, or the settings are.
+    /// [`try_build_layer`](Builder::try_build_layer) returns the error instead.
+    pub async fn build_layer(self) -> GoogleAuthzLayer {
+        let (auth, ()) = self.build_auth().await.unwrap_or_else(|err| panic!("{}", err));
+        GoogleAuthzLayer { auth }
+    }
+
+    /// Like [`build_layer`](Builder::build_layer), but also fetches the first token, so that
+    /// invalid credentials fail at startup rather than on the first request, see
+    /// [`try_build`](Builder::try_build).
+    pub async fn try_build_layer(self) -> Result<GoogleAuthzLayer, auth::Error> {
+        let (mut auth, ()) = self.build_auth().await?;
+        futures_util::future::poll_fn(|cx| auth.poll_ready(cx)).await?;
+        Ok(GoogleAuthzLayer { auth })
+    }
+}
+
+impl<S> Layer<S> for GoogleAuthzLayer {
+    type Service = GoogleAuthz<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        GoogleAuthz::from_parts(self.auth.clone(), service)
+    }
+}
+
+impl fmt::Debug for GoogleAuthzLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GoogleAuthzLayer").field("auth", &self.auth).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::future::poll_fn;
+    use hyper::{header::AUTHORIZATION, Request};
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{credentials, test_util::Echo, Credentials};
+
+    // Dispatches on the path to a service per route, as an axum router does.
+    struct Router {
+        routes: Vec<(&'static str, GoogleAuthz<Echo>)>,
+    }
+
+    impl Router {
+        async fn call(&mut self, path: &str) -> Request<()> {
+            let (_, route) =
+                self.routes.iter_mut().find(|(prefix, _)| path.starts_with(*prefix)).unwrap();
+            poll_fn(|cx| route.poll_ready(cx)).await.unwrap();
+            let req = Request::get(format!("https://example.com{}", path)).body(()).unwrap();
+            route.call(req).await.unwrap()
+        }
+    }
+
+    fn metadata(host: String) -> Credentials {
+        Credentials::from(credentials::Metadata::new().with_host(host))
+    }
+
+    #[tokio::test]
+    async fn test_try_build_layer() {
+        let token = r#"{"access_token":"xxx","expires_in":3600,"token_type":"Bearer"}"#;
+        let host = auth::mock::serve_routes(vec![("/computeMetadata/v1/", token.into())]);
+        let layer = GoogleAuthzLayer::builder()
+            .credentials(metadata(host))
+            .try_build_layer()
+            .await
+            .unwrap();
+
+        let mut router = Router {
+            routes: vec![("/topics", layer.layer(Echo)), ("/subscriptions", layer.layer(Echo))],
+        };
+        for path in ["/topics/x", "/subscriptions/y"] {
+            let req = router.call(path).await;
+            assert_eq!(req.uri().path(), path);
+            assert_eq!(req.headers()[AUTHORIZATION], "Bearer xxx");
+        }
+
+        // not attached to the instance
+        let host = auth::mock::serve_routes(Vec::new());
+        let result =
+            GoogleAuthzLayer::builder().credentials(metadata(host)).try_build_layer().await;
+        assert!(matches!(result, Err(auth::Error::ServiceAccountNotAttached(_))));
+    }
+}
@@ -0,0 +1,141 @@
+use std::{path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    auth::Oauth2Builder,
+    credentials::{self, Credentials, Metadata, DEFAULT_SCOPES},
+};
+
+/// The OAuth 2.0 settings of a service, deserializable from a configuration file, e.g.
+///
+/// ```yaml
+/// credential_type:
+///   type: json_file
+///   path: /etc/google/key.json
+/// max_retry: 5
+/// scopes:
+///   - https://www.googleapis.com/auth/pubsub
+/// ```
+///
+/// See [`GoogleAuthz::builder_from_config`](crate::GoogleAuthz::builder_from_config).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Oauth2Config {
+    pub credential_type: CredentialType,
+    /// 3 by default.
+    #[serde(default = "default_max_retry")]
+    pub max_retry: u8,
+    /// 10 by default.
+    #[serde(default = "default_secs")]
+    pub refresh_buffer_secs: u64,
+    /// 10 by default, 0 disables the timeout.
+    #[serde(default = "default_secs")]
+    pub fetch_timeout_secs: u64,
+    /// `cloud-platform` if empty.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// Where the tokens of [`Oauth2Config`] come from, tagged by `type`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum CredentialType {
+    /// A service account key, user credentials or external account credentials file.
+    JsonFile { path: PathBuf },
+    /// The metadata server, as `account` or the default service account of the instance.
+    Metadata {
+        #[serde(default)]
+        account: Option<String>,
+    },
+}
+
+fn default_max_retry() -> u8 {
+    3
+}
+
+fn default_secs() -> u64 {
+    10
+}
+
+impl Oauth2Config {
+    // Reads the credentials as configured, the fetcher is built from them with the settings of
+    // the returned builder.
+    //
+    // The scopes are leaked, they live as long as the credentials which are usually built once.
+    pub(crate) fn into_oauth2(self) -> credentials::Result<(Credentials, Oauth2Builder)> {
+        let scopes: &'static [&'static str] = if self.scopes.is_empty() {
+            DEFAULT_SCOPES
+        } else {
+            let scopes = self.scopes.into_iter().map(|scope| &*Box::leak(scope.into_boxed_str()));
+            Box::leak(scopes.collect())
+        };
+        let mut oauth2 = Oauth2Builder::default()
+            .max_retry(self.max_retry)
+            .refresh_buffer(Duration::from_secs(self.refresh_buffer_secs));
+        // Checked here, as `Oauth2Builder::build` panics on it.
+        if oauth2.config.refresh_buffer >= oauth2.config.default_expires_in {
+            return Err(credentials::Error::InvalidConfig(
+                "refresh_buffer_secs must be shorter than the default token lifetime of 1 hour",
+            ));
+        }
+        oauth2.config.fetch_timeout = match self.fetch_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let credentials = match self.credential_type {
+            CredentialType::JsonFile { path } => Credentials::from_json_file(path, scopes)?,
+            CredentialType::Metadata { account } => {
+                let metadata = Metadata::new().with_scopes(scopes);
+                let metadata = match account {
+                    Some(account) => metadata.with_account(account),
+                    None => metadata,
+                };
+                Credentials::Metadata(Box::new(metadata))
+            }
+        };
+        Ok((credentials, oauth2))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_deserialize() {
+        let config: Oauth2Config = serde_json::from_value(serde_json::json!({
+            "credential_type": { "type": "metadata" },
+            "fetch_timeout_secs": 0,
+            "scopes": ["a"],
+        }))
+        .unwrap();
+        assert_eq!(config.credential_type, CredentialType::Metadata { account: None });
+        assert_eq!((config.max_retry, config.refresh_buffer_secs), (3, 10));
+
+        let (credentials, oauth2) = config.into_oauth2().unwrap();
+        assert_eq!(oauth2.config.fetch_timeout, None);
+        match credentials {
+            Credentials::Metadata(metadata) => {
+                assert_eq!(metadata.scopes, ["a"]);
+                assert_eq!(metadata.account(), None);
+            }
+            other => panic!("unexpected credentials: {:?}", other),
+        }
+
+        let missing = r#"{"credential_type": {"type": "json_file", "path": "/nonexistent.json"}}"#;
+        let config: Oauth2Config = serde_json::from_str(missing).unwrap();
+        assert!(matches!(config.into_oauth2(), Err(credentials::Error::CredentialsFile(_))));
+        assert!(
+            serde_json::from_str::<Oauth2Config>(r#"{"credential_type": {"type": "x"}}"#).is_err()
+        );
+
+        let config: Oauth2Config = serde_json::from_value(serde_json::json!({
+            "credential_type": { "type": "metadata", "account": "sa@example.com" },
+            "refresh_buffer_secs": 3600,
+        }))
+        .unwrap();
+        assert!(matches!(config.into_oauth2(), Err(credentials::Error::InvalidConfig(_))));
+    }
+}
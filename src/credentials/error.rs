@@ -9,12 +9,25 @@ pub enum Error {
         "not found credentials source, please set the environment variable `RUST_LOG` to `google_authz=trace` for more details"
     )]
     CredentialsSource,
+    #[error("not found credentials file, and the metadata server is disabled")]
+    MetadataServerDisabled,
     #[error("read credentials file error: {0}")]
     CredentialsFile(std::io::Error),
     #[error(
         "user or service account credentials format error: user={user}, service_account={service_account})"
     )]
     CredentialsFormat { user: serde_json::Error, service_account: serde_json::Error },
+    #[error("service account credentials format error: {0}")]
+    ServiceAccountFormat(serde_json::Error),
+    #[error("user credentials format error: {0}")]
+    UserFormat(serde_json::Error),
+    #[error("external account credentials format error: {0}")]
+    ExternalAccountFormat(serde_json::Error),
+    #[error("unsupported credentials: {0}")]
+    UnsupportedCredentials(&'static str),
+    /// A setting of [`Oauth2Config`](crate::Oauth2Config) is invalid.
+    #[error("invalid config: {0}")]
+    InvalidConfig(&'static str),
 }
 
 /// Wrapper for the `Result` type with an [`Error`](Error).
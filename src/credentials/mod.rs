@@ -1,20 +1,56 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use hyper::client::HttpConnector;
+use hyper::client::connect::Connect;
+
+use crate::auth::{self, Error as AuthError, HttpClient, JwtSigner};
 
 mod error;
 mod impls;
+mod refresh_token;
 
 pub use error::*;
+pub use refresh_token::{FileRefreshTokenStore, RefreshTokenStore};
+
+/// Returns true if the GCE metadata server is reachable, i.e. this process is running on GCE, GKE,
+/// Cloud Run or another environment that serves it.
+///
+/// The server is probed with a 1 second timeout, at `GCE_METADATA_HOST` if set, and the result is
+/// cached for the lifetime of the process. A negative result is not trusted while
+/// `GCE_METADATA_HOST` is set, so the server is probed again.
+pub async fn on_gce() -> bool {
+    impls::on_gce(impls::DEFAULT_METADATA_TIMEOUT).await
+}
 
+/// The credentials that requests are authenticated with.
+///
+/// New variants may be added, so matching on it needs a wildcard arm.
 #[cfg_attr(test, derive(PartialEq))]
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Credentials {
+    /// Requests are sent as is.
     None,
+    /// Added as the `key` query parameter.
     ApiKey(String),
+    /// A refresh token of an end user, e.g. from `gcloud auth application-default login`.
     User(User),
+    /// A service account key or a custom [`JwtSigner`].
     ServiceAccount(ServiceAccount),
+    /// Tokens from the GCE metadata server.
     Metadata(Box<Metadata>),
+    /// A token of another identity provider exchanged through STS (workload identity federation).
+    ExternalAccount(Box<ExternalAccount>),
+    /// See [`Credentials::chain`].
+    Chain(Vec<Credentials>),
+    /// A token supplied from outside, e.g. by a sidecar or an integration test.
+    StaticToken(StaticToken),
 }
 
 impl Credentials {
@@ -25,10 +61,208 @@ impl Credentials {
     pub fn builder<'a>() -> Builder<'a> {
         Builder::default()
     }
+
+    /// No authentication, e.g. for emulators or public resources.
+    /// Requests are passed through as is and no token is fetched.
+    pub fn none() -> Self {
+        Self::None
+    }
+
+    /// Tries the first token fetch of each credentials in order, and commits to the first one
+    /// that succeeds. Later token fetches only use the committed credentials.
+    ///
+    /// API key credentials and no credentials are ignored.
+    pub fn chain(credentials: impl IntoIterator<Item = Credentials>) -> Self {
+        Self::Chain(credentials.into_iter().collect())
+    }
+
+    /// Returns the project that the credentials belong to: `project_id` of a service account key,
+    /// or the project of the instance for the metadata server. For a chain, this is the project of
+    /// the first credentials.
+    pub async fn project_id(&self) -> std::result::Result<Option<String>, AuthError> {
+        let mut credentials = self;
+        while let Self::Chain(chain) = credentials {
+            match chain.first() {
+                Some(first) => credentials = first,
+                None => return Ok(None),
+            }
+        }
+        match credentials {
+            Self::ServiceAccount(sa) => Ok(sa.project_id.clone()),
+            Self::Metadata(meta) => meta.project_id().await.map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the project that API quota and billing are attributed to, if any.
+    pub fn quota_project_id(&self) -> Option<&str> {
+        match self {
+            Self::None | Self::ApiKey(_) => None,
+            Self::User(user) => user.quota_project_id.as_deref(),
+            Self::ServiceAccount(sa) => sa.quota_project_id.as_deref(),
+            Self::Metadata(meta) => meta.quota_project_id.as_deref(),
+            Self::ExternalAccount(ea) => ea.quota_project_id.as_deref(),
+            Self::Chain(chain) => chain.iter().find_map(Self::quota_project_id),
+            Self::StaticToken(_) => None,
+        }
+    }
+
+    /// Returns the domain that Google APIs are served under, `googleapis.com` unless the
+    /// credentials belong to another universe such as Trusted Partner Cloud.
+    pub fn universe_domain(&self) -> &str {
+        match self {
+            Self::None | Self::ApiKey(_) | Self::StaticToken(_) => DEFAULT_UNIVERSE_DOMAIN,
+            Self::User(user) => &user.universe_domain,
+            Self::ServiceAccount(sa) => &sa.universe_domain,
+            Self::Metadata(meta) => &meta.universe_domain,
+            Self::ExternalAccount(ea) => &ea.universe_domain,
+            Self::Chain(chain) => {
+                chain.first().map_or(DEFAULT_UNIVERSE_DOMAIN, Self::universe_domain)
+            }
+        }
+    }
+
+    pub(crate) fn scopes(&self) -> &'static [&'static str] {
+        match self {
+            Self::None | Self::ApiKey(_) | Self::StaticToken(_) => &[],
+            Self::User(user) => user.scopes,
+            Self::ServiceAccount(sa) => sa.scopes,
+            Self::Metadata(meta) => meta.scopes,
+            Self::ExternalAccount(ea) => ea.scopes,
+            Self::Chain(chain) => chain.first().map(Self::scopes).unwrap_or(&[]),
+        }
+    }
+
+    // The kind of the credentials, as the `credential_type` field of log events.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::ApiKey(_) => "api_key",
+            Self::User(_) => "user",
+            Self::ServiceAccount(_) => "service_account",
+            Self::Metadata(_) => "metadata",
+            Self::ExternalAccount(_) => "external_account",
+            Self::Chain(_) => "chain",
+            Self::StaticToken(_) => "static_token",
+        }
+    }
+
+    pub(crate) fn from_json_file(
+        path: impl AsRef<Path>,
+        scopes: &'static [&'static str],
+    ) -> Result<Self> {
+        impls::from_json_file(path, scopes)
+    }
+
+    pub(crate) fn set_universe_domain(&mut self, domain: &str) {
+        match self {
+            Self::None | Self::ApiKey(_) | Self::StaticToken(_) => {}
+            Self::User(user) => user.universe_domain = domain.to_owned(),
+            Self::ServiceAccount(sa) => {
+                // The token endpoint in the json is kept unless it is the one of the old universe.
+                if sa.token_uri == token_uri(&sa.universe_domain) {
+                    sa.token_uri = token_uri(domain);
+                }
+                sa.universe_domain = domain.to_owned();
+            }
+            Self::Metadata(meta) => meta.universe_domain = domain.to_owned(),
+            Self::ExternalAccount(ea) => {
+                if ea.token_url == sts_token_url(&ea.universe_domain) {
+                    ea.token_url = sts_token_url(domain);
+                }
+                ea.universe_domain = domain.to_owned();
+            }
+            Self::Chain(chain) => {
+                chain.iter_mut().for_each(|credentials| credentials.set_universe_domain(domain))
+            }
+        }
+    }
+
+    pub(crate) fn take_reload_file(&mut self) -> Option<ReloadFile> {
+        let (path, scopes) = match self {
+            Self::User(user) => (user.reload_path.take()?, user.scopes),
+            Self::ServiceAccount(sa) => (sa.reload_path.take()?, sa.scopes),
+            Self::ExternalAccount(ea) => (ea.reload_path.take()?, ea.scopes),
+            _ => return None,
+        };
+        Some(ReloadFile {
+            path,
+            scopes,
+            universe_domain: self.universe_domain().to_owned(),
+            on_refresh_token: match self {
+                Self::User(user) => user.on_refresh_token.clone(),
+                _ => None,
+            },
+            service_account: match self {
+                Self::ServiceAccount(sa) => Some(ServiceAccountSettings {
+                    token_uri: sa.token_uri.clone(),
+                    assertion_lifetime: sa.assertion_lifetime,
+                    clock_skew: sa.clock_skew,
+                    additional_keys: sa.additional_keys.clone(),
+                }),
+                _ => None,
+            },
+        })
+    }
+
+    fn set_reload_path(&mut self, path: PathBuf) {
+        match self {
+            Self::User(user) => user.reload_path = Some(path),
+            Self::ServiceAccount(sa) => sa.reload_path = Some(path),
+            Self::ExternalAccount(ea) => ea.reload_path = Some(path),
+            _ => {}
+        }
+    }
+
+    fn set_quota_project_id(&mut self, id: String) {
+        match self {
+            Self::None | Self::ApiKey(_) | Self::StaticToken(_) => {}
+            Self::User(user) => user.quota_project_id = Some(id),
+            Self::ServiceAccount(sa) => sa.quota_project_id = Some(id),
+            Self::Metadata(meta) => meta.quota_project_id = Some(id),
+            Self::ExternalAccount(ea) => ea.quota_project_id = Some(id),
+            Self::Chain(chain) => chain
+                .iter_mut()
+                .for_each(|credentials| credentials.set_quota_project_id(id.clone())),
+        }
+    }
+}
+
+/// The universe of the Google default, e.g. Trusted Partner Cloud has its own.
+pub(crate) const DEFAULT_UNIVERSE_DOMAIN: &str = "googleapis.com";
+pub(crate) const DEFAULT_SCOPES: &[&str] = &["https://www.googleapis.com/auth/cloud-platform"];
+// Google rejects assertions valid for longer.
+const MAX_ASSERTION_LIFETIME: Duration = Duration::from_secs(3600);
+const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(10);
+
+fn default_assertion_lifetime() -> Duration {
+    MAX_ASSERTION_LIFETIME
+}
+
+fn default_clock_skew() -> Duration {
+    DEFAULT_CLOCK_SKEW
+}
+
+fn default_universe_domain() -> String {
+    DEFAULT_UNIVERSE_DOMAIN.to_owned()
+}
+
+/// Returns the OAuth 2.0 token endpoint of `universe_domain`.
+pub(crate) fn token_uri(universe_domain: &str) -> String {
+    format!("https://oauth2.{}/token", universe_domain)
+}
+
+/// Returns the STS token exchange endpoint of `universe_domain`.
+pub(crate) fn sts_token_url(universe_domain: &str) -> String {
+    format!("https://sts.{}/v1/token", universe_domain)
+}
+
+fn default_sts_token_url() -> String {
+    sts_token_url(DEFAULT_UNIVERSE_DOMAIN)
 }
 
 #[cfg_attr(test, derive(PartialEq))]
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize)]
 pub struct User {
     #[serde(skip)]
     pub(crate) scopes: &'static [&'static str],
@@ -36,6 +270,120 @@ pub struct User {
     pub(crate) client_id: String,
     pub(crate) client_secret: String,
     pub(crate) refresh_token: String,
+    pub(crate) quota_project_id: Option<String>,
+    #[serde(default = "default_universe_domain")]
+    pub(crate) universe_domain: String,
+    #[serde(skip)]
+    pub(crate) on_refresh_token: Option<RefreshTokenCallback>,
+    #[serde(skip)]
+    pub(crate) reload_path: Option<PathBuf>,
+}
+
+impl User {
+    /// Authorizes `scopes` for an end user in the browser, e.g. for CLI tools.
+    ///
+    /// This passes the consent page URL to `present_url`, e.g. to print it or open a browser, and
+    /// waits, without a timeout, for the browser to be redirected to a local server on a random
+    /// port. The authorization code is exchanged for a refresh token with PKCE. `client_id` and
+    /// `client_secret` are of a desktop app OAuth client. The refresh token can be persisted with
+    /// a [`RefreshTokenStore`].
+    pub async fn interactive_flow(
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+        scopes: &'static [&'static str],
+        present_url: impl FnOnce(&str),
+    ) -> std::result::Result<Self, AuthError> {
+        auth::authorize_user(client_id.into(), client_secret.into(), scopes, present_url).await
+    }
+
+    /// Reads `authorized_user` credentials from `path`, or from the file written by
+    /// `gcloud auth application-default login` if `None`, e.g.
+    /// `~/.config/gcloud/application_default_credentials.json`.
+    ///
+    /// Unlike [`Credentials::builder`], `GOOGLE_CLOUD_QUOTA_PROJECT` does not override the
+    /// `quota_project_id` of the file.
+    pub fn from_authorized_user_file(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => impls::user_from_json_file(path),
+            None => impls::user_from_json_file(&impls::well_known_file()),
+        }
+    }
+
+    /// Revokes the refresh token, e.g. on logout, see [`revoke_token`](crate::revoke_token).
+    /// A service that the credentials were already handed to is not affected, revoke with
+    /// [`GoogleAuthz::revoke`](crate::GoogleAuthz::revoke) instead.
+    pub async fn revoke(&self) -> std::result::Result<(), AuthError> {
+        auth::revoke_token(&self.refresh_token).await
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn refresh_token(&self) -> &str {
+        &self.refresh_token
+    }
+}
+
+/// A credentials file that is re-read when it changes, with the settings that do not come from the
+/// file, e.g. [`ServiceAccount::with_token_uri`], so that they are kept across reloads.
+#[derive(Clone)]
+pub(crate) struct ReloadFile {
+    pub path: PathBuf,
+    pub scopes: &'static [&'static str],
+    universe_domain: String,
+    on_refresh_token: Option<RefreshTokenCallback>,
+    service_account: Option<ServiceAccountSettings>,
+}
+
+#[derive(Clone)]
+struct ServiceAccountSettings {
+    token_uri: String,
+    assertion_lifetime: Duration,
+    clock_skew: Duration,
+    additional_keys: Vec<ServiceAccountKey>,
+}
+
+impl ReloadFile {
+    pub(crate) fn with_scopes(&self, scopes: &'static [&'static str]) -> Self {
+        Self { scopes, ..self.clone() }
+    }
+
+    pub(crate) fn load(&self) -> Result<Credentials> {
+        let mut credentials = impls::from_json_file(&self.path, self.scopes)?;
+        credentials.set_universe_domain(&self.universe_domain);
+        match credentials {
+            Credentials::User(ref mut user) => {
+                user.on_refresh_token = self.on_refresh_token.clone()
+            }
+            Credentials::ServiceAccount(ref mut sa) => {
+                if let Some(ref settings) = self.service_account {
+                    sa.token_uri = settings.token_uri.clone();
+                    sa.assertion_lifetime = settings.assertion_lifetime;
+                    sa.clock_skew = settings.clock_skew;
+                    sa.additional_keys = settings.additional_keys.clone();
+                }
+            }
+            _ => {}
+        }
+        Ok(credentials)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RefreshTokenCallback(pub Arc<dyn Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for RefreshTokenCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshTokenCallback").finish()
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for RefreshTokenCallback {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
 #[cfg_attr(test, derive(PartialEq))]
@@ -45,50 +393,499 @@ pub struct ServiceAccount {
     pub(crate) scopes: &'static [&'static str],
     // json fields
     pub(crate) client_email: String,
+    #[serde(default)]
+    pub(crate) project_id: Option<String>,
     pub(crate) private_key_id: String,
     pub(crate) private_key: String,
     pub(crate) token_uri: String,
+    pub(crate) quota_project_id: Option<String>,
+    #[serde(default = "default_universe_domain")]
+    pub(crate) universe_domain: String,
+    // replaces `private_key` and `private_key_id` when set
+    #[serde(skip)]
+    pub(crate) signer: Option<Signer>,
+    #[serde(skip)]
+    pub(crate) reload_path: Option<PathBuf>,
+    #[serde(skip, default = "default_assertion_lifetime")]
+    pub(crate) assertion_lifetime: Duration,
+    #[serde(skip, default = "default_clock_skew")]
+    pub(crate) clock_skew: Duration,
+    #[serde(skip)]
+    pub(crate) additional_keys: Vec<ServiceAccountKey>,
+}
+
+impl ServiceAccount {
+    /// Sets how long the JWT assertion exchanged for a token is valid, 1 hour by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `lifetime` is zero or longer than 1 hour, as Google rejects it.
+    #[must_use]
+    pub fn with_assertion_lifetime(mut self, lifetime: Duration) -> Self {
+        assert!(
+            lifetime > Duration::ZERO && lifetime <= MAX_ASSERTION_LIFETIME,
+            "assertion lifetime must be between 0 and 1 hour"
+        );
+        self.assertion_lifetime = lifetime;
+        self
+    }
+
+    /// Backdates the `iat` of the JWT assertion by `skew`, 10 seconds by default. Google rejects
+    /// assertions issued in the future by its clock, e.g. from a machine whose clock is ahead.
+    #[must_use]
+    pub fn with_clock_skew(mut self, skew: Duration) -> Self {
+        self.clock_skew = skew;
+        self
+    }
+
+    /// Sends the token requests to `uri` instead of the `token_uri` of the key, e.g. an emulator
+    /// or a proxy. The JWT assertion is addressed to `uri` as well.
+    #[must_use]
+    pub fn with_token_uri(mut self, uri: impl Into<String>) -> Self {
+        self.token_uri = uri.into();
+        self
+    }
+
+    /// Falls back to `keys` in order when a token request signed with the key of the service
+    /// account is rejected, i.e. with a 401 response or an `invalid_grant` error, e.g. while
+    /// rotating keys. The key that last succeeded is tried first on the next fetch.
+    #[must_use]
+    pub fn with_additional_keys(mut self, keys: Vec<ServiceAccountKey>) -> Self {
+        self.additional_keys = keys;
+        self
+    }
+
+    /// Reads the service account key at `path` with the `cloud-platform` scope, and re-reads it
+    /// when it changes on disk, as [`Builder::reload_on_change`]. The new key is used for the
+    /// next token fetch by every clone of the service, requests in flight keep the current token.
+    pub fn watch(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        match impls::from_json_file(path, DEFAULT_SCOPES)? {
+            Credentials::ServiceAccount(mut sa) => {
+                sa.reload_path = Some(path.to_owned());
+                Ok(sa)
+            }
+            _ => Err(Error::UnsupportedCredentials("credentials other than `service_account`")),
+        }
+    }
+
+    pub fn client_email(&self) -> &str {
+        &self.client_email
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Signer(pub Arc<dyn JwtSigner>);
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Signer").field(&self.0).finish()
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for Signer {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Another key of a service account, see [`ServiceAccount::with_additional_keys`].
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone)]
+pub struct ServiceAccountKey {
+    pub(crate) private_key_id: String,
+    pub(crate) private_key: String,
+}
+
+impl ServiceAccountKey {
+    /// `private_key` is PEM encoded, as the `private_key` of a service account key file.
+    pub fn new(private_key_id: impl Into<String>, private_key: impl Into<String>) -> Self {
+        Self { private_key_id: private_key_id.into(), private_key: private_key.into() }
+    }
+}
+
+impl fmt::Debug for ServiceAccountKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServiceAccountKey").field("private_key_id", &self.private_key_id).finish()
+    }
+}
+
+type TokenSource = Arc<
+    dyn Fn() -> std::result::Result<StaticToken, Box<dyn StdError + Send + Sync>> + Send + Sync,
+>;
+
+/// A token that is served as is instead of being fetched, e.g. supplied by a token proxy sidecar.
+///
+/// Without an expiry, the token is served for as long as the service lives. Once it expires,
+/// the token of the source is served, or requests fail with
+/// [`AuthError::StaticTokenExpired`](crate::AuthError::StaticTokenExpired) without one.
+#[derive(Clone)]
+pub struct StaticToken {
+    pub(crate) token: String,
+    pub(crate) expires_at: Option<SystemTime>,
+    pub(crate) source: Option<TokenSource>,
+}
+
+impl StaticToken {
+    /// `token` is the access token without the scheme, it is sent as `Bearer <token>`.
+    pub fn new(token: impl Into<String>) -> Self {
+        Self { token: token.into(), expires_at: None, source: None }
+    }
+
+    /// Calls `source` for the token, and again each time the token is about to expire, e.g. to
+    /// re-read a file written by a sidecar. Tokens without an expiry are served for the default
+    /// lifetime of tokens, 1 hour. The source of the returned token is ignored.
+    pub fn from_source<F>(source: F) -> Self
+    where
+        F: Fn() -> std::result::Result<StaticToken, Box<dyn StdError + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self { token: String::new(), expires_at: None, source: Some(Arc::new(source)) }
+    }
+
+    /// Stops serving the token at `expires_at`.
+    #[must_use]
+    pub fn with_expiry(mut self, expires_at: SystemTime) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+}
+
+impl fmt::Debug for StaticToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticToken")
+            .field("expires_at", &self.expires_at)
+            .field("source", &self.source.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for StaticToken {
+    fn eq(&self, other: &Self) -> bool {
+        let source = match (&self.source, &other.source) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        self.token == other.token && self.expires_at == other.expires_at && source
+    }
 }
 
 #[derive(Debug)]
 pub struct Metadata {
-    pub(crate) client: gcemeta::Client<HttpConnector>,
     pub(crate) scopes: &'static [&'static str],
     pub(crate) account: Option<String>,
+    pub(crate) quota_project_id: Option<String>,
+    pub(crate) universe_domain: String,
+    // `host:port` that overrides the default metadata server
+    pub(crate) host: Option<String>,
+    // Connect timeout of the metadata server requests.
+    pub(crate) timeout: Duration,
+    // Sends the metadata server requests instead of the default client.
+    pub(crate) client: Option<HttpClient>,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self {
+            scopes: &[],
+            account: None,
+            quota_project_id: None,
+            universe_domain: DEFAULT_UNIVERSE_DOMAIN.to_owned(),
+            host: impls::metadata_host_from_env(),
+            timeout: impls::DEFAULT_METADATA_TIMEOUT,
+            client: None,
+        }
+    }
+}
+
+impl Metadata {
+    /// Fetches tokens of the default service account of the instance from the metadata server.
+    ///
+    /// Unlike [`Builder::metadata`], this does not check that the metadata server is reachable.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fetches tokens of the service account `email` from the metadata server, e.g. the Google
+    /// service account bound to the kubernetes service account of a pod by GKE Workload Identity.
+    ///
+    /// Unlike [`Builder::metadata`], this does not check that the metadata server is reachable.
+    pub fn for_service_account(email: &str) -> Self {
+        Self::new().with_account(email)
+    }
+
+    /// Returns the email of the service account, `None` for the default one.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// Fetches tokens of `account`, the email of a service account attached to the instance,
+    /// instead of the default one.
+    #[must_use]
+    pub fn with_account(mut self, account: impl Into<String>) -> Self {
+        self.account = Some(account.into());
+        self
+    }
+
+    /// Sends the requests to the metadata server at `host` (`host:port`) instead of the default
+    /// one, e.g. an emulator. Overrides `GCE_METADATA_HOST`.
+    ///
+    /// An invalid `host` fails when the credentials are used, with
+    /// [`InvalidMetadataHost`](crate::AuthError::InvalidMetadataHost).
+    #[must_use]
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Sends the token requests and the other requests to the metadata server through
+    /// `connector`, e.g. to bypass a proxy that the other egress goes through, or with a shorter
+    /// connect timeout. The connect timeout of the default connector is the `metadata_timeout`.
+    #[must_use]
+    pub fn with_connector<C>(mut self, connector: C) -> Self
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        self.client = Some(HttpClient::with_connector(connector));
+        self
+    }
+
+    /// Returns the id of the project, e.g. `my-project`.
+    ///
+    /// This and the other values of the metadata server are cached for the lifetime of the process.
+    pub async fn project_id(&self) -> std::result::Result<String, AuthError> {
+        self.get("project/project-id").await
+    }
+
+    /// Returns the number of the project, e.g. `123456789012`.
+    pub async fn numeric_project_id(&self) -> std::result::Result<String, AuthError> {
+        self.get("project/numeric-project-id").await
+    }
+
+    /// Returns the email of the service account, also for the default one.
+    pub async fn email(&self) -> std::result::Result<String, AuthError> {
+        let path = auth::service_account_path(self.account.as_deref());
+        self.get(&format!("{}/email", path)).await
+    }
+
+    /// Returns the email, the scopes granted to the instance and the aliases of the service
+    /// account, e.g. to log which identity this process runs as.
+    pub async fn service_account_info(&self) -> std::result::Result<ServiceAccountInfo, AuthError> {
+        let path = auth::service_account_path(self.account.as_deref());
+        let path = format!("{}/?recursive=true", path);
+        Ok(serde_json::from_str(&self.get(&path).await?)?)
+    }
+
+    async fn get(&self, path: &str) -> std::result::Result<String, AuthError> {
+        auth::metadata_value(self, path).await
+    }
+
+    /// Requests tokens with `scopes`, sent comma separated as the `scopes` query parameter of
+    /// `/computeMetadata/v1/instance/service-accounts/{account}/token`.
+    ///
+    /// Without scopes the query parameter is omitted, and the token has the scopes of the
+    /// instance. [`Builder::metadata`] uses [`Builder::scopes`], `cloud-platform` by default.
+    #[must_use]
+    pub fn with_scopes(mut self, scopes: &'static [&'static str]) -> Self {
+        self.scopes = scopes;
+        self
+    }
+}
+
+/// A service account attached to the instance, see [`Metadata::service_account_info`].
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ServiceAccountInfo {
+    pub email: String,
+    /// The access scopes of the instance, tokens never have other scopes.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// e.g. `default` for the default service account.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+impl ServiceAccountInfo {
+    // Returns the scopes that tokens of this service account can not have.
+    pub(crate) fn missing_scopes<'a>(&self, scopes: &[&'a str]) -> Vec<&'a str> {
+        const CLOUD_PLATFORM: &str = "https://www.googleapis.com/auth/cloud-platform";
+        // `cloud-platform` covers the other scopes of Google Cloud APIs.
+        if self.scopes.iter().any(|scope| scope == CLOUD_PLATFORM) {
+            return Vec::new();
+        }
+        scopes.iter().copied().filter(|scope| !self.scopes.iter().any(|s| s == scope)).collect()
+    }
+}
+
+// https://google.aip.dev/auth/4117
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug, serde::Deserialize)]
+pub struct ExternalAccount {
+    #[serde(skip)]
+    pub(crate) scopes: &'static [&'static str],
+    // json fields
+    pub(crate) audience: String,
+    pub(crate) subject_token_type: String,
+    #[serde(default = "default_sts_token_url")]
+    pub(crate) token_url: String,
+    pub(crate) service_account_impersonation_url: Option<String>,
+    pub(crate) credential_source: CredentialSource,
+    pub(crate) quota_project_id: Option<String>,
+    #[serde(default = "default_universe_domain")]
+    pub(crate) universe_domain: String,
+    #[serde(skip)]
+    pub(crate) reload_path: Option<PathBuf>,
+}
+
+impl ExternalAccount {
+    /// The workload identity pool provider that the subject token is exchanged with.
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+}
+
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub(crate) enum CredentialSource {
+    // Tried in order, `environment_id` tells AWS apart from `url`.
+    Aws(AwsCredentialSource),
+    Executable {
+        executable: ExecutableCredentialSource,
+    },
+    File {
+        file: PathBuf,
+        #[serde(default)]
+        format: SubjectTokenFormat,
+    },
+    Url {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        #[serde(default)]
+        format: SubjectTokenFormat,
+    },
+}
+
+// https://google.aip.dev/auth/4117#determining-the-subject-token-in-microsoft-azure-and-url-sourced-credentials
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum SubjectTokenFormat {
+    #[default]
+    Text,
+    Json {
+        subject_token_field_name: String,
+    },
+}
+
+// https://google.aip.dev/auth/4117#determining-the-subject-token-in-executable-sourced-credentials
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone, Debug, serde::Deserialize)]
+pub(crate) struct ExecutableCredentialSource {
+    pub command: String,
+    pub timeout_millis: Option<u64>,
+    pub output_file: Option<PathBuf>,
+}
+
+// https://google.aip.dev/auth/4117#determining-the-subject-token-in-aws
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone, Debug, serde::Deserialize)]
+pub(crate) struct AwsCredentialSource {
+    pub environment_id: String,
+    pub region_url: Option<String>,
+    // the security credentials url
+    pub url: Option<String>,
+    pub regional_cred_verification_url: String,
+    pub imdsv2_session_token_url: Option<String>,
+}
+
+impl From<User> for Credentials {
+    fn from(user: User) -> Self {
+        Self::User(user)
+    }
+}
+
+impl From<ServiceAccount> for Credentials {
+    fn from(sa: ServiceAccount) -> Self {
+        Self::ServiceAccount(sa)
+    }
+}
+
+impl From<StaticToken> for Credentials {
+    fn from(token: StaticToken) -> Self {
+        Self::StaticToken(token)
+    }
+}
+
+impl From<Metadata> for Credentials {
+    fn from(meta: Metadata) -> Self {
+        Self::Metadata(Box::new(meta))
+    }
 }
 
 #[cfg(test)]
 impl PartialEq for Metadata {
     fn eq(&self, other: &Self) -> bool {
-        self.scopes == other.scopes && self.account == other.account
+        self.scopes == other.scopes
+            && self.account == other.account
+            && self.quota_project_id == other.quota_project_id
+            && self.universe_domain == other.universe_domain
+            && self.host == other.host
+            && self.timeout == other.timeout
     }
 }
 
+#[derive(Default)]
 enum Source<'a> {
     None,
+    #[default]
     Default,
-    ApiKey { key: String },
-    Json { data: &'a [u8] },
-    JsonFile { path: &'a Path },
-    Metadata { account: Option<String> },
-}
-
-impl<'a> Default for Source<'a> {
-    fn default() -> Self {
-        Self::Default
-    }
+    ApiKey {
+        key: String,
+    },
+    Json {
+        data: &'a [u8],
+    },
+    JsonFile {
+        path: &'a Path,
+    },
+    Signer {
+        client_email: String,
+        signer: Box<dyn JwtSigner>,
+    },
+    Metadata {
+        account: Option<String>,
+    },
 }
 
 pub struct Builder<'a> {
     scopes: &'static [&'static str],
     source: Source<'a>,
+    on_refresh_token: Option<RefreshTokenCallback>,
+    quota_project_id: Option<String>,
+    reload_on_change: bool,
+    metadata_host: Option<String>,
+    metadata_timeout: Duration,
+    metadata_disabled: bool,
+    universe_domain: Option<String>,
 }
 
 impl<'a> Default for Builder<'a> {
     fn default() -> Self {
         Self {
-            scopes: &["https://www.googleapis.com/auth/cloud-platform"],
+            scopes: DEFAULT_SCOPES,
             source: Default::default(),
+            on_refresh_token: None,
+            quota_project_id: None,
+            reload_on_change: false,
+            metadata_host: None,
+            metadata_timeout: impls::DEFAULT_METADATA_TIMEOUT,
+            metadata_disabled: false,
+            universe_domain: None,
         }
     }
 }
@@ -122,28 +919,134 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Uses a service account whose JWT assertions are signed by `signer` instead of a private key.
+    #[must_use]
+    pub fn signer(mut self, client_email: impl Into<String>, signer: Box<dyn JwtSigner>) -> Self {
+        self.source = Source::Signer { client_email: client_email.into(), signer };
+        self
+    }
+
+    /// Fetches tokens from the metadata server, of `account` if specified or of the default
+    /// service account otherwise.
     #[must_use]
     pub fn metadata(mut self, account: impl Into<Option<String>>) -> Self {
         self.source = Source::Metadata { account: account.into() };
         self
     }
 
+    /// Sends metadata server requests to `host` (`host:port`), e.g. an emulator or a local test
+    /// server. This takes precedence over the `GCE_METADATA_HOST` environment variable.
+    #[must_use]
+    pub fn metadata_host(mut self, host: impl Into<String>) -> Self {
+        self.metadata_host = Some(host.into());
+        self
+    }
+
+    /// Sets the timeout of checking that the metadata server is reachable, and the connect timeout
+    /// of the token requests sent to it. The default is 1 second.
+    #[must_use]
+    pub fn metadata_timeout(mut self, timeout: Duration) -> Self {
+        self.metadata_timeout = timeout;
+        self
+    }
+
+    /// Makes the default credentials fail immediately instead of checking the metadata server if
+    /// no credentials file is found, e.g. in CI containers without egress. Setting the
+    /// `NO_GCE_CHECK` environment variable to `true` has the same effect. Explicit
+    /// [`metadata`](Builder::metadata) credentials are not affected.
+    #[must_use]
+    pub fn disable_metadata_server(mut self) -> Self {
+        self.metadata_disabled = true;
+        self
+    }
+
     #[must_use]
     pub fn scopes(mut self, scopes: &'static [&'static str]) -> Self {
         self.scopes = scopes;
         self
     }
 
+    /// Calls `f` with the new refresh token whenever Google rotates the refresh token of user
+    /// credentials, so that the caller can persist it.
+    #[must_use]
+    pub fn on_refresh_token(mut self, f: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_refresh_token = Some(RefreshTokenCallback(Arc::new(f)));
+        self
+    }
+
+    /// Sets the project sent as `x-goog-user-project`, overriding `quota_project_id` in the json.
+    /// The `GOOGLE_CLOUD_QUOTA_PROJECT` environment variable takes precedence over this.
+    /// This has no effect on API key credentials and no credentials.
+    #[must_use]
+    pub fn with_quota_project(mut self, id: impl Into<String>) -> Self {
+        self.quota_project_id = Some(id.into());
+        self
+    }
+
+    /// Sets the universe that the credentials belong to, overriding `universe_domain` in the json.
+    /// The OAuth 2.0 token endpoint becomes `https://oauth2.{domain}/token`.
+    #[must_use]
+    pub fn universe_domain(mut self, domain: impl Into<String>) -> Self {
+        self.universe_domain = Some(domain.into());
+        self
+    }
+
+    /// Re-reads the file specified by [`json_file`](Builder::json_file) when its modification time
+    /// has changed, or after the token endpoint rejected the credentials. If the file can not be
+    /// parsed, the previously loaded credentials are kept.
+    #[must_use]
+    pub fn reload_on_change(mut self, reload: bool) -> Self {
+        self.reload_on_change = reload;
+        self
+    }
+
+    /// Persists the new refresh token to `store` whenever Google rotates the refresh token of user
+    /// credentials. This replaces [`on_refresh_token`](Builder::on_refresh_token).
+    #[must_use]
+    pub fn refresh_token_store(self, store: impl RefreshTokenStore) -> Self {
+        self.on_refresh_token(move |refresh_token| store.store(refresh_token))
+    }
+
     pub async fn build(self) -> Result<Credentials> {
-        match self.source {
+        let reload_path = match self.source {
+            Source::JsonFile { path } if self.reload_on_change => Some(path.to_owned()),
+            _ => None,
+        };
+        let mut credentials = match self.source {
             Source::None => Ok(Credentials::None),
-            Source::Default => impls::find_default(self.scopes).await,
+            Source::Default => {
+                let disabled = self.metadata_disabled || impls::metadata_disabled_by_env();
+                let metadata =
+                    Some((self.metadata_host, self.metadata_timeout)).filter(|_| !disabled);
+                impls::find_default(self.scopes, metadata).await
+            }
             Source::ApiKey { key } => impls::from_api_key(key),
             Source::Json { data } => impls::from_json(data, self.scopes),
             Source::JsonFile { path } => impls::from_json_file(path, self.scopes),
-            Source::Metadata { account } => Ok(impls::from_metadata(account, self.scopes)
-                .await?
-                .expect("this process must be running on GCE")),
+            Source::Signer { client_email, signer } => {
+                Ok(impls::from_signer(client_email, signer, self.scopes))
+            }
+            Source::Metadata { account } => Ok(impls::from_metadata(
+                account,
+                self.scopes,
+                self.metadata_host,
+                self.metadata_timeout,
+            )
+            .await?
+            .expect("this process must be running on GCE")),
+        }?;
+        if let Credentials::User(ref mut user) = credentials {
+            user.on_refresh_token = self.on_refresh_token;
+        }
+        if let Some(path) = reload_path {
+            credentials.set_reload_path(path);
+        }
+        if let Some(id) = impls::quota_project_id(impls::non_empty_env, self.quota_project_id) {
+            credentials.set_quota_project_id(id);
+        }
+        if let Some(ref domain) = self.universe_domain {
+            credentials.set_universe_domain(domain);
         }
+        Ok(credentials)
     }
 }
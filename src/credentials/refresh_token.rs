@@ -0,0 +1,70 @@
+use std::{error::Error as StdError, fmt, fs, path::PathBuf};
+
+use tracing::{trace, warn};
+
+use crate::auth;
+
+/// Persists refresh tokens of user credentials rotated by Google.
+pub trait RefreshTokenStore: fmt::Debug + Send + Sync + 'static {
+    fn store(&self, refresh_token: &str);
+}
+
+/// A [`RefreshTokenStore`] that rewrites the `refresh_token` field of an `authorized_user`
+/// credentials file, keeping the other fields as is.
+#[derive(Debug)]
+pub struct FileRefreshTokenStore {
+    path: PathBuf,
+}
+
+impl FileRefreshTokenStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn update(&self, refresh_token: &str) -> Result<(), Box<dyn StdError>> {
+        let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&self.path)?)?;
+        json.as_object_mut()
+            .ok_or("credentials file is not a json object")?
+            .insert("refresh_token".to_owned(), refresh_token.into());
+        auth::write_atomic(&self.path, &serde_json::to_vec_pretty(&json)?)?;
+        Ok(())
+    }
+}
+
+impl RefreshTokenStore for FileRefreshTokenStore {
+    fn store(&self, refresh_token: &str) {
+        trace!("try storing refresh token to {:?}", self.path);
+        if let Err(err) = self.update(refresh_token) {
+            warn!("failed to store refresh token: path={:?}, err={:?}", self.path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file_refresh_token_store() {
+        let path = std::env::temp_dir().join("google-authz-test-file-refresh-token-store.json");
+        fs::write(
+            &path,
+            r#"{
+  "client_id": "xxx.apps.googleusercontent.com",
+  "client_secret": "secret-xxx",
+  "refresh_token": "refresh-xxx",
+  "type": "authorized_user"
+}"#,
+        )
+        .unwrap();
+
+        FileRefreshTokenStore::new(&path).store("refresh-yyy");
+
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(json["refresh_token"], "refresh-yyy");
+        assert_eq!(json["client_secret"], "secret-xxx");
+        assert_eq!(json["type"], "authorized_user");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
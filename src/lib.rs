@@ -1,8 +1,30 @@
 mod auth;
+#[cfg(feature = "axum")]
+mod axum;
+mod config;
 mod credentials;
+mod redacted;
 mod service;
+mod store;
 mod sync;
+#[cfg(test)]
+mod test_util;
+mod verify;
 
-pub use auth::Error as AuthError;
-pub use credentials::{Credentials, Error as CredentialsError};
+pub use auth::{
+    revoke_token, CachedToken, Error as AuthError, FileTokenStore, HttpClientConfig, JwtSigner,
+    SignFuture, StsExchange, StsToken, TokenLifetime, TokenSnapshot, TokenStore, UserInfo,
+};
+pub use config::{CredentialType, Oauth2Config};
+pub use credentials::{
+    on_gce, Credentials, Error as CredentialsError, ExternalAccount as ExternalAccountCredentials,
+    FileRefreshTokenStore, Metadata as MetadataCredentials, RefreshTokenStore,
+    ServiceAccount as ServiceAccountCredentials, ServiceAccountInfo, ServiceAccountKey,
+    StaticToken, User as UserCredentials,
+};
 pub use service::{Error, GoogleAuthz};
+pub use store::CredentialStore;
+pub use verify::{Claims, Verifier};
+
+#[cfg(feature = "axum")]
+pub use crate::axum::GoogleAuthzLayer;
@@ -0,0 +1,37 @@
+use std::fmt;
+
+/// Redacted formats as `<redacted>` so that secrets like tokens never end up in logs.
+pub(crate) struct Redacted<T> {
+    // Only read by `expose`, in tests.
+    #[cfg_attr(not(test), allow(dead_code))]
+    value: T,
+}
+
+impl<T> Redacted<T> {
+    pub fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    #[cfg(test)]
+    pub fn expose(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let redacted = Redacted::new("Bearer xxx");
+        assert_eq!(format!("{:?}", redacted), "<redacted>");
+        assert_eq!(*redacted.expose(), "Bearer xxx");
+    }
+}
@@ -1,18 +1,28 @@
 use std::{
     fmt,
     future::{self, Ready},
+    sync::Arc,
     task::{self, Poll},
+    time::{Duration, SystemTime},
 };
 
 use futures_util::{
     future::{Either, MapErr},
     TryFutureExt as _,
 };
-use hyper::Request;
+use hyper::{
+    client::connect::Connect,
+    header::{HeaderName, HeaderValue},
+    Request,
+};
 
 use crate::{
-    auth::{self, Auth, Config},
-    credentials::Credentials,
+    auth::{
+        self, Auth, CachedToken, HttpClientConfig, Oauth2Builder, TokenCallback, TokenSnapshot,
+        TokenStore,
+    },
+    config::Oauth2Config,
+    credentials::{self, Credentials},
 };
 
 /// Represents an inner service error or Google authentication error.
@@ -25,14 +35,14 @@ pub enum Error<E> {
 }
 
 pub struct Builder<S> {
-    config: Config,
+    oauth2: Oauth2Builder,
     credentials: Option<Credentials>,
     service: S,
 }
 
 impl Builder<()> {
     pub fn new<S>(service: S) -> Builder<S> {
-        Builder { config: Default::default(), credentials: Default::default(), service }
+        Builder { oauth2: Default::default(), credentials: Default::default(), service }
     }
 }
 
@@ -40,13 +50,185 @@ impl<S> Builder<S> {
     #[must_use]
     #[cfg(not(feature = "tonic"))]
     pub fn enforce_https(mut self, enforce_https: bool) -> Self {
-        self.config.enforce_https = enforce_https;
+        self.oauth2.config.enforce_https = enforce_https;
         self
     }
 
+    /// Retries a failed token fetch up to `max_retry` times, 3 by default.
+    ///
+    /// Only [retryable](crate::AuthError::is_retryable) errors are retried, e.g. `invalid_grant`
+    /// is returned as is on the first attempt. Once the fetch has failed, the next `poll_ready`
+    /// starts over after `backoff`.
     #[must_use]
     pub fn max_retry(mut self, max_retry: u8) -> Self {
-        self.config.max_retry = max_retry;
+        self.oauth2 = self.oauth2.max_retry(max_retry);
+        self
+    }
+
+    /// Refreshes the token `refresh_buffer` before it expires, 10 seconds by default.
+    ///
+    /// The buffer is clamped to half of the lifetime of each token, e.g. a token that expires in
+    /// 10 seconds is refreshed 5 seconds before it expires rather than right away.
+    ///
+    /// # Panics
+    ///
+    /// `build` panics if this is not shorter than [`default_expires_in`](Builder::default_expires_in).
+    #[must_use]
+    pub fn refresh_buffer(mut self, refresh_buffer: Duration) -> Self {
+        self.oauth2 = self.oauth2.refresh_buffer(refresh_buffer);
+        self
+    }
+
+    /// Waits up to `backoff` before the first retry of a failed token fetch, doubling it on every
+    /// following retry, 200 milliseconds by default. The actual delay is random, from zero to
+    /// the doubled one, so that clients that failed together do not retry together.
+    #[must_use]
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.oauth2 = self.oauth2.backoff(backoff);
+        self
+    }
+
+    /// Caps the delay between token fetch retries, 30 seconds by default.
+    #[must_use]
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.oauth2 = self.oauth2.max_backoff(max_backoff);
+        self
+    }
+
+    /// Waits as long as the `Retry-After` header of a 429 response from the token endpoint asks,
+    /// if it is at most `max_retry_after`, 60 seconds by default. Otherwise the fetch fails with
+    /// [`AuthError::RateLimited`](crate::AuthError::RateLimited) without retrying.
+    #[must_use]
+    pub fn max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.oauth2 = self.oauth2.max_retry_after(max_retry_after);
+        self
+    }
+
+    /// Fails a token fetch attempt with [`AuthError::Timeout`](crate::AuthError::Timeout) if it
+    /// takes longer than `timeout`, 10 seconds by default. The attempt is then retried as any
+    /// other failed one, and its request is dropped with its connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timeout` is zero.
+    #[must_use]
+    pub fn fetch_timeout(mut self, timeout: Duration) -> Self {
+        self.oauth2 = self.oauth2.fetch_timeout(timeout);
+        self
+    }
+
+    /// Keeps serving the last token for up to `grace` past its expiry if refetching it fails after
+    /// all retries, e.g. during an outage of the token endpoint, instead of failing requests.
+    /// Refetching is tried again every few seconds meanwhile. Requests fail once the grace period
+    /// is over; by default, as soon as the retries are exhausted.
+    ///
+    /// See [`GoogleAuthz::is_degraded`].
+    #[must_use]
+    pub fn stale_token_grace(mut self, grace: Duration) -> Self {
+        self.oauth2 = self.oauth2.stale_grace(grace);
+        self
+    }
+
+    /// Refreshes the token in a background task before it expires, so that requests do not wait
+    /// for the token endpoint. Disabled by default, as the task is spawned on the tokio runtime
+    /// that `build` is called within. The first token is still fetched by the first request. A
+    /// failed refresh is retried with an exponential backoff, unless the error is not
+    /// [retryable](crate::AuthError::is_retryable), which stops the task. The task also stops when
+    /// the service and all its clones are dropped, or on [`GoogleAuthz::shutdown`].
+    #[must_use]
+    pub fn background_refresh(mut self, enabled: bool) -> Self {
+        self.oauth2 = self.oauth2.background_refresh(enabled);
+        self
+    }
+
+    /// Loads a still valid token from `store` instead of fetching it, and stores every fetched token.
+    #[must_use]
+    pub fn token_store(mut self, store: impl TokenStore) -> Self {
+        self.oauth2.config.token_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Calls `f` after every token fetch with the new token, and the new refresh token if Google
+    /// rotated the one of user credentials, e.g. to persist both so that a long-lived CLI keeps
+    /// working across restarts. `f` is called without holding any lock, so it can call back into
+    /// the service, but it delays the request that triggered the fetch. Write files atomically,
+    /// to a temporary file that is then renamed over the old one, as
+    /// [`FileTokenStore`](crate::FileTokenStore) does.
+    #[must_use]
+    pub fn on_token(
+        mut self,
+        f: impl Fn(&CachedToken, Option<&str>) + Send + Sync + 'static,
+    ) -> Self {
+        self.oauth2.config.on_token = Some(TokenCallback(Arc::new(f)));
+        self
+    }
+
+    /// Sets the header that the OAuth 2.0 token is sent with, `authorization` by default.
+    #[must_use]
+    pub fn header_name(mut self, name: HeaderName) -> Self {
+        self.oauth2.config.header_name = name;
+        self
+    }
+
+    /// Also sends an ID token for `audience` with `header_name`, next to the access token, e.g.
+    /// `proxy-authorization` for a service behind Identity-Aware Proxy that calls Google APIs too.
+    /// The ID token is fetched and refreshed independently of the access token, and
+    /// `poll_ready` waits for both.
+    ///
+    /// Only service accounts and the metadata server can issue ID tokens, `build` fails for the
+    /// other credentials.
+    #[must_use]
+    pub fn id_token(mut self, audience: impl Into<String>, header_name: HeaderName) -> Self {
+        self.oauth2.config.id_token =
+            Some(auth::IdToken { audience: audience.into(), header_name });
+        self
+    }
+
+    /// Sets the scheme prefix of the OAuth 2.0 token, `token_type` of the token response
+    /// (i.e. `Bearer`) by default.
+    #[must_use]
+    pub fn header_scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.oauth2.config.header_scheme = Some(scheme.into());
+        self
+    }
+
+    /// Sets the token lifetime assumed when the token response does not include `expires_in`,
+    /// 1 hour by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `expires_in` is zero.
+    #[must_use]
+    pub fn default_expires_in(mut self, expires_in: Duration) -> Self {
+        self.oauth2 = self.oauth2.default_expires_in(expires_in);
+        self
+    }
+
+    /// Uses `connector` to send requests to the OAuth 2.0 token endpoint, e.g. to go through a
+    /// unix socket or a proxy with certificate pinning.
+    #[must_use]
+    pub fn connector<C>(mut self, connector: C) -> Self
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        self.oauth2.config.http = self.oauth2.config.http.connector(connector);
+        self
+    }
+
+    /// Sends HTTP/2 pings to the OAuth 2.0 token endpoint every `interval`, also while the
+    /// connection is idle, and reconnects if a ping is not acknowledged within `timeout`.
+    /// Otherwise a connection silently dropped by the server can fail the next token fetch.
+    #[must_use]
+    pub fn http2_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.oauth2 = self.oauth2.with_http2_keepalive(interval, timeout);
+        self
+    }
+
+    /// Tunes the connection pool of the token endpoint connections, independently of the pool of
+    /// the wrapped service, e.g. to keep fewer idle connections open.
+    #[must_use]
+    pub fn http_client_config(mut self, config: HttpClientConfig) -> Self {
+        self.oauth2 = self.oauth2.with_http_client_config(&config);
         self
     }
 
@@ -56,16 +238,41 @@ impl<S> Builder<S> {
         self
     }
 
+    /// # Panics
+    ///
+    /// Panics if no default credentials are found, the credentials are invalid, e.g. a service
+    /// account key with a malformed private key, or the settings are, e.g. a `refresh_buffer` not
+    /// shorter than `default_expires_in`. [`try_build`](Builder::try_build) returns the error
+    /// instead.
     pub async fn build<B>(self) -> GoogleAuthz<S>
     where
         S: tower_service::Service<Request<B>>,
     {
-        let Builder { config, credentials, service } = self;
+        let (auth, service) = self.build_auth().await.unwrap_or_else(|err| panic!("{}", err));
+        GoogleAuthz::from_parts(auth, service)
+    }
+
+    /// Like [`build`](Builder::build), but also fetches the first token, so that invalid
+    /// credentials, e.g. a revoked refresh token, fail at startup rather than on the first request.
+    /// Missing or malformed credentials, e.g. a service account key with an empty
+    /// `client_email`, and invalid settings are returned as an error rather than a panic.
+    pub async fn try_build<B>(self) -> Result<GoogleAuthz<S>, auth::Error>
+    where
+        S: tower_service::Service<Request<B>>,
+    {
+        let (auth, service) = self.build_auth().await?;
+        let mut service = GoogleAuthz::from_parts(auth, service);
+        service.warm_up().await?;
+        Ok(service)
+    }
+
+    pub(crate) async fn build_auth(self) -> Result<(Auth, S), auth::Error> {
+        let Builder { oauth2, credentials, service } = self;
         let credentials = match credentials {
             Some(credentials) => credentials,
-            None => Credentials::new().await,
+            None => Credentials::builder().build().await?,
         };
-        GoogleAuthz { auth: Auth::new(credentials, config), service }
+        Ok((Auth::new(credentials, oauth2)?, service))
     }
 }
 
@@ -74,6 +281,114 @@ pub struct GoogleAuthz<S> {
     service: S,
 }
 
+impl<S> GoogleAuthz<S> {
+    pub(crate) fn from_parts(auth: Auth, service: S) -> Self {
+        Self { auth, service }
+    }
+
+    /// Returns true while an expired token is served because refetching it failed, see
+    /// [`Builder::stale_token_grace`].
+    pub fn is_degraded(&self) -> bool {
+        self.auth.is_degraded()
+    }
+
+    /// Returns how long the token sent with requests remains valid, without refreshing it, e.g.
+    /// for a health check to report that the credentials are about to disrupt requests.
+    /// [`TokenLifetime::Unknown`](crate::TokenLifetime::Unknown) until a token is fetched, and
+    /// for credentials other than OAuth 2.0 ones.
+    pub fn remaining_lifetime(&self) -> crate::TokenLifetime {
+        self.auth.remaining_lifetime()
+    }
+
+    /// Returns the type and expiry of the token sent with requests, without refreshing it. `None`
+    /// until a token is fetched, and for credentials other than OAuth 2.0 ones.
+    pub fn token_snapshot(&self) -> Option<TokenSnapshot> {
+        self.auth.token_snapshot()
+    }
+
+    /// Fetches the token now, with the usual retries, rather than on the first request, e.g. so
+    /// that a readiness check fails on invalid credentials. The first request then sends the
+    /// token without waiting. See also [`Builder::try_build`].
+    pub async fn warm_up(&mut self) -> Result<(), auth::Error> {
+        futures_util::future::poll_fn(|cx| self.auth.poll_ready(cx)).await
+    }
+
+    /// Warms up every service concurrently, e.g. one per tenant, so that the startup takes as
+    /// long as the slowest token fetch rather than all of them. Returns the result of each
+    /// service in order, see [`warm_up`](Self::warm_up).
+    pub async fn warm_up_all(services: Vec<&mut Self>) -> Vec<Result<(), auth::Error>> {
+        Auth::prefetch_all(services.into_iter().map(|service| &mut service.auth).collect()).await
+    }
+
+    /// Returns the current access token, fetching it first if needed, e.g. to hand it to a
+    /// client that is not a tower service. The token is shared with the requests of the service.
+    ///
+    /// Fails with [`AuthError::NoAccessToken`](crate::AuthError::NoAccessToken) for credentials
+    /// other than OAuth 2.0 ones, e.g. an API key.
+    pub async fn access_token(&mut self) -> Result<String, auth::Error> {
+        self.auth.access_token().await
+    }
+
+    /// Like [`access_token`](Self::access_token), but returns the whole header value, e.g.
+    /// `Bearer xxx`.
+    pub async fn header_value(&mut self) -> Result<HeaderValue, auth::Error> {
+        self.auth.header_value().await
+    }
+
+    /// Returns the Google account of the current token, e.g. the signed in user of
+    /// [`Credentials::User`](crate::Credentials::User) credentials. The token needs the
+    /// `openid`, `email` or `profile` scopes, see [`UserInfo`](crate::UserInfo).
+    ///
+    /// Fails with [`AuthError::NoAccessToken`](crate::AuthError::NoAccessToken) for credentials
+    /// other than OAuth 2.0 ones, e.g. an API key.
+    pub async fn userinfo(&mut self) -> Result<crate::UserInfo, auth::Error> {
+        self.auth.userinfo().await
+    }
+
+    /// Discards the current token, even if it has not expired, and fetches a new one, e.g. to
+    /// stop using a token that may have leaked. The new token is shared with every clone.
+    ///
+    /// Fails with [`AuthError::NoAccessToken`](crate::AuthError::NoAccessToken) for credentials
+    /// other than OAuth 2.0 ones, e.g. an API key.
+    pub async fn force_refresh(&mut self) -> Result<(), auth::Error> {
+        self.auth.force_refresh().await
+    }
+
+    /// Discards the current token, even if it has not expired, so that the next request waits for
+    /// a new one, e.g. after an API responded 401 because the key was revoked or IAM changed. It
+    /// applies to every clone of the service. A token fetch in flight is not restarted, so that
+    /// concurrent invalidations result in a single fetch. Does nothing for credentials other
+    /// than OAuth 2.0 ones.
+    pub fn invalidate(&self) {
+        self.auth.invalidate()
+    }
+
+    /// Like [`force_refresh`](Self::force_refresh), but returns the expiry of the new token.
+    pub async fn refresh(&mut self) -> Result<SystemTime, auth::Error> {
+        self.auth.refresh().await
+    }
+
+    /// Revokes the refresh token of user credentials, or the current access token of other
+    /// OAuth 2.0 credentials, e.g. on logout. Afterwards, no token is fetched anymore by any
+    /// clone of the service, and requests fail with
+    /// [`AuthError::Revoked`](crate::AuthError::Revoked) instead of retrying.
+    ///
+    /// Fails with [`AuthError::NoAccessToken`](crate::AuthError::NoAccessToken) for credentials
+    /// other than OAuth 2.0 ones, e.g. an API key.
+    pub async fn revoke(&self) -> Result<(), auth::Error> {
+        self.auth.revoke().await
+    }
+
+    /// Stops refreshing the token and waits for a token fetch in flight to complete, at most
+    /// the fetch timeout, so that it is not dropped mid-request when the runtime shuts down.
+    /// It applies to every clone of the service. Afterwards, requests are sent with the current
+    /// token until it expires, then `poll_ready` fails with
+    /// [`AuthError::Shutdown`](crate::AuthError::Shutdown).
+    pub async fn shutdown(&self) {
+        self.auth.shutdown().await
+    }
+}
+
 impl GoogleAuthz<()> {
     pub async fn new<S, B>(service: S) -> GoogleAuthz<S>
     where
@@ -85,6 +400,33 @@ impl GoogleAuthz<()> {
     pub fn builder<S>(service: S) -> Builder<S> {
         Builder::new(service)
     }
+
+    /// Returns a builder with the credentials and settings of `config`, e.g. read from a
+    /// configuration file. The other settings can still be set on the builder.
+    pub fn builder_from_config<S>(
+        service: S,
+        config: Oauth2Config,
+    ) -> credentials::Result<Builder<S>> {
+        let (credentials, oauth2) = config.into_oauth2()?;
+        Ok(Builder { oauth2, credentials: Some(credentials), service })
+    }
+}
+
+impl<S: Clone> GoogleAuthz<S> {
+    /// Creates a new service whose token is fetched and renewed independently of this one.
+    pub fn fork(&self) -> Self {
+        Self { auth: self.auth.fork(), service: self.service.clone() }
+    }
+
+    /// Returns a service whose OAuth 2.0 tokens have `scopes` instead of the scopes of the
+    /// credentials, e.g. to call another API with the same credentials. The token is cached
+    /// separately, and shared with every service of the same scopes created from this one.
+    ///
+    /// Returns `None` if the credentials can not request other scopes, i.e. user credentials
+    /// and chains. Services without OAuth 2.0 credentials are returned as is.
+    pub fn for_scopes(&self, scopes: &'static [&'static str]) -> Option<Self> {
+        Some(Self { auth: self.auth.for_scopes(scopes)?, service: self.service.clone() })
+    }
 }
 
 impl<S: Clone> Clone for GoogleAuthz<S> {
@@ -133,6 +475,7 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::Echo;
 
     #[tokio::test]
     async fn test_compile() {
@@ -163,4 +506,113 @@ mod test {
         assert_send(&svc);
         assert_sync(&svc);
     }
+
+    #[tokio::test]
+    async fn test_unauthenticated() {
+        use tower_service::Service as _;
+
+        let mut svc = GoogleAuthz::builder(Echo).credentials(Credentials::none()).build().await;
+        assert!(format!("{:?}", svc).contains("Unauthenticated"));
+
+        futures_util::future::poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        let req = Request::builder().uri("http://localhost:8085/v1/topics").body(()).unwrap();
+        let req = svc.call(req).await.unwrap();
+        assert_eq!(req.uri(), "http://localhost:8085/v1/topics");
+        assert!(req.headers().is_empty());
+        assert!(matches!(svc.access_token().await, Err(auth::Error::NoAccessToken)));
+    }
+
+    #[derive(Debug)]
+    struct Revoked;
+
+    impl crate::JwtSigner for Revoked {
+        fn alg(&self) -> &str {
+            "RS256"
+        }
+
+        fn key_id(&self) -> &str {
+            "key-1"
+        }
+
+        fn sign(&self, _: &[u8]) -> crate::SignFuture {
+            Box::pin(async { Err(auth::Error::Jwt("the key is disabled".into())) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_try_build() {
+        let service = GoogleAuthz::builder(Echo).credentials(Credentials::none()).try_build().await;
+        assert!(service.is_ok());
+
+        let credentials =
+            Credentials::builder().signer("sa@project", Box::new(Revoked)).build().await.unwrap();
+        let service = GoogleAuthz::builder(Echo).credentials(credentials).try_build().await;
+        assert!(matches!(service, Err(auth::Error::Jwt(_))));
+
+        let credentials =
+            Credentials::builder().signer("sa@project", Box::new(Revoked)).build().await.unwrap();
+        let mut service = GoogleAuthz::builder(Echo).credentials(credentials).build().await;
+        assert!(matches!(service.warm_up().await, Err(auth::Error::Jwt(_))));
+        // an empty client email fails without panicking
+        let credentials =
+            Credentials::builder().signer("", Box::new(Revoked)).build().await.unwrap();
+        let service = GoogleAuthz::builder(Echo).credentials(credentials).try_build().await;
+        assert!(matches!(service, Err(auth::Error::InvalidServiceAccount { .. })));
+
+        let credentials =
+            Credentials::builder().signer("sa@project", Box::new(Revoked)).build().await.unwrap();
+        let service = GoogleAuthz::builder(Echo)
+            .credentials(credentials)
+            .refresh_buffer(Duration::from_secs(3600))
+            .try_build()
+            .await;
+        assert!(matches!(service, Err(auth::Error::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_id_token() {
+        use hyper::header::{AUTHORIZATION, PROXY_AUTHORIZATION};
+        use tower_service::Service as _;
+
+        let token = r#"{"access_token":"xxx","expires_in":3600,"token_type":"Bearer"}"#;
+        let host = auth::mock::serve_routes(vec![
+            ("/computeMetadata/v1/instance/service-accounts/default/token", token.into()),
+            ("/computeMetadata/v1/instance/service-accounts/default/identity", "a.e30.b".into()),
+        ]);
+        let credentials = Credentials::from(credentials::Metadata::new().with_host(host));
+        let mut svc = GoogleAuthz::builder(Echo)
+            .credentials(credentials)
+            .id_token("https://example.com", PROXY_AUTHORIZATION)
+            .build()
+            .await;
+
+        svc.warm_up().await.unwrap();
+        let req = Request::builder().uri("https://example.com/").body(()).unwrap();
+        let req = svc.call(req).await.unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer xxx");
+        assert_eq!(req.headers()[PROXY_AUTHORIZATION], "Bearer a.e30.b");
+
+        // only service accounts and the metadata server can issue ID tokens
+        for credentials in [Credentials::none(), credentials::StaticToken::new("xxx").into()] {
+            let result = GoogleAuthz::builder(Echo)
+                .credentials(credentials)
+                .id_token("https://example.com", PROXY_AUTHORIZATION)
+                .try_build()
+                .await;
+            assert!(matches!(result, Err(auth::Error::InvalidConfig(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_before_ready() {
+        use tower_service::Service as _;
+
+        let credentials =
+            Credentials::builder().signer("sa@project", Box::new(Revoked)).build().await.unwrap();
+        let mut svc = GoogleAuthz::builder(Echo).credentials(credentials).build().await;
+        // `poll_ready` was not called, or its error was ignored
+        let req = Request::builder().uri("https://pubsub.googleapis.com/").body(()).unwrap();
+        let result = svc.call(req).await;
+        assert!(matches!(result, Err(Error::GoogleAuthz(auth::Error::TokenNotFetched))));
+    }
 }
@@ -0,0 +1,103 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use parking_lot::RwLock;
+
+use crate::GoogleAuthz;
+
+type Key = (String, String);
+
+/// Services keyed by project and service account email, for applications that act as many
+/// service accounts.
+///
+/// Clones share the same entries, and the services handed out share the token of their entry,
+/// so that a token is fetched once per service account.
+pub struct CredentialStore<S> {
+    inner: Arc<RwLock<HashMap<Key, GoogleAuthz<S>>>>,
+}
+
+impl<S: Clone> CredentialStore<S> {
+    pub fn new() -> Self {
+        Self { inner: Default::default() }
+    }
+
+    pub fn get(&self, project: &str, service_account: &str) -> Option<GoogleAuthz<S>> {
+        self.inner.read().get(&key(project, service_account)).cloned()
+    }
+
+    /// Returns the service of `project` and `service_account`, inserting the one returned by `f`
+    /// if there is none. `f` is called at most once, without holding the lock.
+    pub fn get_or_insert(
+        &self,
+        project: &str,
+        service_account: &str,
+        f: impl FnOnce() -> GoogleAuthz<S>,
+    ) -> GoogleAuthz<S> {
+        if let Some(service) = self.get(project, service_account) {
+            return service;
+        }
+        let service = f();
+        // Another thread may have inserted one in the meantime, the first one wins.
+        self.inner.write().entry(key(project, service_account)).or_insert(service).clone()
+    }
+
+    pub fn remove(&self, project: &str, service_account: &str) -> Option<GoogleAuthz<S>> {
+        self.inner.write().remove(&key(project, service_account))
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().is_empty()
+    }
+}
+
+fn key(project: &str, service_account: &str) -> Key {
+    (project.to_owned(), service_account.to_owned())
+}
+
+impl<S: Clone> Default for CredentialStore<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Clone for CredentialStore<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<S> fmt::Debug for CredentialStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.read();
+        f.debug_struct("CredentialStore").field("keys", &inner.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_util::Echo, Credentials};
+
+    #[tokio::test]
+    async fn test_get_or_insert() {
+        let store = CredentialStore::new();
+        let service = GoogleAuthz::builder(Echo).credentials(Credentials::none()).build().await;
+
+        let mut calls = 0;
+        for _ in 0..2 {
+            store.clone().get_or_insert("project", "sa@project", || {
+                calls += 1;
+                service.clone()
+            });
+        }
+        assert_eq!(calls, 1);
+        assert_eq!(store.len(), 1);
+        assert!(store.get("project", "other@project").is_none());
+
+        assert!(store.remove("project", "sa@project").is_some());
+        assert!(store.is_empty());
+    }
+}
@@ -1,4 +1,10 @@
-use std::fmt;
+use std::{
+    fmt,
+    sync::Arc,
+    task::{Wake, Waker},
+};
+
+use parking_lot::Mutex;
 
 /// RefGuard wraps a `Send` type to make it `Sync`, by ensuring that it is only
 /// ever accessed through a &mut pointer.
@@ -23,3 +29,41 @@ impl<T: fmt::Debug> fmt::Debug for RefGuard<T> {
 }
 
 unsafe impl<T: Send> Sync for RefGuard<T> {}
+
+/// Waiters are the tasks waiting for a shared future. It is itself the waker that the future is
+/// polled with, so that every waiting task is woken up, even if the task that polled it last has
+/// been dropped.
+#[derive(Default)]
+pub(crate) struct Waiters {
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl Waiters {
+    pub fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    /// Wakes every waiting task except `current`, which is not waiting anymore.
+    pub fn wake_others(&self, current: &Waker) {
+        let wakers = std::mem::take(&mut *self.wakers.lock());
+        wakers.into_iter().filter(|w| !w.will_wake(current)).for_each(Waker::wake);
+    }
+
+    pub fn wake_all(&self) {
+        let wakers = std::mem::take(&mut *self.wakers.lock());
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl Wake for Waiters {
+    fn wake(self: Arc<Self>) {
+        self.wake_all();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_all();
+    }
+}
@@ -0,0 +1,29 @@
+//! Helpers shared by the unit tests, the integration tests and the benchmarks. The latter two
+//! include this file with `#[path]`, so it only uses the dependencies of the crate.
+
+use std::{
+    convert::Infallible,
+    future::{self, Ready},
+    task::{self, Poll},
+};
+
+use hyper::Request;
+use tower_service::Service;
+
+/// Returns the request as is, e.g. to check the headers that `GoogleAuthz` added.
+#[derive(Clone, Debug)]
+pub struct Echo;
+
+impl Service<Request<()>> for Echo {
+    type Response = Request<()>;
+    type Error = Infallible;
+    type Future = Ready<Result<Request<()>, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<()>) -> Self::Future {
+        future::ready(Ok(req))
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    str::FromStr as _,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    header::{HeaderMap, CACHE_CONTROL},
+    Body, Request, StatusCode, Uri,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use parking_lot::RwLock;
+use tracing::debug;
+
+use crate::auth::{Error as AuthError, HttpClient};
+
+const GOOGLE_CERTS: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const GOOGLE_ISSUERS: &[&str] = &["https://accounts.google.com", "accounts.google.com"];
+
+// Used if the response has no `max-age`.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(3600);
+// Tokens with unknown keys do not refetch the keys more often than this, as anyone can send them.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+fn verify_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> AuthError {
+    AuthError::Verify(err.into())
+}
+
+/// The claims of a Google-issued ID token.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: u64,
+    pub iat: u64,
+    #[serde(default)]
+    pub azp: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: Option<bool>,
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7517
+#[derive(Debug, serde::Deserialize)]
+struct Jwk {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug)]
+struct Keys {
+    keys: Vec<Jwk>,
+    fetched: Instant,
+    expiry: Instant,
+}
+
+/// Verifies RS256 tokens issued by Google, e.g. the bearer token of an incoming request, with
+/// the public keys of a JWKS endpoint.
+///
+/// The keys are fetched on the first verification and cached as long as `Cache-Control` allows.
+/// They are also refetched if a token is signed with an unknown key, as the keys are rotated.
+#[derive(Clone, Debug)]
+pub struct Verifier {
+    inner: HttpClient,
+    uri: Uri,
+    audience: Option<String>,
+    keys: Arc<RwLock<Option<Keys>>>,
+}
+
+impl Verifier {
+    /// Uses the public keys of Google's OAuth 2.0 server.
+    pub fn google() -> Self {
+        Self::new(Uri::from_static(GOOGLE_CERTS))
+    }
+
+    pub fn from_jwks_uri(uri: &str) -> Result<Self, AuthError> {
+        Ok(Self::new(Uri::from_str(uri).map_err(verify_error)?))
+    }
+
+    fn new(uri: Uri) -> Self {
+        Self { inner: HttpClient::new(), uri, audience: None, keys: Default::default() }
+    }
+
+    /// Also requires `aud` to be `audience`. Without it, tokens issued to any client are valid.
+    #[must_use]
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    pub async fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let header = jsonwebtoken::decode_header(token).map_err(verify_error)?;
+        if header.alg != Algorithm::RS256 {
+            return Err(verify_error(format!("unsupported algorithm: {:?}", header.alg)));
+        }
+        let kid = header.kid.ok_or_else(|| verify_error("the token has no `kid`"))?;
+        let unknown_key = || verify_error(format!("unknown key: {}", kid));
+        let (n, e) = match self.key(&kid) {
+            Some(key) => key,
+            None if self.can_fetch() => {
+                self.fetch().await?;
+                self.key(&kid).ok_or_else(unknown_key)?
+            }
+            None => return Err(unknown_key()),
+        };
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        if let Some(ref audience) = self.audience {
+            validation.set_audience(&[audience]);
+        }
+        let key = DecodingKey::from_rsa_components(&n, &e);
+        let claims =
+            jsonwebtoken::decode::<Claims>(token, &key, &validation).map_err(verify_error)?.claims;
+        if !GOOGLE_ISSUERS.contains(&claims.iss.as_str()) {
+            return Err(verify_error(format!("unexpected issuer: {}", claims.iss)));
+        }
+        Ok(claims)
+    }
+
+    // Returns the modulus and the exponent of `kid`, `None` if it is unknown or the keys expired.
+    fn key(&self, kid: &str) -> Option<(String, String)> {
+        let keys = self.keys.read();
+        let keys = keys.as_ref().filter(|keys| keys.expiry > Instant::now())?;
+        let key = keys.keys.iter().find(|key| key.kid == kid)?;
+        Some((key.n.clone(), key.e.clone()))
+    }
+
+    fn can_fetch(&self) -> bool {
+        match *self.keys.read() {
+            Some(ref keys) => {
+                keys.expiry <= Instant::now() || keys.fetched.elapsed() >= MIN_REFETCH_INTERVAL
+            }
+            None => true,
+        }
+    }
+
+    async fn fetch(&self) -> Result<(), AuthError> {
+        let req = Request::get(&self.uri).body(Body::empty()).unwrap();
+        let (parts, body) = self.inner.send_raw(req).await?;
+        if parts.status != StatusCode::OK {
+            return Err(AuthError::Http(parts.status));
+        }
+        let set: JwkSet = serde_json::from_slice(&body)?;
+        let max_age = max_age(&parts.headers).unwrap_or(DEFAULT_MAX_AGE);
+        debug!(uri = %self.uri, keys = set.keys.len(), ?max_age, "fetched the public keys");
+        let now = Instant::now();
+        // a huge max-age would overflow the instant
+        let expiry = now.checked_add(max_age).unwrap_or(now + DEFAULT_MAX_AGE);
+        *self.keys.write() = Some(Keys { keys: set.keys, fetched: now, expiry });
+        Ok(())
+    }
+}
+
+// e.g. `public, max-age=19732, must-revalidate, no-transform`
+fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(CACHE_CONTROL)?.to_str().ok()?;
+    value
+        .split(',')
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+
+    // The public key of `auth/oauth2/testdata/rsa.pem`.
+    const N: &str = "ltaAFdED7B39mSVLdfyvYXc8qnL0Fx5t93-cTtgkjZHUr7jkFxTM2NRJ1YEIRZyf_6x7uarQbCCxqoeQcR1JR5RW4JtunIEsrPyHNs1188sNs-1IjnYJjqJZbrVl3tBS7p3k9jji-Vl2MWuOu9AkYLRMIq39IWOwuZ0gLQ4OND-r0nIk3GxtFdN8NFouIM3g6I7i789r6sCTaJzIhD1_F0BVQ0kS0jiW_s1zz7v_jUN7e3g607kC7qqTeXi4X-PAHJenvalV5kslgcvpHBNNZ9hgdfrcBU7CYMXnRQmgrjmDb4r9UMHFDTXCJwYZpcY9Ybuc-UFUuLXXdLXmxZq6bw";
+
+    fn token(iss: &str, aud: &str) -> String {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let claims = serde_json::json!({
+            "iss": iss, "sub": "123", "aud": aud, "iat": now, "exp": now + 3600,
+            "email": "user@example.com",
+        });
+        let header = Header { kid: Some("key-1".into()), ..Header::new(Algorithm::RS256) };
+        let key = EncodingKey::from_rsa_pem(include_bytes!("auth/oauth2/testdata/rsa.pem"));
+        jsonwebtoken::encode(&header, &claims, &key.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let verifier = Verifier::google().audience("client-1");
+        let jwk = Jwk { kid: "key-1".into(), n: N.into(), e: "AQAB".into() };
+        let now = Instant::now();
+        *verifier.keys.write() =
+            Some(Keys { keys: vec![jwk], fetched: now, expiry: now + DEFAULT_MAX_AGE });
+
+        let claims = verifier.verify(&token("https://accounts.google.com", "client-1")).await;
+        assert_eq!(claims.unwrap().email.as_deref(), Some("user@example.com"));
+        let other_audience = token("https://accounts.google.com", "client-2");
+        assert!(matches!(verifier.verify(&other_audience).await, Err(AuthError::Verify(_))));
+        let other_issuer = token("https://example.com", "client-1");
+        assert!(matches!(verifier.verify(&other_issuer).await, Err(AuthError::Verify(_))));
+        // the keys were just fetched, so an unknown key fails without fetching them again
+        assert!(!verifier.can_fetch());
+    }
+
+    #[test]
+    fn test_max_age() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(max_age(&headers), None);
+        let value = "public, max-age=19732, must-revalidate, no-transform";
+        headers.insert(CACHE_CONTROL, value.parse().unwrap());
+        assert_eq!(max_age(&headers), Some(Duration::from_secs(19732)));
+    }
+}
@@ -0,0 +1,182 @@
+//! The whole `poll_ready` and `call` cycle against a local token endpoint, without any GCP
+//! credentials.
+
+mod mock;
+#[path = "../../src/test_util.rs"]
+mod test_util;
+
+use std::{convert::Infallible, time::Duration};
+
+use futures_util::future::poll_fn;
+use google_authz::{AuthError, Credentials, Error, GoogleAuthz, ServiceAccountKey};
+use hyper::{client::HttpConnector, Request};
+use mock::{Reply, TokenServer};
+use test_util::Echo;
+use tower_service::Service;
+
+fn token(access_token: &'static str, expires_in: u64) -> Reply {
+    Reply::Token { access_token, expires_in }
+}
+
+const PRIVATE_KEY: &str = include_str!("../../src/auth/oauth2/testdata/rsa.pem");
+
+async fn credentials(server: &TokenServer) -> Credentials {
+    let json = serde_json::json!({
+        "type": "service_account",
+        "client_email": "sa@project.iam.gserviceaccount.com",
+        "private_key_id": "key-1",
+        "private_key": PRIVATE_KEY,
+        "token_uri": server.token_uri(),
+    })
+    .to_string();
+    Credentials::builder().json(json.as_bytes()).build().await.unwrap()
+}
+
+async fn service(server: &TokenServer, max_retry: u8) -> GoogleAuthz<Echo> {
+    service_with(credentials(server).await, max_retry).await
+}
+
+async fn service_with(credentials: Credentials, max_retry: u8) -> GoogleAuthz<Echo> {
+    GoogleAuthz::builder(Echo)
+        .credentials(credentials)
+        // The token endpoint is plain http.
+        .connector(HttpConnector::new())
+        .max_retry(max_retry)
+        .backoff(Duration::ZERO)
+        .build()
+        .await
+}
+
+// Returns the `authorization` header of a request sent through `service`.
+async fn authorization(service: &mut GoogleAuthz<Echo>) -> Result<String, Error<Infallible>> {
+    poll_fn(|cx| service.poll_ready(cx)).await?;
+    let req = service.call(Request::get("https://example.com/").body(()).unwrap()).await?;
+    Ok(req.headers()["authorization"].to_str().unwrap().to_owned())
+}
+
+#[tokio::test]
+async fn test_fetch() {
+    let server = TokenServer::start(vec![token("token-1", 3600)]).await;
+    let mut service = service(&server, 0).await;
+    assert_eq!(authorization(&mut service).await.unwrap(), "Bearer token-1");
+    // the token is reused
+    assert_eq!(authorization(&mut service.clone()).await.unwrap(), "Bearer token-1");
+    assert_eq!(server.requests(), 1);
+}
+
+#[tokio::test]
+async fn test_warm_up() {
+    let server = TokenServer::start(vec![token("token-1", 3600)]).await;
+    let mut service = service(&server, 0).await;
+    service.warm_up().await.unwrap();
+    assert_eq!(server.requests(), 1);
+    assert!(service.token_snapshot().is_some());
+    assert_eq!(authorization(&mut service).await.unwrap(), "Bearer token-1");
+    assert_eq!(server.requests(), 1);
+}
+
+#[tokio::test]
+async fn test_retry_server_error() {
+    let server = TokenServer::start(vec![Reply::Status(500), token("token-1", 3600)]).await;
+    let mut service = service(&server, 1).await;
+    assert_eq!(authorization(&mut service).await.unwrap(), "Bearer token-1");
+    assert_eq!(server.requests(), 2);
+}
+
+#[tokio::test]
+async fn test_retry_network_error() {
+    // The client reconnects once within an attempt, so an attempt fails after two closes.
+    let server =
+        TokenServer::start(vec![Reply::Close, Reply::Close, token("token-1", 3600)]).await;
+    let mut service = service(&server, 1).await;
+    assert_eq!(authorization(&mut service).await.unwrap(), "Bearer token-1");
+    assert_eq!(server.requests(), 3);
+}
+
+#[tokio::test]
+async fn test_max_retry_network_error() {
+    let server = TokenServer::start((0..4).map(|_| Reply::Close).collect()).await;
+    let mut service = service(&server, 1).await;
+    match authorization(&mut service).await {
+        Err(Error::GoogleAuthz(AuthError::MaxRetriesExceeded { attempts, source })) => {
+            assert_eq!(attempts, 2);
+            assert!(matches!(*source, AuthError::Io(_)), "{:?}", source);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(server.requests(), 4);
+}
+
+#[tokio::test]
+async fn test_refresh_expired_token() {
+    let server = TokenServer::start(vec![token("token-1", 1), token("token-2", 3600)]).await;
+    let mut service = service(&server, 0).await;
+    assert_eq!(authorization(&mut service).await.unwrap(), "Bearer token-1");
+    // the refresh buffer is clamped to half of the lifetime of the token
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(authorization(&mut service).await.unwrap(), "Bearer token-2");
+    assert_eq!(server.requests(), 2);
+}
+
+#[tokio::test]
+async fn test_max_retry() {
+    let server = TokenServer::start(vec![Reply::Status(500), Reply::Status(503)]).await;
+    let mut service = service(&server, 1).await;
+    match authorization(&mut service).await {
+        Err(Error::GoogleAuthz(AuthError::MaxRetriesExceeded { attempts, .. })) => {
+            assert_eq!(attempts, 2)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(server.requests(), 2);
+}
+
+#[tokio::test]
+async fn test_key_rotation() {
+    let server = TokenServer::start(vec![
+        Reply::Status(401),
+        token("token-1", 3600),
+        token("token-2", 3600),
+    ])
+    .await;
+    let sa = match credentials(&server).await {
+        Credentials::ServiceAccount(sa) => sa,
+        other => panic!("unexpected credentials: {:?}", other),
+    };
+    let sa = sa.with_additional_keys(vec![ServiceAccountKey::new("key-2", PRIVATE_KEY)]);
+    let mut service = service_with(sa.into(), 0).await;
+    assert_eq!(authorization(&mut service).await.unwrap(), "Bearer token-1");
+    // the key that succeeded is tried first
+    service.force_refresh().await.unwrap();
+    assert_eq!(authorization(&mut service).await.unwrap(), "Bearer token-2");
+    assert_eq!(server.key_ids(), ["key-1", "key-2", "key-2"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_clones() {
+    let delay = || Reply::Delay(Duration::from_millis(50));
+    let replies = vec![delay(), token("token-1", 1), delay(), token("token-2", 3600)];
+    let server = TokenServer::start(replies).await;
+    let service = service(&server, 0).await;
+    let tasks = (0..32)
+        .map(|_| {
+            let mut service = service.clone();
+            tokio::spawn(async move {
+                let mut tokens = Vec::new();
+                // across the refresh of the first token, after 500ms
+                for _ in 0..20 {
+                    tokens.push(authorization(&mut service).await.unwrap());
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                tokens
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        let tokens = task.await.unwrap();
+        assert_eq!(tokens.first().unwrap(), "Bearer token-1");
+        assert_eq!(tokens.last().unwrap(), "Bearer token-2");
+    }
+    // one fetch per token
+    assert_eq!(server.requests(), 2);
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+};
+
+pub enum Reply {
+    Token { access_token: &'static str, expires_in: u64 },
+    Status(u16),
+    // Closes the connection without a response.
+    Close,
+    // Delays the next reply.
+    Delay(Duration),
+}
+
+// A token endpoint that answers each connection with the next scripted reply.
+pub struct TokenServer {
+    addr: SocketAddr,
+    requests: Arc<AtomicUsize>,
+    bodies: Arc<Mutex<Vec<String>>>,
+}
+
+impl TokenServer {
+    pub async fn start(replies: Vec<Reply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let replies = Arc::new(Mutex::new(VecDeque::from(replies)));
+
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+
+        let (counter, received) = (requests.clone(), bodies.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let body = read_request(&mut stream).await;
+                received.lock().push(body);
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut reply = replies.lock().pop_front().expect("no more scripted replies");
+                while let Reply::Delay(delay) = reply {
+                    tokio::time::sleep(delay).await;
+                    reply = replies.lock().pop_front().expect("no more scripted replies");
+                }
+                let resp = match reply {
+                    Reply::Token { access_token, expires_in } => response(
+                        "200 OK",
+                        &format!(
+                            r#"{{"access_token":"{}","expires_in":{},"token_type":"Bearer"}}"#,
+                            access_token, expires_in
+                        ),
+                    ),
+                    Reply::Status(status) => response(&format!("{} Error", status), ""),
+                    Reply::Close => continue,
+                    Reply::Delay(_) => unreachable!(),
+                };
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        Self { addr, requests, bodies }
+    }
+
+    pub fn token_uri(&self) -> String {
+        format!("http://{}/token", self.addr)
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    // The `kid` of the JWT assertion of each request.
+    pub fn key_ids(&self) -> Vec<String> {
+        self.bodies
+            .lock()
+            .iter()
+            .map(|body| {
+                let assertion = body.split("assertion=").nth(1).unwrap();
+                let header = assertion.split('.').next().unwrap();
+                let header = base64::decode_config(header, base64::URL_SAFE_NO_PAD).unwrap();
+                let header: serde_json::Value = serde_json::from_slice(&header).unwrap();
+                header["kid"].as_str().unwrap().to_owned()
+            })
+            .collect()
+    }
+}
+
+// Reads the headers and the body, so that the client does not see a reset connection, and
+// returns the body.
+async fn read_request(stream: &mut TcpStream) -> String {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed before the request was read");
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let headers = String::from_utf8_lossy(&buf[..header_end]).to_ascii_lowercase();
+    let content_length = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .map_or(0, |len| len.trim().parse().unwrap());
+    while buf.len() < header_end + content_length {
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed before the body was read");
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    String::from_utf8_lossy(&buf[header_end..]).into_owned()
+}
+
+fn response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}